@@ -6,6 +6,7 @@ fn main() {
     generate_c_header(&crate_dir);
 }
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn generate_c_header(crate_dir: &str) {
     cbindgen::generate(&crate_dir)
         .expect("Unable to generate bindings")
@@ -24,6 +24,14 @@ enum MaybenotEventType {
    * We received a padding packet.
    */
   MaybenotEventType_PaddingReceived = 3,
+  /**
+   * The timer of the machine was started or updated.
+   */
+  MaybenotEventType_TimerBegin = 4,
+  /**
+   * The timer of the machine expired.
+   */
+  MaybenotEventType_TimerEnd = 5,
 };
 typedef uint32_t MaybenotEventType;
 
@@ -51,6 +59,42 @@ enum MaybenotResult {
    * A null pointer was encountered
    */
   MaybenotResult_NullPointer = 4,
+  /**
+   * The machine string wasn't valid hex
+   */
+  MaybenotResult_InvalidMachineHex = 5,
+  /**
+   * The decoded machine string wasn't valid zlib
+   */
+  MaybenotResult_InvalidMachineZlib = 6,
+  /**
+   * The machine string uses an unsupported version of the format
+   */
+  MaybenotResult_UnsupportedMachineVersion = 7,
+  /**
+   * The machine string was truncated or had trailing data
+   */
+  MaybenotResult_TruncatedMachine = 8,
+  /**
+   * A machine had a distribution with invalid parameters
+   */
+  MaybenotResult_InvalidMachineDist = 9,
+  /**
+   * A machine had invalid transition probabilities
+   */
+  MaybenotResult_InvalidMachineProbabilities = 10,
+  /**
+   * A machine had no states or too many states
+   */
+  MaybenotResult_InvalidMachineStateCount = 11,
+  /**
+   * A machine had invalid padding or blocking limits
+   */
+  MaybenotResult_InvalidMachineLimits = 12,
+  /**
+   * The decompressed machine string was too large
+   */
+  MaybenotResult_MachineTooLarge = 13,
 };
 typedef uint32_t MaybenotResult;
 
@@ -93,6 +137,10 @@ enum MaybenotAction_Tag {
    */
   MaybenotAction_InjectPadding = 1,
   MaybenotAction_BlockOutgoing = 2,
+  /**
+   * Update the timer of the machine.
+   */
+  MaybenotAction_UpdateTimer = 3,
 };
 typedef uint32_t MaybenotAction_Tag;
 
@@ -137,12 +185,29 @@ typedef struct MaybenotAction_BlockOutgoing_Body {
   struct MaybenotDuration duration;
 } MaybenotAction_BlockOutgoing_Body;
 
+typedef struct MaybenotAction_UpdateTimer_Body {
+  /**
+   * The machine that generated the action.
+   */
+  uintptr_t machine;
+  /**
+   * The duration until the timer expires.
+   */
+  struct MaybenotDuration duration;
+  /**
+   * If false and the timer is already active, keep the longest of the
+   * duration and the remaining duration of the timer.
+   */
+  bool replace;
+} MaybenotAction_UpdateTimer_Body;
+
 typedef struct MaybenotAction {
   MaybenotAction_Tag tag;
   union {
     MaybenotAction_Cancel_Body cancel;
     MaybenotAction_InjectPadding_Body inject_padding;
     MaybenotAction_BlockOutgoing_Body block_outgoing;
+    MaybenotAction_UpdateTimer_Body update_timer;
   };
 } MaybenotAction;
 
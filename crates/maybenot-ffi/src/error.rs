@@ -1,3 +1,5 @@
+use maybenot::error::MachineError;
+
 /// An FFI friendly result error code type.
 #[repr(u32)]
 #[derive(Debug, Clone, Copy)]
@@ -16,6 +18,56 @@ pub enum MaybenotResult {
 
     /// A null pointer was encountered
     NullPointer = 4,
+
+    /// The machine string wasn't valid hex
+    InvalidMachineHex = 5,
+
+    /// The decoded machine string wasn't valid zlib
+    InvalidMachineZlib = 6,
+
+    /// The machine string uses an unsupported version of the format
+    UnsupportedMachineVersion = 7,
+
+    /// The machine string was truncated or had trailing data
+    TruncatedMachine = 8,
+
+    /// A machine had a distribution with invalid parameters
+    InvalidMachineDist = 9,
+
+    /// A machine had invalid transition probabilities
+    InvalidMachineProbabilities = 10,
+
+    /// A machine had no states or too many states
+    InvalidMachineStateCount = 11,
+
+    /// A machine had invalid padding or blocking limits
+    InvalidMachineLimits = 12,
+
+    /// The decompressed machine string was too large
+    MachineTooLarge = 13,
+}
+
+impl From<MachineError> for MaybenotResult {
+    fn from(error: MachineError) -> Self {
+        match error {
+            MachineError::InvalidHex(_) => MaybenotResult::InvalidMachineHex,
+            MachineError::InvalidZlib(_) => MaybenotResult::InvalidMachineZlib,
+            MachineError::UnsupportedVersion(_) => MaybenotResult::UnsupportedMachineVersion,
+            MachineError::Truncated(_) => MaybenotResult::TruncatedMachine,
+            MachineError::TooLarge { .. } => MaybenotResult::MachineTooLarge,
+            MachineError::InvalidDist { .. } => MaybenotResult::InvalidMachineDist,
+            MachineError::InvalidProbabilities { .. } => {
+                MaybenotResult::InvalidMachineProbabilities
+            }
+            MachineError::NoStates | MachineError::TooManyStates { .. } => {
+                MaybenotResult::InvalidMachineStateCount
+            }
+            MachineError::InvalidFraction { .. }
+            | MachineError::InvalidWindow { .. }
+            | MachineError::InvalidRateLimit(_) => MaybenotResult::InvalidMachineLimits,
+            _ => MaybenotResult::InvalidMachineString,
+        }
+    }
 }
 
 impl<T> From<Result<T, MaybenotResult>> for MaybenotResult {
@@ -2,7 +2,7 @@ use core::{mem::MaybeUninit, str::FromStr, time::Duration};
 use std::time::Instant;
 
 use maybenot::{
-    framework::{Framework, MachineId, TriggerEvent},
+    framework::{Framework, MachineId, StdFramework, TriggerEvent},
     machine::Machine,
 };
 
@@ -18,7 +18,7 @@ pub use ffi::*;
 /// - Feed it actions: [maybenot_on_event].
 /// - Stop it: [maybenot_stop].
 pub struct MaybenotFramework {
-    framework: Framework<Vec<Machine>>,
+    framework: StdFramework<Vec<Machine>>,
 
     /// A buffer used internally for converting from [MaybenotEvent]s.
     events_buf: Vec<TriggerEvent>,
@@ -61,6 +61,12 @@ pub enum MaybenotEventType {
 
     /// We received a padding packet.
     PaddingReceived = 3,
+
+    /// The timer of the machine was started or updated.
+    TimerBegin = 4,
+
+    /// The timer of the machine expired.
+    TimerEnd = 5,
 }
 
 #[repr(C, u32)]
@@ -99,6 +105,19 @@ pub enum MaybenotAction {
         /// How long to block.
         duration: MaybenotDuration,
     } = 2,
+
+    /// Update the timer of the machine.
+    UpdateTimer {
+        /// The machine that generated the action.
+        machine: usize,
+
+        /// The duration until the timer expires.
+        duration: MaybenotDuration,
+
+        /// If false and the timer is already active, keep the longest of the
+        /// duration and the remaining duration of the timer.
+        replace: bool,
+    } = 3,
 }
 
 impl MaybenotFramework {
@@ -111,8 +130,7 @@ impl MaybenotFramework {
         let machines: Vec<_> = machines_str
             .lines()
             .map(Machine::from_str)
-            .collect::<Result<_, _>>()
-            .map_err(|_e| MaybenotResult::InvalidMachineString)?;
+            .collect::<Result<Vec<_>, _>>()?;
 
         let machines_count = machines.len();
 
@@ -191,6 +209,15 @@ fn convert_action(action: &maybenot::framework::Action) -> MaybenotAction {
             bypass,
             machine: machine.into_raw(),
         },
+        maybenot::framework::Action::UpdateTimer {
+            duration,
+            replace,
+            machine,
+        } => MaybenotAction::UpdateTimer {
+            duration: duration.into(),
+            replace,
+            machine: machine.into_raw(),
+        },
     }
 }
 
@@ -209,6 +236,12 @@ fn convert_event(event: MaybenotEvent) -> TriggerEvent {
         MaybenotEventType::PaddingReceived => TriggerEvent::PaddingRecv {
             bytes_recv: event.xmit_bytes,
         },
+        MaybenotEventType::TimerBegin => TriggerEvent::TimerBegin {
+            machine: MachineId::from_raw(event.machine),
+        },
+        MaybenotEventType::TimerEnd => TriggerEvent::TimerEnd {
+            machine: MachineId::from_raw(event.machine),
+        },
     }
 }
 
@@ -2,6 +2,7 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, error::Error, time::Duration};
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 struct Bin {
     range: (f64, f64),
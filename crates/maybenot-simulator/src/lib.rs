@@ -123,13 +123,13 @@ use network::Network;
 use queue::SimQueue;
 
 use maybenot::{
-    framework::{Action, Framework, MachineId, TriggerEvent},
+    framework::{Action, Framework, MachineId, StdFramework, TriggerEvent},
     machine::Machine,
 };
 
 use crate::{
     network::sim_network_activity,
-    peek::{peek_blocked_exp, peek_queue, peek_scheduled},
+    peek::{peek_blocked_exp, peek_internal_timer, peek_queue, peek_scheduled},
 };
 
 /// SimEvent represents an event in the simulator. It is used internally to
@@ -160,9 +160,11 @@ pub struct ScheduledAction {
 /// The state of the client or the server in the simulator.
 pub struct SimState<M> {
     /// an instance of the Maybenot framework
-    framework: Framework<M>,
+    framework: StdFramework<M>,
     /// scheduled actions (timers)
     scheduled_action: HashMap<MachineId, ScheduledAction>,
+    /// expiry of the internal timer of each machine (set by UpdateTimer)
+    internal_timer: HashMap<MachineId, Instant>,
     /// blocking time (active if in the future, relative to current_time)
     blocking_until: Instant,
     /// whether the active blocking bypassable or not
@@ -197,6 +199,7 @@ where
             )
             .unwrap(),
             scheduled_action: HashMap::new(),
+            internal_timer: HashMap::new(),
             // has to be in the past
             blocking_until: current_time.checked_sub(Duration::from_micros(1)).unwrap(),
             blocking_bypassable: false,
@@ -402,10 +405,10 @@ pub fn sim_advanced(
         // get actions, update scheduled actions
         if next.client {
             debug!("sim(): trigger @client framework\n{:#?}", next.event);
-            trigger_update(&mut client, &next, &current_time);
+            trigger_update(&mut client, &next, sq, &current_time);
         } else {
             debug!("sim(): trigger @server framework\n{:#?}", next.event);
-            trigger_update(&mut server, &next, &current_time);
+            trigger_update(&mut server, &next, sq, &current_time);
         }
 
         // conditional save to resulting trace: only on network activity if set
@@ -456,6 +459,7 @@ pub fn sim_advanced(
     }
 
     // sort the trace by time
+    #[allow(clippy::unnecessary_sort_by)]
     trace.sort_by(|a, b| a.time.cmp(&b.time));
 
     trace
@@ -477,18 +481,20 @@ fn pick_next<M: AsRef<[Machine]>>(
     debug!("\tpick_next(): peek_scheduled = {:?}", s);
     let b = peek_blocked_exp(&client.blocking_until, &server.blocking_until, current_time);
     debug!("\tpick_next(): peek_blocked_exp = {:?}", b);
-    let (q, q_peek) = peek_queue(sq, client, server, s.min(b), current_time);
+    let t = peek_internal_timer(&client.internal_timer, &server.internal_timer, current_time);
+    debug!("\tpick_next(): peek_internal_timer = {:?}", t);
+    let (q, q_peek) = peek_queue(sq, client, server, s.min(b).min(t), current_time);
     debug!("\tpick_next(): peek_queue = {:?}", q);
 
     // no next?
-    if s == Duration::MAX && b == Duration::MAX && q == Duration::MAX {
+    if s == Duration::MAX && b == Duration::MAX && t == Duration::MAX && q == Duration::MAX {
         return None;
     }
 
     // We prioritize the queue: in general, stuff happens faster outside the
     // framework than inside it. On overload, the user of the framework will
     // bulk trigger events in the framework.
-    if q <= s && q <= b {
+    if q <= s && q <= b && q <= t {
         debug!("\tpick_next(): picked queue");
         sq.remove(q_peek.as_ref().unwrap());
 
@@ -502,7 +508,7 @@ fn pick_next<M: AsRef<[Machine]>>(
 
     // next is blocking expiry, happens outside of framework, so probably faster
     // than framework
-    if b <= s {
+    if b <= s && b <= t {
         debug!("\tpick_next(): picked blocking");
         // create SimEvent and move blocking into (what soon will be) the past
         // to indicate that it has been processed
@@ -537,6 +543,12 @@ fn pick_next<M: AsRef<[Machine]>>(
         });
     }
 
+    // next is the expiry of an internal timer of a machine
+    if t <= s {
+        debug!("\tpick_next(): picked internal timer");
+        return Some(do_internal_timer(client, server, current_time + t));
+    }
+
     // what's left is scheduled actions: find the action act on the action,
     // putting the event into the sim queue, and then recurse
     debug!("\tpick_next(): picked scheduled");
@@ -548,6 +560,42 @@ fn pick_next<M: AsRef<[Machine]>>(
     pick_next(sq, client, server, current_time)
 }
 
+fn do_internal_timer<M: AsRef<[Machine]>>(
+    client: &mut SimState<M>,
+    server: &mut SimState<M>,
+    target: Instant,
+) -> SimEvent {
+    // find the expired timer, removing it to indicate that it has been processed
+    let mut expired = client
+        .internal_timer
+        .iter()
+        .find(|(_, t)| **t == target)
+        .map(|(m, _)| (*m, true));
+    if expired.is_none() {
+        expired = server
+            .internal_timer
+            .iter()
+            .find(|(_, t)| **t == target)
+            .map(|(m, _)| (*m, false));
+    }
+    let (machine, is_client) = expired.expect("BUG: no internal timer found");
+    if is_client {
+        client.internal_timer.remove(&machine);
+    } else {
+        server.internal_timer.remove(&machine);
+    }
+
+    SimEvent {
+        event: TriggerEvent::TimerEnd { machine },
+        time: target,
+        delay: Duration::from_micros(0),
+        client: is_client,
+        bypass: false,
+        replace: false,
+        fuzz: fastrand::i32(..),
+    }
+}
+
 fn do_scheduled<M: AsRef<[Machine]>>(
     client: &mut SimState<M>,
     server: &mut SimState<M>,
@@ -663,12 +711,18 @@ fn do_scheduled<M: AsRef<[Machine]>>(
                 fuzz: fastrand::i32(..),
             })
         }
+        Action::UpdateTimer { .. } => {
+            // timer updates are never scheduled, see trigger_update()
+            None
+        }
     }
 }
 
+#[allow(clippy::cloned_ref_to_slice_refs)]
 fn trigger_update<M: AsRef<[Machine]>>(
     state: &mut SimState<M>,
     next: &SimEvent,
+    sq: &mut SimQueue,
     current_time: &Instant,
 ) {
     let trigger_delay = state.trigger_delay();
@@ -718,6 +772,29 @@ fn trigger_update<M: AsRef<[Machine]>>(
                     },
                 );
             }
+            Action::UpdateTimer {
+                duration,
+                replace,
+                machine,
+            } => {
+                // the internal timer is independent of scheduled actions
+                let begin = *current_time + trigger_delay;
+                let expiry = begin + *duration;
+                let update = match state.internal_timer.get(machine) {
+                    Some(active) => *replace || expiry > *active,
+                    None => true,
+                };
+                if update {
+                    state.internal_timer.insert(*machine, expiry);
+                    sq.push(
+                        TriggerEvent::TimerBegin { machine: *machine },
+                        next.client,
+                        begin,
+                        trigger_delay,
+                        Reverse(begin),
+                    );
+                }
+            }
         };
     }
 }
@@ -731,6 +808,7 @@ fn trigger_update<M: AsRef<[Machine]>>(
 /// delay between the client and server. Returns a SimQueue with the events in
 /// the trace for use with [`sim`].
 
+#[allow(clippy::empty_line_after_doc_comments)]
 pub fn parse_trace(trace: &str, network: &Network) -> SimQueue {
     parse_trace_advanced(trace, network, None, None)
 }
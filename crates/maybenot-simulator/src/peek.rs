@@ -181,6 +181,23 @@ pub fn peek_scheduled(
     earliest
 }
 
+pub fn peek_internal_timer(
+    timers_c: &HashMap<MachineId, Instant>,
+    timers_s: &HashMap<MachineId, Instant>,
+    current_time: Instant,
+) -> Duration {
+    // there is at most one internal timer per machine
+    let mut earliest = Duration::MAX;
+
+    for t in timers_c.values().chain(timers_s.values()) {
+        if *t >= current_time && t.duration_since(current_time) < earliest {
+            earliest = t.duration_since(current_time);
+        }
+    }
+
+    earliest
+}
+
 pub fn peek_blocked_exp(
     blocking_c: &Instant,
     blocking_s: &Instant,
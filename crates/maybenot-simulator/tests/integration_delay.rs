@@ -5,6 +5,7 @@ use maybenot::{
     event::Event,
     machine::Machine,
     state::State,
+    window::Window,
 };
 use maybenot_simulator::{
     integration::{BinDist, Integration},
@@ -32,8 +33,11 @@ fn get_test_machine() -> Machine {
     Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 0,
         max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1],
         include_small_packets: true,
     }
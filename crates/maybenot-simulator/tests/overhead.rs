@@ -0,0 +1,185 @@
+use std::time::{Duration, Instant};
+
+use maybenot::{
+    dist::{Dist, DistType},
+    event::Event,
+    framework::TriggerEvent,
+    machine::{Machine, MachineBuilder, StateBuilder},
+    overhead::{estimate, TrafficModel},
+};
+use maybenot_simulator::{network::Network, parse_trace, sim};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const RUNS: usize = 1000;
+
+fn exponential(mean: f64) -> Dist {
+    Dist {
+        dist: DistType::Weibull,
+        param1: mean,
+        param2: 1.0,
+        start: 0.0,
+        max: 0.0,
+    }
+}
+
+fn constant(value: f64) -> Dist {
+    Dist {
+        dist: DistType::Uniform,
+        param1: value,
+        param2: value,
+        start: 0.0,
+        max: 0.0,
+    }
+}
+
+// Poisson arrivals with the rate per second until the duration, as trace lines
+// with nanosecond timestamps.
+fn arrivals(
+    rng: &mut StdRng,
+    rate: f64,
+    duration: Duration,
+    direction: &str,
+) -> Vec<(u64, String)> {
+    let mut lines = vec![];
+    let mut time = 0.0;
+    loop {
+        time += -(1.0 - rng.gen::<f64>()).ln() / rate;
+        if time >= duration.as_secs_f64() {
+            return lines;
+        }
+        let ns = (time * 1e9) as u64;
+        lines.push((ns, format!("{},{},1000", ns, direction)));
+    }
+}
+
+// Simulates the machine on random traces of the traffic, returning the mean
+// number of padding packets and bytes sent within the duration, and the mean
+// time in microseconds of the last padding packet.
+fn simulate(machine: &Machine, traffic: &TrafficModel, duration: Duration) -> (f64, f64, f64) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let delay = Duration::from_micros(1);
+    let network = Network::new(delay);
+    let (mut packets, mut bytes, mut last) = (0.0, 0.0, 0.0);
+    for _ in 0..RUNS {
+        let mut lines = arrivals(&mut rng, traffic.nonpadding_sent, duration, "s");
+        lines.extend(
+            arrivals(&mut rng, traffic.nonpadding_recv, duration, "r")
+                .into_iter()
+                .filter(|(ns, _)| *ns >= delay.as_nanos() as u64),
+        );
+        lines.sort();
+        // a small packet, ignored by the machine, starts the simulation at 0
+        let trace = std::iter::once("0,s,10".to_string())
+            .chain(lines.into_iter().map(|(_, line)| line))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut sq = parse_trace(&trace, &network);
+        let start: Instant = sq.peek().unwrap().0.time;
+        let trace = sim(std::slice::from_ref(machine), &[], &mut sq, delay, 0, false);
+        let mut run_last: f64 = 0.0;
+        for e in trace {
+            let TriggerEvent::PaddingSent { bytes_sent, .. } = e.event else {
+                continue;
+            };
+            if !e.client {
+                continue;
+            }
+            let time = e.time.duration_since(start);
+            if time < duration {
+                packets += 1.0;
+                bytes += bytes_sent as f64;
+            }
+            run_last = run_last.max(time.as_secs_f64() * 1e6);
+        }
+        last += run_last;
+    }
+    (
+        packets / RUNS as f64,
+        bytes / RUNS as f64,
+        last / RUNS as f64,
+    )
+}
+
+fn assert_near(simulated: f64, estimated: f64, name: &str) {
+    assert!(
+        (simulated - estimated).abs() < 0.1 * estimated,
+        "{}: simulated {}, estimated {}",
+        name,
+        simulated,
+        estimated
+    );
+}
+
+#[test_log::test]
+fn estimate_matches_simulated_padding() {
+    // pad after sent packets until padding stops, or a received packet
+    // interrupts it
+    let m = MachineBuilder::new()
+        .state(
+            "idle",
+            StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+        )
+        .state(
+            "pad",
+            StateBuilder::new()
+                .pad(constant(500.0))
+                .timeout(exponential(5000.0))
+                .transition(Event::PaddingSent, "pad", 0.7)
+                .transition(Event::PaddingSent, "idle", 0.3)
+                .transition(Event::NonPaddingRecv, "idle", 0.5),
+        )
+        .build()
+        .unwrap();
+    let traffic = TrafficModel {
+        nonpadding_sent: 50.0,
+        nonpadding_recv: 50.0,
+        padding_recv: 0.0,
+        mtu: 1420,
+    };
+    let duration = Duration::from_secs(2);
+
+    let e = estimate(&m, &traffic, duration).unwrap();
+    let (packets, bytes, _) = simulate(&m, &traffic, duration);
+    assert_near(packets, e.padding_packets, "padding packets");
+    assert_near(bytes, e.padding_bytes, "padding bytes");
+    assert_eq!(e.microsec_to_end, f64::INFINITY);
+}
+
+#[test_log::test]
+fn estimate_matches_simulated_time_to_end() {
+    // pad after sent packets until the machine ends, restarting on received
+    // packets
+    let m = MachineBuilder::new()
+        .state(
+            "idle",
+            StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+        )
+        .state(
+            "pad",
+            StateBuilder::new()
+                .pad(constant(500.0))
+                .timeout(exponential(2000.0))
+                .transition(Event::PaddingSent, "pad", 0.8)
+                .end(Event::PaddingSent, 0.2)
+                .transition(Event::NonPaddingRecv, "idle", 0.5),
+        )
+        .build()
+        .unwrap();
+    let traffic = TrafficModel {
+        nonpadding_sent: 100.0,
+        nonpadding_recv: 200.0,
+        padding_recv: 0.0,
+        mtu: 1420,
+    };
+    // long enough for the machine to end, so that the last padding packet
+    // is when it ends
+    let duration = Duration::from_secs(1);
+
+    let e = estimate(&m, &traffic, duration).unwrap();
+    assert!(e.end_probability > 0.999);
+    let (packets, _, last) = simulate(&m, &traffic, duration);
+    assert_near(packets, e.padding_packets, "padding packets");
+    assert_near(last, e.microsec_to_end, "microsec to end");
+}
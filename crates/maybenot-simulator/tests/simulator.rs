@@ -1,3 +1,11 @@
+#![allow(
+    clippy::cloned_ref_to_slice_refs,
+    clippy::clone_on_copy,
+    clippy::len_zero,
+    clippy::needless_range_loop,
+    clippy::to_string_in_format_args
+)]
+
 use log::debug;
 use maybenot_simulator::{network::Network, parse_trace, queue::SimQueue, sim, SimEvent};
 
@@ -13,8 +21,10 @@ use maybenot::{
     framework::TriggerEvent,
     machine::Machine,
     state::State,
+    window::Window,
 };
 
+#[allow(clippy::too_many_arguments)]
 fn run_test_sim(
     input: &str,
     output: &str,
@@ -161,8 +171,11 @@ fn test_simple_pad_machine() {
     let m = Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 0,
         max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1],
         include_small_packets: true,
     };
@@ -248,8 +261,11 @@ fn test_simple_block_machine() {
     let m = Machine {
         allowed_padding_bytes: 0,
         max_padding_frac: 0.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 1000,
         max_blocking_frac: 1.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1],
         include_small_packets: true,
     };
@@ -312,8 +328,11 @@ fn test_both_block_machine() {
     let client = Machine {
         allowed_padding_bytes: 0,
         max_padding_frac: 0.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 1000,
         max_blocking_frac: 1.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1],
         include_small_packets: true,
     };
@@ -384,8 +403,11 @@ fn test_blockpadding() {
     let m = Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 1000,
         max_blocking_frac: 1.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1, s2],
         include_small_packets: true,
     };
@@ -469,8 +491,11 @@ fn test_bypass_machine() {
     let mut m = Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 1000,
         max_blocking_frac: 1.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1, s2],
         include_small_packets: true,
     };
@@ -624,8 +649,11 @@ fn test_replace_machine() {
     let mut m = Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 0,
         max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1],
         include_small_packets: true,
     };
@@ -804,8 +832,11 @@ fn test_bypass_replace_machine() {
     let mut m = Machine {
         allowed_padding_bytes: 10000,
         max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
         allowed_blocked_microsec: 10000,
         max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
         states: vec![s0, s1, s2],
         include_small_packets: true,
     };
@@ -990,3 +1021,69 @@ fn test_excessive_sim_delay() {
     // 21574 is the number of events in EARLY_TRACE
     assert_eq!(client_trace.len(), 21574);
 }
+
+#[test_log::test]
+fn test_timer_machine() {
+    // a machine that pads once no nonpadding has been sent for 10us
+    let num_states = 3;
+    let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+    let mut e: HashMap<usize, f64> = HashMap::new();
+    e.insert(1, 1.0);
+    t.insert(Event::NonPaddingSent, e);
+    let s0 = State::new(t, num_states);
+
+    // arm (or re-arm) the timer, pad on expiry
+    let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+    let mut e: HashMap<usize, f64> = HashMap::new();
+    e.insert(1, 1.0);
+    t.insert(Event::NonPaddingSent, e);
+    let mut e: HashMap<usize, f64> = HashMap::new();
+    e.insert(2, 1.0);
+    t.insert(Event::TimerEnd, e);
+    let mut s1 = State::new(t, num_states);
+    s1.action = Dist {
+        dist: DistType::Uniform,
+        param1: 10.0,
+        param2: 10.0,
+        start: 0.0,
+        max: 0.0,
+    };
+    s1.action_is_timer = true;
+    s1.replace = true;
+
+    // pad right away, then back to waiting
+    let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+    let mut e: HashMap<usize, f64> = HashMap::new();
+    e.insert(1, 1.0);
+    t.insert(Event::PaddingSent, e);
+    let mut s2 = State::new(t, num_states);
+    s2.timeout = Dist {
+        dist: DistType::Uniform,
+        param1: 0.0,
+        param2: 0.0,
+        start: 0.0,
+        max: 0.0,
+    };
+    let m = Machine {
+        allowed_padding_bytes: 10000,
+        max_padding_frac: 1.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
+        allowed_blocked_microsec: 0,
+        max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
+        states: vec![s0, s1, s2],
+        include_small_packets: true,
+    };
+
+    run_test_sim(
+        "0,sn,100 18,sn,200 25,rn,300 25,rn,300 30,sn,500 35,rn,600",
+        "0,sn,100 0,tb 10,te 10,sp,1420 10,tb 18,sn,200 18,tb 25,rn,300 25,rn,300 28,te 28,sp,1420 28,tb 30,sn,500 30,tb 35,rn,600 40,te 40,sp,1420",
+        Duration::from_micros(5),
+        std::slice::from_ref(&m),
+        &[],
+        true,
+        50,
+        false,
+    );
+}
@@ -0,0 +1,38 @@
+//! Benchmarks of sampling distributions: constructing the sampler on every
+//! sample versus a prepared distribution.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use maybenot::dist::{Dist, DistType};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn sample(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sample");
+    for (dist, param1, param2) in [
+        (DistType::Uniform, 10.0, 100.0),
+        (DistType::Normal, 100.0, 10.0),
+        (DistType::Binomial, 100.0, 0.5),
+        (DistType::Beta, 2.0, 5.0),
+    ] {
+        let d = Dist {
+            dist,
+            param1,
+            param2,
+            start: 0.0,
+            max: 0.0,
+        };
+        let prepared = d.prepare().unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        group.bench_function(format!("{}/dist", dist), |b| {
+            b.iter(|| black_box(d.sample(&mut rng)))
+        });
+        group.bench_function(format!("{}/prepared", dist), |b| {
+            b.iter(|| black_box(prepared.sample(&mut rng)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sample);
+criterion_main!(benches);
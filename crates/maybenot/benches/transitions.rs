@@ -0,0 +1,140 @@
+//! Benchmarks of state transitions on large machines: sampling the next state
+//! from compiled transition tables versus a linear scan of the probabilities in
+//! each state, and triggering events in a framework.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use maybenot::compiled::CompiledMachine;
+use maybenot::constants::*;
+use maybenot::event::Event;
+use maybenot::framework::{Framework, MachineId, TriggerEvent};
+use maybenot::machine::Machine;
+use maybenot::state::State;
+use maybenot::window::Window;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// A machine where every state transitions to a few random states on every
+// event, the common case for large machines.
+fn large_machine(num_states: usize, seed: u64) -> Machine {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let states = (0..num_states)
+        .map(|_| {
+            let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+            for event in Event::iterator() {
+                let mut e: HashMap<usize, f64> = HashMap::new();
+                for _ in 0..4 {
+                    e.insert(rng.gen_range(0..num_states), 0.2);
+                }
+                t.insert(*event, e);
+            }
+            State::new(t, num_states)
+        })
+        .collect();
+    Machine {
+        allowed_padding_bytes: 0,
+        max_padding_frac: 0.0,
+        padding_window: Window::Lifetime,
+        padding_rate_limit: None,
+        allowed_blocked_microsec: 0,
+        max_blocking_frac: 0.0,
+        blocking_window: Window::Lifetime,
+        states,
+        include_small_packets: false,
+    }
+}
+
+// The sampling done before transitions were compiled.
+fn linear_scan(state: &State, event: Event, p: f64) -> usize {
+    let Some(probabilities) = state.next_state.get(&event) else {
+        return STATENOP;
+    };
+    let mut total = 0.0;
+    for (i, prob) in probabilities.iter().enumerate() {
+        total += prob;
+        if p <= total {
+            return match probabilities.len() - i {
+                1 => STATEEND,
+                2 => STATECANCEL,
+                _ => i,
+            };
+        }
+    }
+    STATENOP
+}
+
+fn next_state(c: &mut Criterion) {
+    let mut group = c.benchmark_group("next_state");
+    for num_states in [10, 100, 1000] {
+        let machine = large_machine(num_states, 0);
+        let compiled = CompiledMachine::new(&machine).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let lookups: Vec<(usize, Event, f64)> = (0..1000)
+            .map(|_| {
+                (
+                    rng.gen_range(0..num_states),
+                    *Event::iterator().nth(rng.gen_range(0..11)).unwrap(),
+                    rng.gen::<f64>(),
+                )
+            })
+            .collect();
+
+        group.bench_with_input(
+            BenchmarkId::new("linear_scan", num_states),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for (state, event, p) in lookups {
+                        black_box(linear_scan(&machine.states[*state], *event, *p));
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("compiled", num_states),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for (state, event, p) in lookups {
+                        black_box(compiled.next_state(*state, *event, *p));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn trigger_events(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trigger_events");
+    for num_states in [10, 100, 1000] {
+        let machines = vec![large_machine(num_states, 0)];
+        let start = Instant::now();
+        let mut f =
+            Framework::new_with_rng(&machines, 0.0, 0.0, 1420, start, StdRng::seed_from_u64(1))
+                .unwrap();
+        let events = [
+            TriggerEvent::NonPaddingSent { bytes_sent: 1420 },
+            TriggerEvent::NonPaddingRecv { bytes_recv: 1420 },
+            TriggerEvent::PaddingSent {
+                bytes_sent: 1420,
+                machine: MachineId::from_raw(0),
+            },
+            TriggerEvent::PaddingRecv { bytes_recv: 1420 },
+        ];
+        let mut elapsed = 0;
+
+        group.bench_function(BenchmarkId::from_parameter(num_states), |b| {
+            b.iter(|| {
+                elapsed += 1;
+                let now = start + Duration::from_micros(elapsed);
+                black_box(f.trigger_events(&events, now).count());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, next_state, trigger_events);
+criterion_main!(benches);
@@ -0,0 +1,362 @@
+//! Static analysis of machines, to review them before use, see [`analyze()`].
+//!
+//! The analysis is conservative about which events can occur: events from the
+//! application (sent and received packets and MTU updates) and blocking
+//! (possibly by other machines) can always occur, while
+//! [`Event::PaddingSent`] requires a reachable state that pads,
+//! [`Event::TimerBegin`] and [`Event::TimerEnd`] a reachable state that
+//! updates the timer, [`Event::CounterZero`] reachable states that can make a
+//! counter nonzero and then zero (by incrementing or setting it, and then
+//! decrementing or setting it), and [`Event::LimitReached`] that the current
+//! state has a limit.
+
+use crate::constants::*;
+use crate::dist::{Dist, DistType};
+use crate::event::Event;
+use crate::machine::Machine;
+use crate::state::{CounterOperation, State};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The result of [`analyze()`]. States are referred to by index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// States that can never be reached from the start state (index 0).
+    pub unreachable_states: Vec<usize>,
+    /// Reachable states without transitions to another state or
+    /// [`STATEEND`] on any event that can occur in the state: once reached,
+    /// the machine is stuck in the state.
+    pub dead_states: Vec<usize>,
+    /// Reachable states whose action can never fire: the state is never
+    /// transitioned to (only the start state can be reached without a
+    /// transition), or its limit is always zero.
+    pub inactive_states: Vec<usize>,
+    /// If [`STATEEND`] can be reached.
+    pub end_reachable: bool,
+    /// Events without transitions in any reachable state.
+    pub ignored_events: Vec<Event>,
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "unreachable states: {:?}", self.unreachable_states)?;
+        writeln!(f, "dead states: {:?}", self.dead_states)?;
+        writeln!(f, "inactive states: {:?}", self.inactive_states)?;
+        writeln!(f, "end reachable: {}", self.end_reachable)?;
+        write!(f, "ignored events: {:?}", self.ignored_events)
+    }
+}
+
+/// Analyze a valid machine (see [`Machine::validate()`]) for unreachable,
+/// dead, and inactive states, if [`STATEEND`] is reachable, and events the
+/// machine ignores. Transitions are only followed on events that can occur,
+/// see the [module documentation](crate::analysis).
+pub fn analyze(machine: &Machine) -> Analysis {
+    let num_states = machine.states.len();
+
+    // the events that can occur depend on the reachable states, so repeat
+    // until no more states are reachable
+    let mut reachable = vec![false; num_states];
+    let mut entered = vec![false; num_states];
+    let mut end_reachable = false;
+    if num_states > 0 {
+        reachable[0] = true;
+    }
+    loop {
+        let events = machine_events(machine, &reachable);
+        let mut changed = false;
+        for (index, state) in machine.states.iter().enumerate() {
+            if !reachable[index] {
+                continue;
+            }
+            for next in targets(state, &events, num_states) {
+                match next {
+                    STATEEND => end_reachable = true,
+                    STATECANCEL => {}
+                    next => {
+                        entered[next] = true;
+                        if !reachable[next] {
+                            reachable[next] = true;
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let events = machine_events(machine, &reachable);
+    let reachable_states = || (0..num_states).filter(|i| reachable[*i]);
+    Analysis {
+        unreachable_states: (0..num_states).filter(|i| !reachable[*i]).collect(),
+        dead_states: reachable_states()
+            .filter(|i| {
+                targets(&machine.states[*i], &events, num_states)
+                    .all(|next| next == *i || next == STATECANCEL)
+            })
+            .collect(),
+        inactive_states: reachable_states()
+            .filter(|i| !entered[*i] || always_zero(&machine.states[*i].limit))
+            .collect(),
+        end_reachable,
+        ignored_events: Event::iterator()
+            .filter(|e| {
+                reachable_states().all(|i| {
+                    machine.states[i]
+                        .next_state
+                        .get(e)
+                        .is_none_or(|p| p.iter().all(|p| *p <= 0.0))
+                })
+            })
+            .copied()
+            .collect(),
+    }
+}
+
+// The events that can occur for a machine with the reachable states, except
+// LimitReached that depends on the current state.
+fn machine_events(machine: &Machine, reachable: &[bool]) -> Vec<Event> {
+    let states = || {
+        machine
+            .states
+            .iter()
+            .zip(reachable)
+            .filter(|(_, r)| **r)
+            .map(|(s, _)| s)
+    };
+    let pads = states().any(|s| !s.action_is_block && !s.action_is_timer);
+    let timer = states().any(|s| s.action_is_timer);
+    // counters start at zero, so a counter has to become nonzero before it
+    // can reach zero, with values of 1 if not set
+    let counter_zero = (0..NUMCOUNTERS).any(|i| {
+        let updates = || states().filter_map(move |s| s.counters[i]);
+        let nonzero = updates().any(|c| {
+            c.operation != CounterOperation::Decrement && !c.value.is_some_and(|v| always_zero(&v))
+        });
+        let zero = updates().any(|c| match c.operation {
+            CounterOperation::Increment => false,
+            CounterOperation::Decrement => !c.value.is_some_and(|v| always_zero(&v)),
+            CounterOperation::Set => c.value.is_some(),
+        });
+        nonzero && zero
+    });
+    Event::iterator()
+        .filter(|e| match e {
+            Event::PaddingSent => pads,
+            Event::TimerBegin | Event::TimerEnd => timer,
+            Event::CounterZero => counter_zero,
+            Event::LimitReached => false,
+            _ => true,
+        })
+        .copied()
+        .collect()
+}
+
+// The possible next states of a state on the events, and on LimitReached if
+// the state has a limit.
+fn targets<'a>(
+    state: &'a State,
+    events: &'a [Event],
+    num_states: usize,
+) -> impl Iterator<Item = usize> + 'a {
+    let limited = state.limit.dist != DistType::None;
+    state
+        .next_state
+        .iter()
+        .filter(move |(e, _)| events.contains(e) || (limited && **e == Event::LimitReached))
+        .flat_map(move |(_, p)| {
+            p.iter()
+                .enumerate()
+                .filter(|(_, p)| **p > 0.0)
+                .map(move |(i, _)| match i {
+                    i if i < num_states => i,
+                    i if i == num_states => STATECANCEL,
+                    _ => STATEEND,
+                })
+        })
+}
+
+// If a distribution always rounds to zero when sampled, e.g., a limit so the
+// action of the state never fires.
+fn always_zero(limit: &Dist) -> bool {
+    if limit.dist == DistType::None {
+        return false;
+    }
+    // samples are at least zero and at most max (if set), so bounded
+    // distributions have a largest possible sample
+    let upper = match limit.dist {
+        DistType::Uniform => Some(limit.param2),
+        DistType::Binomial => Some(limit.param1),
+        DistType::Beta => Some(1.0),
+        _ => None,
+    }
+    .map(|upper| upper + limit.start);
+    let upper = match (upper, limit.max > 0.0) {
+        (Some(upper), true) => upper.min(limit.max),
+        (Some(upper), false) => upper,
+        (None, true) => limit.max,
+        (None, false) => return false,
+    };
+    upper < 0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::*;
+    use crate::machine::{MachineBuilder, StateBuilder};
+    use crate::state::CounterUpdate;
+    use crate::test_support::uniform;
+
+    #[test]
+    fn analyze_machine() {
+        let limit = |max| uniform(0.0, max);
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .transition(Event::NonPaddingSent, "pad", 1.0)
+                    .transition(Event::TimerEnd, "timer", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new().limit(limit(10.0)).transition(
+                    Event::LimitReached,
+                    "block",
+                    1.0,
+                ),
+            )
+            .state(
+                "block",
+                StateBuilder::new()
+                    .block(Dist::new())
+                    .limit(limit(0.4))
+                    .transition(Event::PaddingRecv, "block", 1.0)
+                    .cancel(Event::BlockingEnd, 1.0),
+            )
+            .state(
+                "timer",
+                StateBuilder::new()
+                    .timer(Dist::new())
+                    .end(Event::TimerEnd, 1.0),
+            )
+            .build()
+            .unwrap();
+
+        let a = analyze(&m);
+        // no reachable state updates the timer, so TimerEnd never occurs
+        assert_eq!(a.unreachable_states, vec![3]);
+        assert_eq!(a.dead_states, vec![2]);
+        assert_eq!(a.inactive_states, vec![0, 2]);
+        assert!(!a.end_reachable);
+        assert_eq!(
+            a.ignored_events,
+            vec![
+                Event::NonPaddingRecv,
+                Event::PaddingSent,
+                Event::BlockingBegin,
+                Event::UpdateMTU,
+                Event::CounterZero,
+                Event::TimerBegin,
+            ]
+        );
+    }
+
+    #[test]
+    fn analyze_events() {
+        // a counter that can become nonzero and then zero makes CounterZero
+        // possible, which makes the timer state reachable and thereby
+        // TimerEnd, and so on
+        let update = |operation, value| CounterUpdate { operation, value };
+        // always rounds to zero
+        let zero = uniform(0.0, 0.4);
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .block(Dist::new())
+                    .counter(0, update(CounterOperation::Increment, None))
+                    .transition(Event::NonPaddingSent, "count", 1.0),
+            )
+            .state(
+                "count",
+                StateBuilder::new()
+                    .block(Dist::new())
+                    .counter(0, update(CounterOperation::Decrement, None))
+                    .transition(Event::CounterZero, "timer", 1.0),
+            )
+            .state(
+                "timer",
+                StateBuilder::new()
+                    .timer(Dist::new())
+                    .transition(Event::TimerEnd, "pad", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .transition(Event::PaddingSent, "start", 0.5)
+                    .end(Event::PaddingSent, 0.5),
+            )
+            .build()
+            .unwrap();
+
+        let a = analyze(&m);
+        assert!(a.unreachable_states.is_empty());
+        assert!(a.dead_states.is_empty());
+        assert!(a.inactive_states.is_empty());
+        assert!(a.end_reachable);
+
+        // a counter that is only decremented, or not updated, stays zero, so
+        // only the first two states are reachable
+        let mut m = m;
+        let unreachable = |m: &Machine| {
+            let a = analyze(m);
+            a.unreachable_states == vec![2, 3]
+                && a.dead_states == vec![1]
+                && a.inactive_states == vec![0]
+                && !a.end_reachable
+        };
+        m.states[0].counters[0] = None;
+        assert!(unreachable(&m));
+        m.states[1].counters[0] = None;
+        assert!(unreachable(&m));
+        // also if it is set or incremented on another counter, or by zero
+        m.states[0].counters[1] = Some(update(CounterOperation::Increment, None));
+        m.states[1].counters[0] = Some(update(CounterOperation::Decrement, None));
+        assert!(unreachable(&m));
+        m.states[0].counters[0] = Some(update(CounterOperation::Set, Some(zero)));
+        assert!(unreachable(&m));
+
+        // setting a counter to a nonzero value, and then maybe to zero
+        m.states[0].counters[0] = Some(update(CounterOperation::Set, None));
+        assert!(analyze(&m).unreachable_states.is_empty());
+        m.states[1].counters[0] = Some(update(CounterOperation::Set, Some(zero)));
+        assert!(analyze(&m).unreachable_states.is_empty());
+        m.states[1].counters[0] = Some(update(CounterOperation::Set, None));
+        assert!(unreachable(&m));
+    }
+
+    #[test]
+    fn limits() {
+        let mut d = Dist {
+            dist: DistType::Uniform,
+            param1: 0.0,
+            param2: 0.4,
+            start: 0.0,
+            max: 0.0,
+        };
+        assert!(always_zero(&d));
+        d.start = 1.0;
+        assert!(!always_zero(&d));
+        d.max = 0.3;
+        assert!(always_zero(&d));
+        d.dist = DistType::Normal;
+        assert!(always_zero(&d));
+        d.max = 0.0;
+        assert!(!always_zero(&d));
+        assert!(!always_zero(&Dist::new()));
+    }
+}
@@ -0,0 +1,114 @@
+//! A padding and blocking budget shared by many frameworks, see
+//! [`SharedBudget`]. Only available on targets with 64-bit atomics.
+
+use crate::error::FrameworkError;
+use crate::framework::validate_fractions;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// A thread-safe budget that many [`Framework`](crate::framework::Framework)
+/// instances can share (e.g., one framework per tunnel on a host), set with
+/// [`Framework::with_shared_budget()`](crate::framework::Framework::with_shared_budget)
+/// using an [`Arc`](alloc::sync::Arc). The budget accounts for the padding,
+/// non-padding, and blocking of all frameworks, and its limits are checked by
+/// each framework on top of its own limits.
+///
+/// The blocking fraction is the total duration blocked divided by the total
+/// running duration of all frameworks sharing the budget.
+#[derive(Debug)]
+pub struct SharedBudget {
+    max_padding_frac: f64,
+    max_blocking_frac: f64,
+    max_padding_bytes: u64,
+    padding_sent: AtomicU64,
+    nonpadding_sent: AtomicU64,
+    blocking_microsec: AtomicU64,
+    running_microsec: AtomicU64,
+}
+
+impl SharedBudget {
+    /// Create a new budget with the maximum fractions of padding and blocking
+    /// (zero for no limit) and the maximum number of padding bytes (zero for
+    /// no limit). Returns an error if the fractions are not [0.0, 1.0].
+    pub fn new(
+        max_padding_frac: f64,
+        max_blocking_frac: f64,
+        max_padding_bytes: u64,
+    ) -> Result<Self, FrameworkError> {
+        validate_fractions(max_padding_frac, max_blocking_frac)?;
+        Ok(SharedBudget {
+            max_padding_frac,
+            max_blocking_frac,
+            max_padding_bytes,
+            padding_sent: AtomicU64::new(0),
+            nonpadding_sent: AtomicU64::new(0),
+            blocking_microsec: AtomicU64::new(0),
+            running_microsec: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the number of padding bytes sent.
+    pub fn padding_sent(&self) -> u64 {
+        self.padding_sent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of non-padding bytes sent.
+    pub fn nonpadding_sent(&self) -> u64 {
+        self.nonpadding_sent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total duration blocked.
+    pub fn blocking_duration(&self) -> Duration {
+        Duration::from_micros(self.blocking_microsec.load(Ordering::Relaxed))
+    }
+
+    /// Returns the total running duration.
+    pub fn running_duration(&self) -> Duration {
+        Duration::from_micros(self.running_microsec.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn add_padding(&self, bytes: u64) {
+        self.padding_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_nonpadding(&self, bytes: u64) {
+        self.nonpadding_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_time(&self, elapsed: Duration, blocking: bool) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        if micros == 0 {
+            return;
+        }
+        self.running_microsec.fetch_add(micros, Ordering::Relaxed);
+        if blocking {
+            self.blocking_microsec.fetch_add(micros, Ordering::Relaxed);
+        }
+    }
+
+    /// True if the maximum number of padding bytes has been sent.
+    pub(crate) fn padding_bytes_exceeded(&self) -> bool {
+        self.max_padding_bytes > 0 && self.padding_sent() >= self.max_padding_bytes
+    }
+
+    /// True if the maximum fraction of padding has been reached.
+    pub(crate) fn padding_frac_exceeded(&self) -> bool {
+        if self.max_padding_frac == 0.0 {
+            return false;
+        }
+        let padding = self.padding_sent();
+        let total = padding.saturating_add(self.nonpadding_sent());
+        total > 0 && padding as f64 / total as f64 >= self.max_padding_frac
+    }
+
+    /// True if the maximum fraction of blocking has been reached.
+    pub(crate) fn blocking_frac_exceeded(&self) -> bool {
+        if self.max_blocking_frac == 0.0 {
+            return false;
+        }
+        let running = self.running_microsec.load(Ordering::Relaxed);
+        running > 0
+            && self.blocking_microsec.load(Ordering::Relaxed) as f64 / running as f64
+                >= self.max_blocking_frac
+    }
+}
@@ -0,0 +1,296 @@
+//! A compiled runtime representation of a [`Machine`], see
+//! [`CompiledMachine`].
+
+use crate::constants::*;
+use crate::dist::{Dist, PreparedDist};
+use crate::error::{MachineError, COUNTERFIELDS};
+use crate::event::Event;
+use crate::machine::Machine;
+use crate::state;
+use alloc::vec;
+use alloc::vec::Vec;
+use rand::RngCore;
+
+/// The number of events, see [`Event::iterator()`].
+const NUMEVENTS: usize = 11;
+
+/// A [`Machine`] compiled for sampling. For transitions, for each state and
+/// event, a dense table points to a sparse array of cumulative probabilities
+/// that is binary searched, instead of a map lookup and a linear scan over all
+/// states. The distributions of each state are prepared once, see
+/// [`PreparedDist`]. Created by the
+/// [`Framework`](crate::framework::Framework) for each machine, or once for
+/// many frameworks by
+/// [`ValidatedMachines`](crate::machine::ValidatedMachines).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompiledMachine {
+    // the range in transitions for each state and event
+    events: Vec<[(usize, usize); NUMEVENTS]>,
+    // (cumulative probability, next state) for transitions with a probability
+    // above zero
+    transitions: Vec<(f64, usize)>,
+    states: Vec<CompiledState>,
+}
+
+/// The prepared distributions of a [`State`](crate::state::State), sampled
+/// like the state.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CompiledState {
+    timeout: PreparedDist,
+    action: PreparedDist,
+    limit: PreparedDist,
+    counters: [Option<PreparedDist>; NUMCOUNTERS],
+}
+
+impl CompiledState {
+    /// Sample a timeout.
+    pub(crate) fn sample_timeout<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        state::sample_timeout(&self.timeout, rng)
+    }
+
+    /// Sample a limit.
+    pub(crate) fn sample_limit<R: RngCore + ?Sized>(&self, rng: &mut R) -> u64 {
+        state::sample_limit(&self.limit, rng)
+    }
+
+    /// Sample a size for a padding action.
+    pub(crate) fn sample_size<R: RngCore + ?Sized>(&self, mtu: u64, rng: &mut R) -> u64 {
+        state::sample_size(&self.action, mtu, rng)
+    }
+
+    /// Sample a blocking duration for a blocking action.
+    pub(crate) fn sample_block<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        state::sample_block(&self.action, rng)
+    }
+
+    /// Sample a timer duration for a timer action.
+    pub(crate) fn sample_timer<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        state::sample_timer(&self.action, rng)
+    }
+
+    /// Sample the value of the update of the counter with the given index.
+    pub(crate) fn sample_counter_value<R: RngCore + ?Sized>(
+        &self,
+        counter: usize,
+        rng: &mut R,
+    ) -> u64 {
+        state::sample_counter_value(self.counters[counter].as_ref(), rng)
+    }
+}
+
+impl CompiledMachine {
+    /// Compile the machine. Returns an error if a distribution is not valid,
+    /// otherwise the machine SHOULD be valid (see [`Machine::validate()`]).
+    pub fn new(machine: &Machine) -> Result<Self, MachineError> {
+        let num_states = machine.states.len();
+        let mut events = vec![[(0, 0); NUMEVENTS]; num_states];
+        let mut transitions = vec![];
+
+        for (state, ranges) in machine.states.iter().zip(events.iter_mut()) {
+            for (event, probabilities) in &state.next_state {
+                let start = transitions.len();
+                let mut total = 0.0;
+                for (i, p) in probabilities.iter().enumerate() {
+                    // same order of additions as the probabilities, so the
+                    // same sampled probability picks the same state
+                    total += p;
+                    if *p <= 0.0 {
+                        continue;
+                    }
+                    // the last two are pseudo-states
+                    let next = match i {
+                        i if i < num_states => i,
+                        i if i == num_states => STATECANCEL,
+                        _ => STATEEND,
+                    };
+                    transitions.push((total, next));
+                }
+                ranges[event_index(*event)] = (start, transitions.len());
+            }
+        }
+
+        let states = machine
+            .states
+            .iter()
+            .enumerate()
+            .map(|(index, state)| {
+                let prepare = |dist: &Dist, field: &'static str| {
+                    dist.prepare().map_err(|e| e.in_dist(index, field))
+                };
+                let mut counters = [None; NUMCOUNTERS];
+                for (i, (counter, update)) in counters.iter_mut().zip(&state.counters).enumerate() {
+                    if let Some(value) = update.and_then(|u| u.value) {
+                        *counter = Some(prepare(&value, COUNTERFIELDS[i])?);
+                    }
+                }
+                Ok(CompiledState {
+                    timeout: prepare(&state.timeout, "timeout")?,
+                    action: prepare(&state.action, "action")?,
+                    limit: prepare(&state.limit, "limit")?,
+                    counters,
+                })
+            })
+            .collect::<Result<_, MachineError>>()?;
+
+        Ok(CompiledMachine {
+            events,
+            transitions,
+            states,
+        })
+    }
+
+    /// Returns the prepared distributions of a state.
+    pub(crate) fn state(&self, state: usize) -> &CompiledState {
+        &self.states[state]
+    }
+
+    /// Returns the next state when in `state` on `event`, given a probability
+    /// `p` sampled uniformly from [0.0, 1.0), or `None` if there is no
+    /// transition. The next state may be [`STATECANCEL`] or [`STATEEND`].
+    pub fn next_state(&self, state: usize, event: Event, p: f64) -> Option<usize> {
+        sample_transition(self.transitions(state, event)?, p)
+    }
+
+    /// Returns the transitions of `state` on `event`, or `None` if there are
+    /// none (so that no probability needs to be sampled).
+    pub(crate) fn transitions(&self, state: usize, event: Event) -> Option<&[(f64, usize)]> {
+        let (start, end) = self.events.get(state)?[event_index(event)];
+        (start < end).then(|| &self.transitions[start..end])
+    }
+}
+
+/// Returns the next state of the first transition with a cumulative
+/// probability of at least `p`, or `None` for the remaining probability up
+/// until 1.0 (a no-op transition).
+pub(crate) fn sample_transition(transitions: &[(f64, usize)], p: f64) -> Option<usize> {
+    let i = transitions.partition_point(|(total, _)| *total < p);
+    transitions.get(i).map(|(_, next)| *next)
+}
+
+fn event_index(event: Event) -> usize {
+    event as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiled::*;
+    use crate::state::State;
+    use crate::window::Window;
+    use std::collections::HashMap;
+
+    #[test]
+    fn event_indices() {
+        assert_eq!(Event::iterator().len(), NUMEVENTS);
+        for (i, e) in Event::iterator().enumerate() {
+            assert_eq!(event_index(*e), i);
+        }
+    }
+
+    #[test]
+    fn compiled_next_state() {
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 0.25);
+        e.insert(STATECANCEL, 0.25);
+        e.insert(STATEEND, 0.25);
+        t.insert(Event::PaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let s1 = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), num_states);
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: false,
+        };
+        let c = CompiledMachine::new(&m).unwrap();
+
+        assert_eq!(c.next_state(0, Event::PaddingSent, 0.0), Some(1));
+        assert_eq!(c.next_state(0, Event::PaddingSent, 0.25), Some(1));
+        assert_eq!(c.next_state(0, Event::PaddingSent, 0.3), Some(STATECANCEL));
+        assert_eq!(c.next_state(0, Event::PaddingSent, 0.7), Some(STATEEND));
+        // the remaining probability is a no-op transition
+        assert_eq!(c.next_state(0, Event::PaddingSent, 0.9), None);
+        assert_eq!(c.next_state(0, Event::PaddingRecv, 0.1), None);
+        assert_eq!(c.next_state(1, Event::PaddingSent, 0.1), None);
+        assert!(c.transitions(1, Event::PaddingSent).is_none());
+        assert!(c.transitions(2, Event::PaddingSent).is_none());
+    }
+
+    #[test]
+    fn compiled_matches_linear_scan() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        // the previous sampling, from a linear scan of the probabilities
+        fn linear_scan(state: &State, event: Event, p: f64) -> Option<usize> {
+            let probabilities = state.next_state.get(&event)?;
+            let mut total = 0.0;
+            for (i, prob) in probabilities.iter().enumerate() {
+                total += prob;
+                if p <= total {
+                    return Some(match probabilities.len() - i {
+                        1 => STATEEND,
+                        2 => STATECANCEL,
+                        _ => i,
+                    });
+                }
+            }
+            None
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let num_states = 20;
+        let states: Vec<State> = (0..num_states)
+            .map(|_| {
+                let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+                for event in Event::iterator() {
+                    if rng.gen_bool(0.3) {
+                        continue;
+                    }
+                    let mut e: HashMap<usize, f64> = HashMap::new();
+                    let mut left = 1.0;
+                    for _ in 0..rng.gen_range(1..5) {
+                        let next = match rng.gen_range(0..num_states + 2) {
+                            i if i == num_states => STATECANCEL,
+                            i if i == num_states + 1 => STATEEND,
+                            i => i,
+                        };
+                        let p = rng.gen_range(0.0..left);
+                        left -= p;
+                        e.insert(next, p);
+                    }
+                    t.insert(*event, e);
+                }
+                State::new(t, num_states)
+            })
+            .collect();
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states,
+            include_small_packets: false,
+        };
+        let c = CompiledMachine::new(&m).unwrap();
+
+        for _ in 0..10_000 {
+            let state = rng.gen_range(0..num_states);
+            let event = *Event::iterator().nth(rng.gen_range(0..NUMEVENTS)).unwrap();
+            let p = rng.gen::<f64>();
+            assert_eq!(
+                c.next_state(state, event, p),
+                linear_scan(&m.states[state], event, p)
+            );
+        }
+    }
+}
@@ -2,7 +2,16 @@
 
 /// The highest possible version of a [`Machine`](crate::machine) supported by
 /// this framework.
-pub const VERSION: u8 = 1;
+pub const VERSION: u8 = 4;
+
+/// The version of the JSON format of a [`Machine`](crate::machine::Machine)
+/// created by this framework, see [`json`](crate::json).
+#[cfg(feature = "json")]
+pub const JSONVERSION: u32 = 1;
+
+/// The version of [`FrameworkSnapshot`](crate::framework::FrameworkSnapshot)
+/// created by this framework.
+pub const SNAPSHOTVERSION: u16 = 1;
 
 /// The maximum sampled timeout in a [`State`](crate::state), set to a day in
 /// microseconds.
@@ -15,6 +24,29 @@ pub const MAXSAMPLEDBLOCK: f64 = 1000.0 * 1000.0 * 60.0;
 /// The size (in bytes) of a serialized [`State`](crate::state).
 pub const SERIALIZEDDISTSIZE: usize = 2 + 8 * 4;
 
+/// The size (in bytes) of a serialized
+/// [`CounterUpdate`](crate::state::CounterUpdate): an operation byte and a
+/// distribution.
+pub const SERIALIZEDCOUNTERUPDATESIZE: usize = 1 + SERIALIZEDDISTSIZE;
+
+/// The maximum length of a [`Window::Microsec`](crate::window::Window), set to
+/// a year in microseconds.
+pub const MAXWINDOWMICROSEC: u64 = 365 * 24 * 60 * 60 * 1000 * 1000;
+
+/// The size (in bytes) of a serialized [`Window`](crate::window::Window): a
+/// kind byte and a value.
+pub const SERIALIZEDWINDOWSIZE: usize = 1 + 8;
+
+/// The size (in bytes) of a serialized optional
+/// [`RateLimit`](crate::rate::RateLimit): a flag byte, the rate, and the burst.
+pub const SERIALIZEDRATELIMITSIZE: usize = 1 + 8 + 8;
+
+/// The maximum size (in bytes) of a decompressed serialized
+/// [`Machine`](crate::machine), of the states of a parsed sparse machine,
+/// and of the transition matrices of the states of a parsed sparse or JSON
+/// machine. This bounds memory use when parsing untrusted machines.
+pub const MAXMACHINESIZE: usize = 256 * 1024 * 1024;
+
 /// The maximum possible sampled limit of a [`State`](crate::state). This is the
 /// default if no limit dist is specified (in practice, the same as no limit).
 pub const STATELIMITMAX: u64 = u64::MAX;
@@ -37,3 +69,27 @@ pub const STATEMAX: usize = STATECANCEL - 1;
 /// WireGuard keepalive), see [`include_small_packets`](crate::machine) in
 /// [`Machine`](crate::machine).
 pub const MAXSMALLPACKETSIZE: u64 = 52;
+
+/// The number of counters of a [`Machine`](crate::machine) that can be updated
+/// on transition to a [`State`](crate::state).
+pub const NUMCOUNTERS: usize = 2;
+
+/// The maximum number of transitions on
+/// [`CounterZero`](crate::event::Event::CounterZero) caused by a single
+/// triggered event. Further counters reaching zero are ignored, so that a
+/// [`Machine`](crate::machine) cannot loop forever between states that zero
+/// their counters.
+pub const MAXCOUNTERZERO: usize = 100;
+
+/// The maximum number of nodes of the chain of
+/// [`estimate()`](crate::overhead::estimate), a few per state of the
+/// [`Machine`](crate::machine). The expected time to end takes time cubic in
+/// the number of nodes, so this bounds the time and memory taken to estimate
+/// the overhead of a large machine.
+pub const MAXESTIMATENODES: usize = 1_000;
+
+/// The maximum number of steps of the chain of
+/// [`estimate()`](crate::overhead::estimate), which grow with the duration
+/// times the largest rate of the chain. This bounds the time taken to estimate
+/// the overhead of a machine.
+pub const MAXESTIMATESTEPS: u64 = 10_000_000;
@@ -1,17 +1,22 @@
 //! Distributions sampled as part of a [`State`](crate::state).
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use byteorder::ByteOrder;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::LittleEndian;
+use core::error::Error;
+use core::fmt;
+use rand::RngCore;
 use rand_distr::{
     Beta, Binomial, Distribution, Gamma, Geometric, LogNormal, Normal, Pareto, Poisson, Uniform,
     Weibull,
 };
 use serde::{Deserialize, Serialize};
-use simple_error::bail;
-use std::error::Error;
-use std::fmt;
 
 use crate::constants::*;
+use crate::error::MachineError;
 
 /// DistType represents the type of a [`Dist`]. Supports a wide range of
 /// different distributions. Some are probably useless and some are probably
@@ -48,6 +53,18 @@ pub enum DistType {
     /// Beta distribution with set alpha and beta.
     Beta,
 }
+impl DistType {
+    /// Returns the number of parameters read by the type, the first
+    /// (`param1`) or both (`param1` and `param2`).
+    pub(crate) fn num_params(&self) -> usize {
+        match self {
+            DistType::None => 0,
+            DistType::Geometric | DistType::Poisson => 1,
+            _ => 2,
+        }
+    }
+}
+
 impl fmt::Display for DistType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -202,163 +219,223 @@ impl Dist {
     }
 
     /// Validate that the parameters are valid for the set [`DistType`].
-    pub fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        match &self.dist {
-            DistType::None => {}
-            DistType::Uniform => {
-                if self.param1 > self.param2 {
-                    bail!("for Uniform dist, got param2 > param1")
-                }
-            }
-            DistType::Normal => {
-                let mean = self.param1;
-                let stdev = self.param2;
-                Normal::new(mean, stdev)?;
-            }
-            DistType::LogNormal => {
-                let mu = self.param1;
-                let sigma = self.param2;
-                LogNormal::new(mu, sigma)?;
-            }
-            DistType::Binomial => {
-                let trials = self.param1 as u64;
-                let probability = self.param2;
-                Binomial::new(trials, probability)?;
-            }
-            DistType::Geometric => {
-                let probability = self.param1;
-                Geometric::new(probability)?;
-            }
-            DistType::Pareto => {
-                let scale = self.param1;
-                let shape = self.param2;
-                Pareto::new(scale, shape)?;
-            }
-            DistType::Poisson => {
-                let lambda = self.param1;
-                Poisson::new(lambda)?;
-            }
-            DistType::Weibull => {
-                let scale = self.param1;
-                let shape = self.param2;
-                Weibull::new(scale, shape)?;
-            }
-            DistType::Gamma => {
-                let scale = self.param1;
-                let shape = self.param2;
-                // note order below in inversed from others for some reason in rand_distr
-                Gamma::new(shape, scale)?;
-            }
-            DistType::Beta => {
-                let alpha = self.param1;
-                let beta = self.param2;
-                Beta::new(alpha, beta)?;
-            }
-        };
-
-        Ok(())
+    pub fn validate(&self) -> Result<(), MachineError> {
+        self.prepare().map(|_| ())
     }
 
-    /// Sample the distribution. May panic if not valid (see [`Self::validate()`]).
-    pub fn sample(self) -> f64 {
-        let mut r: f64 = 0.0;
-        r = r.max(self.distsample() + self.start);
-        if self.max > 0.0 {
-            return r.min(self.max);
+    /// Prepare the distribution for sampling, constructing the underlying
+    /// sampler once. Returns an error if the parameters are not valid for the
+    /// set [`DistType`].
+    pub fn prepare(&self) -> Result<PreparedDist, MachineError> {
+        fn invalid(e: impl fmt::Display) -> MachineError {
+            MachineError::InvalidParameters(e.to_string())
         }
-        r
-    }
 
-    fn distsample(self) -> f64 {
-        match &self.dist {
-            DistType::None => f64::MAX,
+        // start and max clamp every sample, the rest depend on the type
+        let params = [self.start, self.max, self.param1, self.param2];
+        if !params[..2 + self.dist.num_params()]
+            .iter()
+            .all(|v| v.is_finite())
+        {
+            return Err(invalid("got a parameter that is NaN or infinite"));
+        }
+        let sampler = match &self.dist {
+            DistType::None => Sampler::None,
             DistType::Uniform => {
                 let min = self.param1;
                 let max = self.param2;
+                if min > max {
+                    return Err(invalid("for Uniform dist, got param2 > param1"));
+                }
                 // special common case for handcrafted machines, also not
                 // supported by rand_dist::Uniform
                 if min == max {
-                    return min;
+                    Sampler::Constant(min)
+                } else if !(max - min).is_finite() {
+                    return Err(invalid("for Uniform dist, got a range that is not finite"));
+                } else {
+                    Sampler::Uniform(Uniform::new(min, max))
                 }
-                Uniform::new(min, max).sample(&mut rand::thread_rng())
             }
             DistType::Normal => {
                 let mean = self.param1;
                 let stdev = self.param2;
-                Normal::new(mean, stdev)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Normal(Normal::new(mean, stdev).map_err(invalid)?)
             }
             DistType::LogNormal => {
                 let mu = self.param1;
                 let sigma = self.param2;
-                LogNormal::new(mu, sigma)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::LogNormal(LogNormal::new(mu, sigma).map_err(invalid)?)
             }
             DistType::Binomial => {
                 let trials = self.param1 as u64;
                 let probability = self.param2;
-                Binomial::new(trials, probability)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng()) as f64
+                Sampler::Binomial(Binomial::new(trials, probability).map_err(invalid)?)
             }
             DistType::Geometric => {
                 let probability = self.param1;
-                Geometric::new(probability)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng()) as f64
+                // rand_distr::Geometric never finishes setting up if the
+                // probability is positive but 1.0 - probability rounds to 1.0
+                if probability > 0.0 && 1.0 - probability == 1.0 {
+                    return Err(invalid(
+                        "for Geometric dist, got a probability too small to sample",
+                    ));
+                }
+                Sampler::Geometric(Geometric::new(probability).map_err(invalid)?)
             }
             DistType::Pareto => {
                 let scale = self.param1;
                 let shape = self.param2;
-                Pareto::new(scale, shape)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Pareto(Pareto::new(scale, shape).map_err(invalid)?)
             }
             DistType::Poisson => {
                 let lambda = self.param1;
-                Poisson::new(lambda)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Poisson(Poisson::new(lambda).map_err(invalid)?)
             }
             DistType::Weibull => {
                 let scale = self.param1;
                 let shape = self.param2;
-                Weibull::new(scale, shape)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Weibull(Weibull::new(scale, shape).map_err(invalid)?)
             }
             DistType::Gamma => {
                 let scale = self.param1;
                 let shape = self.param2;
                 // note order below in inversed from others for some reason in rand_distr
-                Gamma::new(shape, scale)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Gamma(Gamma::new(shape, scale).map_err(invalid)?)
             }
             DistType::Beta => {
                 let alpha = self.param1;
                 let beta = self.param2;
-                Beta::new(alpha, beta)
-                    .unwrap()
-                    .sample(&mut rand::thread_rng())
+                Sampler::Beta(Beta::new(alpha, beta).map_err(invalid)?)
             }
+        };
+
+        Ok(PreparedDist {
+            dist: *self,
+            sampler,
+        })
+    }
+
+    /// Sample the distribution using the provided source of randomness. The
+    /// distribution is prepared on every call, see [`Self::prepare()`] to
+    /// sample repeatedly. May panic if not valid (see [`Self::validate()`]).
+    pub fn sample<R: RngCore + ?Sized>(self, rng: &mut R) -> f64 {
+        self.prepare().unwrap().sample(rng)
+    }
+
+    /// Like [`Self::sample()`], but returns an error if not valid (see
+    /// [`Self::validate()`]) instead of panicking.
+    pub fn try_sample<R: RngCore + ?Sized>(self, rng: &mut R) -> Result<f64, MachineError> {
+        Ok(self.prepare()?.sample(rng))
+    }
+
+    /// Returns the approximate mean of samples: the mean of the distribution
+    /// plus start, at least 0.0 and at most max (if set). Exact only if no
+    /// samples are clamped. As when sampling, the mean of [`DistType::None`]
+    /// is [`f64::MAX`]. Distributions without a finite mean, e.g.,
+    /// [`DistType::Pareto`] with a shape of at most 1.0, have an infinite mean.
+    pub fn mean(&self) -> f64 {
+        let mean = match self.dist {
+            DistType::None => f64::MAX,
+            DistType::Uniform => (self.param1 + self.param2) / 2.0,
+            DistType::Normal => self.param1,
+            DistType::LogNormal => libm::exp(self.param1 + self.param2 * self.param2 / 2.0),
+            DistType::Binomial => libm::trunc(self.param1) * self.param2,
+            DistType::Geometric => (1.0 - self.param1) / self.param1,
+            DistType::Pareto if self.param2 > 1.0 => {
+                self.param1 * self.param2 / (self.param2 - 1.0)
+            }
+            DistType::Pareto => f64::INFINITY,
+            DistType::Poisson => self.param1,
+            DistType::Weibull => self.param1 * libm::tgamma(1.0 + 1.0 / self.param2),
+            DistType::Gamma => self.param1 * self.param2,
+            DistType::Beta => self.param1 / (self.param1 + self.param2),
+        };
+        let mean = (mean + self.start).max(0.0);
+        if self.max > 0.0 {
+            return mean.min(self.max);
         }
+        mean
     }
 
     /// Returns the serialized distribution of [`SERIALIZEDDISTSIZE`] bytes.
     pub fn serialize(self) -> Vec<u8> {
-        let mut wtr = vec![];
-        wtr.write_u16::<LittleEndian>(self.dist.into()).unwrap();
-        wtr.write_f64::<LittleEndian>(self.param1).unwrap();
-        wtr.write_f64::<LittleEndian>(self.param2).unwrap();
-        wtr.write_f64::<LittleEndian>(self.start).unwrap();
-        wtr.write_f64::<LittleEndian>(self.max).unwrap();
+        let mut wtr = Vec::with_capacity(SERIALIZEDDISTSIZE);
+        wtr.extend_from_slice(&u16::from(self.dist).to_le_bytes());
+        wtr.extend_from_slice(&self.param1.to_le_bytes());
+        wtr.extend_from_slice(&self.param2.to_le_bytes());
+        wtr.extend_from_slice(&self.start.to_le_bytes());
+        wtr.extend_from_slice(&self.max.to_le_bytes());
         wtr
     }
 }
 
+/// A [`Dist`] prepared for sampling by [`Dist::prepare()`]. Sampling is
+/// allocation-free and cannot panic. Prepared distributions are equal if their
+/// [`Dist`] are equal.
+#[derive(Debug, Clone, Copy)]
+pub struct PreparedDist {
+    dist: Dist,
+    sampler: Sampler,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Sampler {
+    None,
+    Constant(f64),
+    Uniform(Uniform<f64>),
+    Normal(Normal<f64>),
+    LogNormal(LogNormal<f64>),
+    Binomial(Binomial),
+    Geometric(Geometric),
+    Pareto(Pareto<f64>),
+    Poisson(Poisson<f64>),
+    Weibull(Weibull<f64>),
+    Gamma(Gamma<f64>),
+    Beta(Beta<f64>),
+}
+
+impl PartialEq for PreparedDist {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist == other.dist
+    }
+}
+
+impl PreparedDist {
+    /// Returns the prepared distribution.
+    pub fn dist(&self) -> Dist {
+        self.dist
+    }
+
+    /// Sample the distribution using the provided source of randomness, with
+    /// the same result as [`Dist::sample()`].
+    pub fn sample<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        let mut r: f64 = 0.0;
+        r = r.max(self.distsample(rng) + self.dist.start);
+        if self.dist.max > 0.0 {
+            return r.min(self.dist.max);
+        }
+        r
+    }
+
+    fn distsample<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        match &self.sampler {
+            Sampler::None => f64::MAX,
+            Sampler::Constant(value) => *value,
+            Sampler::Uniform(d) => d.sample(rng),
+            Sampler::Normal(d) => d.sample(rng),
+            Sampler::LogNormal(d) => d.sample(rng),
+            Sampler::Binomial(d) => d.sample(rng) as f64,
+            Sampler::Geometric(d) => d.sample(rng) as f64,
+            Sampler::Pareto(d) => d.sample(rng),
+            Sampler::Poisson(d) => d.sample(rng),
+            Sampler::Weibull(d) => d.sample(rng),
+            Sampler::Gamma(d) => d.sample(rng),
+            Sampler::Beta(d) => d.sample(rng),
+        }
+    }
+}
+
 /// Try to parse a [`Dist`] from the provided bytes.
 pub fn parse_dist(buf: Vec<u8>) -> Result<Dist, Box<dyn Error + Send + Sync>> {
     if buf.len() < SERIALIZEDDISTSIZE {
@@ -428,7 +505,7 @@ mod tests {
             max: 0.0,
         };
 
-        assert_eq!(d.sample(), f64::MAX);
+        assert_eq!(d.sample(&mut rand::thread_rng()), f64::MAX);
     }
 
     #[test]
@@ -458,4 +535,132 @@ mod tests {
             assert_eq!(d.dist, r.dist);
         }
     }
+
+    #[test]
+    fn prepared_matches_dist() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        for i in 0..=10 {
+            let d = Dist {
+                dist: DistType::from(i),
+                param1: 0.5,
+                param2: 0.8,
+                start: 1.0,
+                max: 100.0,
+            };
+            let p = d.prepare().unwrap();
+            assert_eq!(p.dist(), d);
+            let mut r1 = StdRng::seed_from_u64(i as u64);
+            let mut r2 = StdRng::seed_from_u64(i as u64);
+            for _ in 0..100 {
+                assert_eq!(d.sample(&mut r1), p.sample(&mut r2));
+            }
+        }
+    }
+
+    #[test]
+    fn mean() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut d = Dist {
+            dist: DistType::None,
+            param1: 0.5,
+            param2: 3.0,
+            start: 1.0,
+            max: 0.0,
+        };
+        assert_eq!(d.mean(), f64::MAX);
+        d.dist = DistType::Uniform;
+        assert_eq!(d.mean(), 2.75);
+        d.max = 2.0;
+        assert_eq!(d.mean(), 2.0);
+        d.max = 0.0;
+        d.start = -10.0;
+        assert_eq!(d.mean(), 0.0);
+        d.dist = DistType::Pareto;
+        d.param2 = 1.0;
+        assert_eq!(d.mean(), f64::INFINITY);
+
+        // close to the mean of samples that are not clamped
+        let mut rng = StdRng::seed_from_u64(0);
+        for (i, param1, param2) in [
+            (1, 0.5, 3.0),
+            (2, 0.5, 3.0),
+            (3, 0.5, 0.5),
+            (4, 10.0, 0.5),
+            (5, 0.5, 0.0),
+            (6, 0.5, 5.0),
+            (7, 0.5, 0.0),
+            (8, 0.5, 3.0),
+            (9, 0.5, 3.0),
+            (10, 0.5, 3.0),
+        ] {
+            let d = Dist {
+                dist: DistType::from(i),
+                param1,
+                param2,
+                start: 10.0,
+                max: 0.0,
+            };
+            let n = 100_000;
+            let sampled = (0..n).map(|_| d.sample(&mut rng)).sum::<f64>() / n as f64;
+            assert!(
+                (sampled - d.mean()).abs() < 0.01 * d.mean(),
+                "{}: sampled {}, mean {}",
+                d,
+                sampled,
+                d.mean()
+            );
+        }
+    }
+
+    #[test]
+    fn prepare_invalid() {
+        let mut d = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        assert!(d.prepare().is_err());
+        assert!(d.try_sample(&mut rand::thread_rng()).is_err());
+        // would panic in the sampler if not rejected
+        d.param1 = f64::NAN;
+        assert!(d.prepare().is_err());
+        d.param1 = f64::MIN;
+        d.param2 = f64::MAX;
+        assert!(d.prepare().is_err());
+        // only the parameters read by the type have to be finite
+        for v in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut d = Dist::new();
+            d.param1 = v;
+            d.param2 = v;
+            assert!(d.prepare().is_ok());
+            d.max = v;
+            assert!(d.prepare().is_err());
+            let mut d = Dist {
+                dist: DistType::Poisson,
+                param1: 1.0,
+                param2: v,
+                start: 0.0,
+                max: 0.0,
+            };
+            assert!(d.prepare().is_ok());
+            d.param1 = v;
+            assert!(d.prepare().is_err());
+        }
+        d.dist = DistType::Beta;
+        d.param1 = -1.0;
+        d.param2 = 1.0;
+        assert!(d.prepare().is_err());
+        // would never finish setting up the sampler if not rejected
+        d.dist = DistType::Geometric;
+        d.param1 = 1e-20;
+        assert!(d.prepare().is_err());
+        d.param1 = 1e-10;
+        assert!(d.prepare().is_ok());
+    }
 }
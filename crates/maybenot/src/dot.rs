@@ -0,0 +1,233 @@
+//! Export of machines as Graphviz DOT graphs, see [`Machine::to_dot()`].
+//!
+//! States are nodes identified by index, labelled with their action, timeout,
+//! and limit, and any flags and counter updates. The start state (index 0) is
+//! pointed to by an unlabelled edge. Transitions are edges labelled with the
+//! [`Event`] and probability, with one edge per event and target. The
+//! pseudo-states [`STATEEND`](crate::constants::STATEEND) and
+//! [`STATECANCEL`](crate::constants::STATECANCEL) are the special nodes `end`
+//! and `cancel`, present if any transition leads to them. An example machine
+//! that, after the first sent packet, pads until it ends or reaches its limit
+//! (render with, e.g., `dot -Tsvg`):
+//!
+//! ```text
+//! digraph machine {
+//!   start [shape=point];
+//!   start -> 0;
+//!   0 [shape=box, label="0\naction: pad MTU\ntimeout: none\nlimit: none"];
+//!   1 [shape=box, label="1\naction: pad MTU\ntimeout: Uniform [0.0, 1000.0]\nlimit: Uniform [1.0, 10.0]"];
+//!   end [shape=doublecircle, label="END"];
+//!   0 -> 1 [label="NonPaddingSent 1"];
+//!   1 -> 1 [label="PaddingSent 0.9"];
+//!   1 -> end [label="PaddingSent 0.1"];
+//! }
+//! ```
+
+use crate::dist::DistType;
+use crate::event::Event;
+use crate::machine::Machine;
+use crate::state::State;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+impl Machine {
+    /// Export the machine as a Graphviz DOT graph, see [`dot`](crate::dot).
+    /// The machine SHOULD be valid (see [`Machine::validate()`]): transitions
+    /// to states that do not exist are left out.
+    pub fn to_dot(&self) -> String {
+        let num_states = self.states.len();
+        let mut out = String::from("digraph machine {\n");
+        out.push_str("  start [shape=point];\n");
+        if num_states > 0 {
+            out.push_str("  start -> 0;\n");
+        }
+        for (index, state) in self.states.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "  {} [shape=box, label=\"{}\"];",
+                index,
+                escape(&state_label(index, state))
+            );
+        }
+
+        // the pseudo-states are only added if used
+        let (mut cancel, mut end) = (false, false);
+        let mut edges = String::new();
+        for (index, state) in self.states.iter().enumerate() {
+            for event in Event::iterator() {
+                let Some(probabilities) = state.next_state.get(event) else {
+                    continue;
+                };
+                for (next, p) in probabilities.iter().enumerate() {
+                    if *p <= 0.0 {
+                        continue;
+                    }
+                    let next = match next {
+                        next if next < num_states => format!("{}", next),
+                        next if next == num_states => {
+                            cancel = true;
+                            String::from("cancel")
+                        }
+                        next if next == num_states + 1 => {
+                            end = true;
+                            String::from("end")
+                        }
+                        _ => continue,
+                    };
+                    let _ = writeln!(
+                        edges,
+                        "  {} -> {} [label=\"{} {}\"];",
+                        index, next, event, p
+                    );
+                }
+            }
+        }
+        if cancel {
+            out.push_str("  cancel [shape=octagon, label=\"CANCEL\"];\n");
+        }
+        if end {
+            out.push_str("  end [shape=doublecircle, label=\"END\"];\n");
+        }
+        out.push_str(&edges);
+        out.push_str("}\n");
+        out
+    }
+}
+
+// The label of a state, with lines separated by newlines.
+fn state_label(index: usize, state: &State) -> String {
+    let action = match (state.action_is_block, state.action_is_timer) {
+        (true, _) => "block",
+        (_, true) => "timer",
+        _ => "pad",
+    };
+    // padding without a size distribution is of the MTU
+    let size = match (action, state.action.dist) {
+        ("pad", DistType::None) => String::from("MTU"),
+        _ => format!("{}", state.action),
+    };
+    let mut lines = vec![format!("{}", index), format!("action: {} {}", action, size)];
+    if !state.action_is_timer {
+        lines.push(format!("timeout: {}", state.timeout));
+    }
+    lines.push(format!("limit: {}", state.limit));
+
+    let flags: Vec<&str> = [
+        (state.bypass, "bypass"),
+        (state.replace, "replace"),
+        (state.limit_includes_nonpadding, "limit includes nonpadding"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect();
+    if !flags.is_empty() {
+        lines.push(flags.join(", "));
+    }
+    for (i, update) in state.counters.iter().enumerate() {
+        if let Some(update) = update {
+            let value = match update.value {
+                Some(value) => format!("{}", value),
+                None => String::from("1"),
+            };
+            lines.push(format!("counters[{}]: {:?} {}", i, update.operation, value));
+        }
+    }
+    lines.join("\n")
+}
+
+// Escape a string for a quoted DOT string, with newlines as line breaks.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dist::Dist;
+    use crate::dot::*;
+    use crate::machine::{MachineBuilder, StateBuilder};
+    use crate::state::{CounterOperation, CounterUpdate};
+    use crate::test_support::uniform;
+
+    #[test]
+    fn to_dot() {
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .timeout(uniform(0.0, 1000.0))
+                    .limit(uniform(1.0, 10.0))
+                    .transition(Event::PaddingSent, "pad", 0.9)
+                    .end(Event::PaddingSent, 0.1),
+            )
+            .build()
+            .unwrap();
+
+        // the example in the module documentation
+        assert_eq!(
+            m.to_dot(),
+            "digraph machine {
+  start [shape=point];
+  start -> 0;
+  0 [shape=box, label=\"0\\naction: pad MTU\\ntimeout: none\\nlimit: none\"];
+  1 [shape=box, label=\"1\\naction: pad MTU\\ntimeout: Uniform [0.0, 1000.0]\\nlimit: Uniform [1.0, 10.0]\"];
+  end [shape=doublecircle, label=\"END\"];
+  0 -> 1 [label=\"NonPaddingSent 1\"];
+  1 -> 1 [label=\"PaddingSent 0.9\"];
+  1 -> end [label=\"PaddingSent 0.1\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn to_dot_labels() {
+        let m = MachineBuilder::new()
+            .state(
+                "block",
+                StateBuilder::new()
+                    .block(Dist::new())
+                    .bypass(true)
+                    .replace(true)
+                    .counter(
+                        1,
+                        CounterUpdate {
+                            operation: CounterOperation::Decrement,
+                            value: None,
+                        },
+                    )
+                    .cancel(Event::BlockingEnd, 0.5)
+                    .transition(Event::CounterZero, "timer", 1.0),
+            )
+            .state(
+                "timer",
+                StateBuilder::new()
+                    .timer(Dist::new())
+                    .transition(Event::TimerEnd, "block", 1.0),
+            )
+            .build()
+            .unwrap();
+
+        let dot = m.to_dot();
+        assert!(dot.contains(
+            "  0 [shape=box, label=\"0\\naction: block none\\ntimeout: none\\nlimit: none\\nbypass, replace\\ncounters[1]: Decrement 1\"];\n"
+        ));
+        // timers have no timeout
+        assert!(dot.contains("  1 [shape=box, label=\"1\\naction: timer none\\nlimit: none\"];\n"));
+        assert!(dot.contains("  cancel [shape=octagon, label=\"CANCEL\"];\n"));
+        assert!(!dot.contains("  end "));
+        assert!(dot.contains("  0 -> cancel [label=\"BlockingEnd 0.5\"];\n"));
+        assert!(dot.contains("  0 -> 1 [label=\"CounterZero 1\"];\n"));
+        assert!(dot.contains("  1 -> 0 [label=\"TimerEnd 1\"];\n"));
+
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
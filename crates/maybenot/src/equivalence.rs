@@ -0,0 +1,561 @@
+//! Minimization and equivalence checking of machines, see
+//! [`Machine::minimize()`] and [`Machine::is_equivalent()`].
+//!
+//! States are equivalent if they have identical parameters (all fields but
+//! the transitions) and, on each event, the same probabilities of
+//! transitioning to each class of equivalent states,
+//! [`STATECANCEL`](crate::constants::STATECANCEL), and
+//! [`STATEEND`](crate::constants::STATEEND). Merging equivalent states turns
+//! transitions between them into transitions to the same state, which only
+//! makes a difference for states with a limit: a transition to another state
+//! samples a new limit, while a transition to the same state may decrement it.
+//! States with a limit are therefore not merged if they have transitions to
+//! other states they are equivalent to.
+//!
+//! Parameters and probabilities are compared exactly, so states are only
+//! equivalent if, e.g., their probabilities to states in the same class sum
+//! to exactly the same value.
+
+use crate::dist::{Dist, DistType};
+use crate::event::Event;
+use crate::machine::Machine;
+use crate::state::State;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+impl Machine {
+    /// Returns the machine with all equivalent states merged, see
+    /// [`equivalence`](crate::equivalence). Merged states take the place of
+    /// the first of their states, so the start state remains at index 0, and
+    /// the order of states is otherwise kept. The machine SHOULD be valid (see
+    /// [`Machine::validate()`]).
+    pub fn minimize(&self) -> Machine {
+        let num_states = self.states.len();
+        let classes = refine(
+            num_states,
+            |i| parameters(&self.states[i]),
+            |i, classes| merge_signature(&self.states[i], i, classes),
+        );
+        let num_classes = classes.iter().max().map_or(0, |c| c + 1);
+
+        let mut states = Vec::with_capacity(num_classes);
+        for (state, class) in self.states.iter().zip(&classes) {
+            // classes are numbered in order of their first state
+            if *class < states.len() {
+                continue;
+            }
+            let mut merged = state.clone();
+            for probabilities in merged.next_state.values_mut() {
+                let mut p = vec![0.0; num_classes + 2];
+                for (i, probability) in probabilities.iter().enumerate() {
+                    let next = match classes.get(i) {
+                        Some(class) => *class,
+                        None => num_classes + i - num_states,
+                    };
+                    if let Some(p) = p.get_mut(next) {
+                        *p += probability;
+                    }
+                }
+                *probabilities = p;
+            }
+            states.push(merged);
+        }
+
+        Machine {
+            allowed_padding_bytes: self.allowed_padding_bytes,
+            max_padding_frac: self.max_padding_frac,
+            padding_window: self.padding_window,
+            padding_rate_limit: self.padding_rate_limit,
+            allowed_blocked_microsec: self.allowed_blocked_microsec,
+            max_blocking_frac: self.max_blocking_frac,
+            blocking_window: self.blocking_window,
+            states,
+            include_small_packets: self.include_small_packets,
+        }
+    }
+
+    /// Returns true if the machines are structurally equivalent: equal up to
+    /// renumbering the states other than the start state (index 0). Unlike
+    /// comparing [`Machine::name()`], the order of states does not matter. To
+    /// also consider machines with equivalent states equivalent, compare
+    /// minimized machines (see [`Machine::minimize()`]).
+    pub fn is_equivalent(&self, other: &Machine) -> bool {
+        if self.allowed_padding_bytes != other.allowed_padding_bytes
+            || self.max_padding_frac != other.max_padding_frac
+            || self.padding_window != other.padding_window
+            || self.padding_rate_limit != other.padding_rate_limit
+            || self.allowed_blocked_microsec != other.allowed_blocked_microsec
+            || self.max_blocking_frac != other.max_blocking_frac
+            || self.blocking_window != other.blocking_window
+            || self.include_small_packets != other.include_small_packets
+            || self.states.len() != other.states.len()
+        {
+            return false;
+        }
+        let n = self.states.len();
+
+        // refine the states of both machines together: only states in the
+        // same class can be renumbered to each other
+        let state = |i: usize| match i < n {
+            true => (&self.states[i], i),
+            false => (&other.states[i - n], i - n),
+        };
+        let classes = refine(
+            2 * n,
+            |i| (state(i).1 == 0, parameters(state(i).0)),
+            |i, classes| {
+                let offset = if i < n { 0 } else { n };
+                let (state, index) = state(i);
+                structure_signature(state, index, &classes[offset..offset + n])
+            },
+        );
+        let mut counts = BTreeMap::new();
+        for (i, class) in classes.iter().enumerate() {
+            *counts.entry(*class).or_insert(0) += if i < n { 1 } else { -1 };
+        }
+        if counts.values().any(|c| *c != 0) {
+            return false;
+        }
+
+        // search for a renumbering, state by state, backtracking when a state
+        // has no consistent match
+        let mut renumbered: Vec<usize> = Vec::with_capacity(n);
+        let mut used = vec![false; n];
+        let mut next = vec![0; n + 1];
+        loop {
+            let i = renumbered.len();
+            if i == n {
+                return true;
+            }
+            // the start state must remain the start state
+            let candidates = next[i]..if i == 0 { 1 } else { n };
+            let found = candidates.into_iter().find(|j| {
+                !used[*j]
+                    && classes[i] == classes[n + j]
+                    && consistent(self, other, &renumbered, i, *j)
+            });
+            match found {
+                Some(j) => {
+                    next[i] = j + 1;
+                    next[i + 1] = 0;
+                    renumbered.push(j);
+                    used[j] = true;
+                }
+                None => {
+                    let Some(j) = renumbered.pop() else {
+                        return false;
+                    };
+                    used[j] = false;
+                }
+            }
+        }
+    }
+}
+
+// Partitions states into classes, numbered in order of their first state,
+// starting with states with equal keys and then splitting classes by the
+// signatures of states until no class splits.
+fn refine<K: Ord, S: Ord>(
+    num_states: usize,
+    key: impl Fn(usize) -> K,
+    signature: impl Fn(usize, &[usize]) -> S,
+) -> Vec<usize> {
+    let mut classes = number(num_states, key);
+    loop {
+        let refined = number(num_states, |i| (classes[i], signature(i, &classes)));
+        // numbered the same way, so equal unless a class split
+        if refined == classes {
+            return classes;
+        }
+        classes = refined;
+    }
+}
+
+fn number<K: Ord>(num_states: usize, key: impl Fn(usize) -> K) -> Vec<usize> {
+    let mut numbers = BTreeMap::new();
+    (0..num_states)
+        .map(|i| {
+            let next = numbers.len();
+            *numbers.entry(key(i)).or_insert(next)
+        })
+        .collect()
+}
+
+// The parameters of a state, all fields but the transitions, for exact
+// comparison.
+fn parameters(state: &State) -> Vec<u64> {
+    let dist = |key: &mut Vec<u64>, d: &Dist| {
+        key.push(u16::from(d.dist) as u64);
+        key.extend([d.param1, d.param2, d.start, d.max].map(f64::to_bits));
+    };
+    let mut key = vec![
+        state.action_is_block as u64,
+        state.action_is_timer as u64,
+        state.bypass as u64,
+        state.replace as u64,
+        state.limit_includes_nonpadding as u64,
+    ];
+    dist(&mut key, &state.timeout);
+    dist(&mut key, &state.action);
+    dist(&mut key, &state.limit);
+    for update in &state.counters {
+        match update {
+            None => key.push(0),
+            Some(update) => {
+                key.push(1 + update.operation as u64);
+                match &update.value {
+                    None => key.push(0),
+                    Some(value) => {
+                        key.push(1);
+                        dist(&mut key, value);
+                    }
+                }
+            }
+        }
+    }
+    key
+}
+
+// Where a transition goes, by class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Class(usize),
+    // to the state itself
+    Same,
+    Cancel,
+    End,
+}
+
+// The targets and probabilities of transitions from a state, with the
+// remaining probabilities (after states) to STATECANCEL and STATEEND.
+fn targets<'a>(
+    state: &'a State,
+    index: usize,
+    classes: &'a [usize],
+    same: bool,
+) -> impl Iterator<Item = (Event, Target, f64)> + 'a {
+    state.next_state.iter().flat_map(move |(event, p)| {
+        p.iter()
+            .enumerate()
+            .filter(|(_, p)| **p > 0.0)
+            .map(move |(i, p)| {
+                let target = match classes.get(i) {
+                    _ if same && i == index => Target::Same,
+                    Some(class) => Target::Class(*class),
+                    None if i == classes.len() => Target::Cancel,
+                    None => Target::End,
+                };
+                (*event, target, *p)
+            })
+    })
+}
+
+// The signature of a state for merging: the sum of probabilities to each
+// target on each event. States with a limit and transitions to other states
+// in their class are not merged, so they get a signature of their own.
+fn merge_signature(
+    state: &State,
+    index: usize,
+    classes: &[usize],
+) -> (Option<usize>, Vec<(Event, Target, u64)>) {
+    let limited = state.limit.dist != DistType::None;
+    let mut sums = BTreeMap::new();
+    for (event, target, p) in targets(state, index, classes, limited) {
+        *sums.entry((event, target)).or_insert(0.0) += p;
+    }
+    let own = limited
+        && sums
+            .keys()
+            .any(|(_, t)| *t == Target::Class(classes[index]));
+    let signature = sums
+        .into_iter()
+        .map(|((event, target), p)| (event, target, f64::to_bits(p)))
+        .collect();
+    (own.then_some(index), signature)
+}
+
+// The signature of a state for structural equivalence: the transitions to
+// each target on each event.
+fn structure_signature(
+    state: &State,
+    index: usize,
+    classes: &[usize],
+) -> Vec<(Event, Target, u64)> {
+    let mut signature: Vec<_> = targets(state, index, classes, true)
+        .map(|(event, target, p)| (event, target, f64::to_bits(p)))
+        .collect();
+    signature.sort();
+    signature
+}
+
+// If renumbering state i of a to j of b is consistent with the states
+// renumbered so far.
+fn consistent(a: &Machine, b: &Machine, renumbered: &[usize], i: usize, j: usize) -> bool {
+    let n = a.states.len();
+    let p = |m: &Machine, from: usize, event: &Event, to: usize| {
+        m.states[from]
+            .next_state
+            .get(event)
+            .and_then(|p| p.get(to))
+            .copied()
+            .unwrap_or(0.0)
+    };
+    Event::iterator().all(|e| {
+        [i, n, n + 1]
+            .into_iter()
+            .zip([j, n, n + 1])
+            .all(|(to_a, to_b)| p(a, i, e, to_a) == p(b, j, e, to_b))
+            && renumbered
+                .iter()
+                .enumerate()
+                .all(|(k, l)| p(a, i, e, k) == p(b, j, e, *l) && p(a, k, e, i) == p(b, *l, e, j))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::equivalence::*;
+    use crate::machine::{MachineBuilder, StateBuilder};
+    use crate::overhead::{estimate, TrafficModel};
+    use crate::test_support::constant;
+    use core::time::Duration;
+
+    // The machine with states renumbered, state i to order[i].
+    fn renumber(m: &Machine, order: &[usize]) -> Machine {
+        let n = m.states.len();
+        let mut states = m.states.clone();
+        for (i, state) in m.states.iter().enumerate() {
+            let mut state = state.clone();
+            for p in state.next_state.values_mut() {
+                let mut renumbered = p.clone();
+                for (j, next) in order.iter().enumerate() {
+                    renumbered[*next] = p[j];
+                }
+                renumbered[n..].copy_from_slice(&p[n..]);
+                *p = renumbered;
+            }
+            states[order[i]] = state;
+        }
+        Machine {
+            states,
+            ..m.clone()
+        }
+    }
+
+    #[test]
+    fn minimize() {
+        // two identical padding states, and two padding states that alternate
+        // between each other
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .transition(Event::NonPaddingSent, "a", 0.5)
+                    .transition(Event::NonPaddingSent, "b", 0.5)
+                    .transition(Event::NonPaddingRecv, "c", 1.0),
+            )
+            .state(
+                "a",
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "start", 0.5)
+                    .end(Event::PaddingSent, 0.5),
+            )
+            .state(
+                "c",
+                StateBuilder::new()
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "d", 1.0)
+                    .end(Event::NonPaddingSent, 1.0),
+            )
+            .state(
+                "b",
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "start", 0.5)
+                    .end(Event::PaddingSent, 0.5),
+            )
+            .state(
+                "d",
+                StateBuilder::new()
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "c", 1.0)
+                    .end(Event::NonPaddingSent, 1.0),
+            )
+            .build()
+            .unwrap();
+
+        let min = m.minimize();
+        let expected = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .transition(Event::NonPaddingSent, "a", 1.0)
+                    .transition(Event::NonPaddingRecv, "c", 1.0),
+            )
+            .state(
+                "a",
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "start", 0.5)
+                    .end(Event::PaddingSent, 0.5),
+            )
+            .state(
+                "c",
+                StateBuilder::new()
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "c", 1.0)
+                    .end(Event::NonPaddingSent, 1.0),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(min, expected);
+        assert_eq!(min.minimize(), min);
+        min.validate().unwrap();
+
+        // the same overhead
+        let traffic = TrafficModel {
+            nonpadding_sent: 100.0,
+            nonpadding_recv: 100.0,
+            padding_recv: 0.0,
+            mtu: 1420,
+        };
+        let e1 = estimate(&m, &traffic, Duration::from_millis(100)).unwrap();
+        let e2 = estimate(&min, &traffic, Duration::from_millis(100)).unwrap();
+        assert!((e1.padding_bytes - e2.padding_bytes).abs() < 1e-6 * e1.padding_bytes);
+        assert!((e1.microsec_to_end - e2.microsec_to_end).abs() < 1e-6 * e1.microsec_to_end);
+
+        // minimized machines are equivalent
+        let order = [0, 4, 2, 1, 3];
+        assert!(renumber(&m, &order).minimize().is_equivalent(&min));
+    }
+
+    #[test]
+    fn minimize_limits() {
+        // states with a limit that alternate are not merged
+        let pad = |next| {
+            StateBuilder::new()
+                .limit(constant(5.0))
+                .transition(Event::PaddingSent, next, 1.0)
+        };
+        let m = MachineBuilder::new()
+            .state("a", pad("b"))
+            .state("b", pad("a"))
+            .build()
+            .unwrap();
+        assert_eq!(m.minimize(), m);
+
+        // but can be if they stay in the same state
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .transition(Event::NonPaddingSent, "a", 0.5)
+                    .transition(Event::NonPaddingSent, "b", 0.5),
+            )
+            .state("a", pad("a"))
+            .state("b", pad("b"))
+            .build()
+            .unwrap();
+        let min = m.minimize();
+        assert_eq!(min.states.len(), 2);
+        assert_eq!(min.states[0].next_state[&Event::NonPaddingSent][1], 1.0);
+        assert_eq!(min.states[1].next_state[&Event::PaddingSent][1], 1.0);
+    }
+
+    #[test]
+    fn equivalent() {
+        // a ring of identical states, where only the transitions to the end
+        // distinguish the states
+        let n = 8;
+        let mut b = MachineBuilder::new();
+        for i in 0..n {
+            let next = format!("{}", (i + 1) % n);
+            let mut s = StateBuilder::new()
+                .transition(Event::PaddingSent, &next, 0.5)
+                .transition(Event::NonPaddingSent, format!("{}", (i + 3) % n), 0.5);
+            if i == 5 {
+                s = s.end(Event::PaddingRecv, 1.0);
+            }
+            b = b.state(format!("{}", i), s);
+        }
+        let m = b.build().unwrap();
+        assert!(m.is_equivalent(&m));
+
+        // any order keeping the start state
+        use rand::rngs::StdRng;
+        use rand::seq::SliceRandom;
+        use rand::SeedableRng;
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            let mut order: Vec<usize> = (1..n).collect();
+            order.shuffle(&mut rng);
+            order.insert(0, 0);
+            let r = renumber(&m, &order);
+            assert!(m.is_equivalent(&r));
+            assert!(r.is_equivalent(&m));
+        }
+
+        // but not a different start state
+        let order: Vec<usize> = (0..n).map(|i| (i + 1) % n).collect();
+        assert!(!m.is_equivalent(&renumber(&m, &order)));
+
+        // or different probabilities, parameters, or machines
+        let mut other = m.clone();
+        other.states[5]
+            .next_state
+            .get_mut(&Event::PaddingRecv)
+            .unwrap()[n + 1] = 0.5;
+        assert!(!m.is_equivalent(&other));
+        let mut other = m.clone();
+        other.states[3].timeout = constant(1.0);
+        assert!(!m.is_equivalent(&other));
+        let mut other = m.clone();
+        other.max_padding_frac = 0.5;
+        assert!(!m.is_equivalent(&other));
+        let mut other = m.clone();
+        other.states.pop();
+        assert!(!m.is_equivalent(&other));
+
+        // states that are only told apart by searching: from the start state
+        // to one cycle of six states, or two cycles of three
+        let cycles = |next: fn(usize) -> usize| {
+            let mut start = StateBuilder::new();
+            for i in 1..=6 {
+                start = start.transition(Event::NonPaddingSent, format!("{}", i), 1.0 / 6.0);
+            }
+            let mut b = MachineBuilder::new().state("start", start);
+            for i in 1..=6 {
+                b = b.state(
+                    format!("{}", i),
+                    StateBuilder::new().transition(Event::PaddingSent, format!("{}", next(i)), 1.0),
+                );
+            }
+            b.build().unwrap()
+        };
+        let one = cycles(|i| i % 6 + 1);
+        let two = cycles(|i| if i % 3 == 0 { i - 2 } else { i + 1 });
+        assert!(!one.is_equivalent(&two));
+        assert!(one.is_equivalent(&renumber(&one, &[0, 2, 3, 4, 5, 6, 1])));
+        assert!(two.is_equivalent(&renumber(&two, &[0, 4, 5, 6, 1, 2, 3])));
+
+        // end and cancel are not states
+        let m = MachineBuilder::new()
+            .state("start", StateBuilder::new().end(Event::PaddingSent, 1.0))
+            .build()
+            .unwrap();
+        let mut other = m.clone();
+        other.states[0]
+            .next_state
+            .insert(Event::PaddingSent, vec![0.0, 0.0, 1.0]);
+        assert!(m.is_equivalent(&other));
+        other.states[0]
+            .next_state
+            .insert(Event::PaddingSent, vec![0.0, 1.0, 0.0]);
+        assert!(!m.is_equivalent(&other));
+    }
+}
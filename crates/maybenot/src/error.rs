@@ -0,0 +1,231 @@
+//! Errors when parsing and validating machines, see [`MachineError`], and when
+//! creating and changing frameworks, see [`FrameworkError`].
+
+use crate::constants::NUMCOUNTERS;
+use crate::event::Event;
+use crate::framework::MachineId;
+use alloc::string::String;
+use core::fmt;
+
+// the names of the counter fields of a state, for errors
+pub(crate) const COUNTERFIELDS: [&str; NUMCOUNTERS] = ["counters[0]", "counters[1]"];
+
+/// An error when parsing, validating, or serializing a
+/// [`Machine`](crate::machine::Machine), to report and branch on the exact
+/// cause.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum MachineError {
+    /// The machine string is not valid hex.
+    InvalidHex(String),
+    /// The decoded machine string is not valid zlib.
+    InvalidZlib(String),
+    /// The version of the format is not supported.
+    UnsupportedVersion(u16),
+    /// The serialized machine, or a part of it, is too small or too large.
+    Truncated(String),
+    /// The serialized machine is larger than allowed by its number of states
+    /// or [`MAXMACHINESIZE`](crate::constants::MAXMACHINESIZE).
+    TooLarge {
+        /// The maximum size in bytes.
+        max: usize,
+    },
+    /// The serialized machine has a value that is not valid in the format,
+    /// e.g., an unknown flag.
+    InvalidEncoding(String),
+    /// The JSON of the machine is invalid, see [`json`](crate::json). The
+    /// location is a line and column, or a path to the invalid field.
+    InvalidJson {
+        /// Where the problem is.
+        location: String,
+        /// What the problem is.
+        reason: String,
+    },
+    /// The machine cannot be serialized in the requested version of the
+    /// format.
+    Unrepresentable {
+        /// The requested version.
+        version: u16,
+        /// What cannot be represented.
+        reason: String,
+    },
+    /// The machine has no states.
+    NoStates,
+    /// The machine has more states than supported.
+    TooManyStates {
+        /// The maximum number of states.
+        max: usize,
+        /// The number of states of the machine.
+        found: usize,
+    },
+    /// A fraction of the machine is not in [0.0, 1.0].
+    InvalidFraction {
+        /// The name of the field.
+        field: &'static str,
+        /// The invalid fraction.
+        value: f64,
+    },
+    /// A window of the machine is invalid.
+    InvalidWindow {
+        /// The name of the field.
+        field: &'static str,
+        /// What the problem is.
+        reason: String,
+    },
+    /// The padding rate limit of the machine is invalid.
+    InvalidRateLimit(String),
+    /// A distribution has parameters that are invalid for its type, see
+    /// [`Dist::validate()`](crate::dist::Dist::validate). Reported as
+    /// [`MachineError::InvalidDist`] for the distributions of a machine.
+    InvalidParameters(String),
+    /// A distribution of a state has invalid parameters.
+    InvalidDist {
+        /// The index of the state.
+        state: usize,
+        /// The name of the field, e.g., `action` or `counters[0]`.
+        field: &'static str,
+        /// What the problem is.
+        reason: String,
+    },
+    /// The probability vector of a state on an event is invalid.
+    InvalidProbabilities {
+        /// The index of the state.
+        state: usize,
+        /// The event of the probability vector.
+        event: Event,
+        /// What the problem is.
+        reason: String,
+    },
+    /// A state has an invalid combination of flags or counter updates.
+    InvalidState {
+        /// The index of the state.
+        state: usize,
+        /// What the problem is.
+        reason: String,
+    },
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::InvalidHex(e) => write!(f, "failed to decode hex, {}", e),
+            MachineError::InvalidZlib(e) => write!(f, "not in zlib format, {}", e),
+            MachineError::UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
+            MachineError::Truncated(e) => write!(f, "truncated machine, {}", e),
+            MachineError::TooLarge { max } => {
+                write!(f, "machine too large, max is {} bytes", max)
+            }
+            MachineError::InvalidEncoding(e) => write!(f, "invalid encoding, {}", e),
+            MachineError::InvalidJson { location, reason } => {
+                write!(f, "{}: {}", location, reason)
+            }
+            MachineError::Unrepresentable { version, reason } => {
+                write!(f, "cannot serialize as version {}, {}", version, reason)
+            }
+            MachineError::NoStates => write!(f, "a machine must have at least one state"),
+            MachineError::TooManyStates { max, found } => {
+                write!(f, "too many states, max is {}, found {}", max, found)
+            }
+            MachineError::InvalidFraction { field, value } => {
+                write!(f, "{} has to be [0.0, 1.0], got {}", field, value)
+            }
+            MachineError::InvalidWindow { field, reason } => write!(f, "{}: {}", field, reason),
+            MachineError::InvalidRateLimit(e) => write!(f, "padding_rate_limit: {}", e),
+            MachineError::InvalidParameters(e) => write!(f, "invalid parameters, {}", e),
+            MachineError::InvalidDist {
+                state,
+                field,
+                reason,
+            } => write!(f, "state at index {}, {}: {}", state, field, reason),
+            MachineError::InvalidProbabilities {
+                state,
+                event,
+                reason,
+            } => write!(f, "state at index {}, event {}: {}", state, event, reason),
+            MachineError::InvalidState { state, reason } => {
+                write!(f, "state at index {}: {}", state, reason)
+            }
+        }
+    }
+}
+
+impl core::error::Error for MachineError {}
+
+impl MachineError {
+    // an error of a distribution in the given field of the state at the index
+    pub(crate) fn in_dist(self, state: usize, field: &'static str) -> MachineError {
+        match self {
+            MachineError::InvalidParameters(reason) => MachineError::InvalidDist {
+                state,
+                field,
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+/// An error when creating a [`Framework`](crate::framework::Framework),
+/// changing its limits or machines, or restoring it from a
+/// [`FrameworkSnapshot`](crate::framework::FrameworkSnapshot).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum FrameworkError {
+    /// A machine of the framework is invalid.
+    InvalidMachine {
+        /// The index of the machine.
+        index: usize,
+        /// What is invalid.
+        error: MachineError,
+    },
+    /// A limit of the framework (a max fraction, window, or padding rate
+    /// limit) is invalid, as it would be for a machine.
+    InvalidLimit(MachineError),
+    /// The version of the snapshot is not supported.
+    UnsupportedSnapshotVersion(u16),
+    /// The snapshot is not of a framework running the same machines.
+    SnapshotMismatch(String),
+    /// A time in the snapshot cannot be represented relative to the reference.
+    UnrepresentableTime,
+    /// There is no machine with the identifier in the framework.
+    UnknownMachine(MachineId),
+    /// The machine with the identifier has already been retired.
+    RetiredMachine(MachineId),
+}
+
+impl fmt::Display for FrameworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameworkError::InvalidMachine { index, error } => {
+                write!(f, "machine at index {}: {}", index, error)
+            }
+            FrameworkError::InvalidLimit(e) => write!(f, "{}", e),
+            FrameworkError::UnsupportedSnapshotVersion(v) => {
+                write!(f, "unsupported snapshot version: {}", v)
+            }
+            FrameworkError::SnapshotMismatch(e) => {
+                write!(f, "snapshot does not match the framework, {}", e)
+            }
+            FrameworkError::UnrepresentableTime => write!(
+                f,
+                "time in snapshot cannot be represented relative to the reference"
+            ),
+            FrameworkError::UnknownMachine(id) => {
+                write!(f, "no machine with id {}", id.into_raw())
+            }
+            FrameworkError::RetiredMachine(id) => {
+                write!(f, "machine with id {} already retired", id.into_raw())
+            }
+        }
+    }
+}
+
+impl core::error::Error for FrameworkError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            FrameworkError::InvalidMachine { error, .. } => Some(error),
+            FrameworkError::InvalidLimit(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -3,12 +3,12 @@
 use serde::{Deserialize, Serialize};
 
 use self::Event::*;
-use std::fmt;
-use std::hash::Hash;
-use std::slice::Iter;
+use core::fmt;
+use core::hash::Hash;
+use core::slice::Iter;
 
 /// An Event may trigger a [`State`](crate::state) transition.
-#[derive(Debug, Eq, Hash, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub enum Event {
     /// NonPaddingRecv is when we received non-padding.
     NonPaddingRecv,
@@ -26,6 +26,12 @@ pub enum Event {
     LimitReached,
     /// UpdateMTU is when the MTU of the protected connection was updated.
     UpdateMTU,
+    /// CounterZero is when a counter of the machine reached zero (internal).
+    CounterZero,
+    /// TimerBegin is when the timer of the machine started.
+    TimerBegin,
+    /// TimerEnd is when the timer of the machine expired.
+    TimerEnd,
 }
 
 impl fmt::Display for Event {
@@ -36,7 +42,7 @@ impl fmt::Display for Event {
 
 impl Event {
     pub fn iterator() -> Iter<'static, Event> {
-        static EVENTS: [Event; 8] = [
+        static EVENTS: [Event; 11] = [
             NonPaddingRecv,
             PaddingRecv,
             NonPaddingSent,
@@ -45,9 +51,19 @@ impl Event {
             BlockingEnd,
             LimitReached,
             UpdateMTU,
+            CounterZero,
+            TimerBegin,
+            TimerEnd,
         ];
         EVENTS.iter()
     }
+
+    /// Iterate over the events of version 1 of the machine format, i.e., the
+    /// events that existed before [`Event::CounterZero`], [`Event::TimerBegin`],
+    /// and [`Event::TimerEnd`].
+    pub(crate) fn v1_iterator() -> Iter<'static, Event> {
+        Self::iterator().as_slice()[..8].iter()
+    }
 }
 
 #[cfg(test)]
@@ -64,4 +80,15 @@ mod tests {
         assert_eq!(Event::LimitReached.to_string(), "LimitReached");
         assert_eq!(Event::UpdateMTU.to_string(), "UpdateMTU");
     }
+
+    #[test]
+    fn v2_events() {
+        assert_eq!(Event::CounterZero.to_string(), "CounterZero");
+        assert_eq!(Event::TimerBegin.to_string(), "TimerBegin");
+        assert_eq!(Event::TimerEnd.to_string(), "TimerEnd");
+        assert_eq!(Event::iterator().len(), 11);
+        assert_eq!(Event::v1_iterator().len(), 8);
+        assert!(!Event::v1_iterator()
+            .any(|e| matches!(e, Event::CounterZero | Event::TimerBegin | Event::TimerEnd)));
+    }
 }
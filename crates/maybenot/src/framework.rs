@@ -22,9 +22,13 @@
 //! // Parse machine, this is a "no-op" machine that does nothing. Typically,
 //! // you should expect to get one or more serialized machines, not build them
 //! // from scratch. The framework takes a vector with zero or more machines as
-//! // input when created. To add or remove a machine, just recreate the
-//! // framework. If you expect to create many instances of the framework for
-//! // the same machines, then share the same vector across framework instances.
+//! // input when created. To add or remove a machine, either recreate the
+//! // framework or, if the framework owns the vector of machines, use
+//! // Framework::add_machine() and Framework::remove_machine() (which keeps
+//! // the global padding and blocking accounting). If you expect to create many
+//! // instances of the framework for the same machines, then share the same
+//! // vector across framework instances, validated once with
+//! // ValidatedMachines::new() so the framework does not validate them again.
 //! // All runtime information is allocated internally in the framework without
 //! // modifying the machines.
 //! let s = "789cedca2101000000c230e85f1a8387009f9e351d051503ca0003";
@@ -68,11 +72,14 @@
 //!     for action in f.trigger_events(&events, Instant::now()) {
 //!         // After triggering all the events, the framework will provide zero
 //!         // or more actions to take, up to a maximum of one action per
-//!         // machine (regardless of the number of events). It is your
-//!         // responsibility to perform those actions according to the
-//!         // specification. To do so, you will need up to one timer per
-//!         // machine. The machine identifier (machine in each Action) uniquely
-//!         // and deterministically maps to a single machine running in the
+//!         // machine (regardless of the number of events). If you need every
+//!         // action caused by a batch of events, use trigger_events_all()
+//!         // instead. It is your responsibility to perform those actions
+//!         // according to the specification. To do so, you will need up to
+//!         // two timers per machine: one for scheduled actions and one for the
+//!         // machine's own timer (see Action::UpdateTimer). The machine
+//!         // identifier (machine in each Action) uniquely and
+//!         // deterministically maps to a single machine running in the
 //!         // framework (so suitable as a key for a data structure storing your
 //!         // timers, e.g., a HashMap<MachineId, SomeTimerDataStructure>).
 //!         match action {
@@ -161,6 +168,22 @@
 //!                 // available to check as part of dealing with Action::InjectPadding
 //!                 // actions (see above).
 //!             }
+//!             Action::UpdateTimer {
+//!                 duration: _,
+//!                 replace: _,
+//!                 machine: _,
+//!             } => {
+//!                 // Set the machine's own timer to expire after the duration.
+//!                 // This timer is separate from the action timer above: do
+//!                 // NOT touch any scheduled padding or blocking action. If
+//!                 // replace is false and the timer is already active, pick
+//!                 // the longest of the duration and the remaining duration.
+//!                 //
+//!                 // If the timer was started or its expiry changed, add
+//!                 // TriggerEvent::TimerBegin { machine: machine } to be
+//!                 // triggered next loop iteration. On expiry, add
+//!                 // TriggerEvent::TimerEnd { machine: machine }.
+//!             }
 //!         }
 //!     }
 //!
@@ -169,17 +192,32 @@
 //!     break;
 //! }
 //! ```
-use simple_error::bail;
-
+#[cfg(target_has_atomic = "64")]
+use crate::budget::SharedBudget;
+use crate::compiled::{sample_transition, CompiledMachine};
 use crate::constants::*;
 use crate::dist::DistType;
+use crate::error::{FrameworkError, MachineError};
 use crate::event::*;
 use crate::machine::*;
-use std::cmp::Ordering;
-use std::error::Error;
-use std::fmt;
-use std::time::Duration;
-use std::time::Instant;
+use crate::observer::{ActionLimit, NoObserver, Observer};
+use crate::rate::{RateLimit, TokenBucket, TokenBucketSnapshot};
+use crate::time::Instant;
+use crate::window::{Window, WindowCounter, WindowCounterSnapshot};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+#[cfg(target_has_atomic = "64")]
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+#[cfg(any(feature = "std", test))]
+use rand::rngs::ThreadRng;
+use rand::Rng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
 
 /// An opaque token representing one machine running inside the framework.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -223,6 +261,14 @@ pub enum TriggerEvent {
     LimitReached { machine: MachineId },
     /// The MTU of the protected connection was updated.
     UpdateMTU { new_mtu: u16 },
+    /// An event triggered internally by the framework when a counter of a
+    /// machine reached zero.
+    CounterZero { machine: MachineId },
+    /// The timer of the machine was started (or its expiry changed) as a
+    /// result of an [`Action::UpdateTimer`].
+    TimerBegin { machine: MachineId },
+    /// The timer of the machine expired.
+    TimerEnd { machine: MachineId },
 }
 
 impl TriggerEvent {
@@ -237,6 +283,9 @@ impl TriggerEvent {
             TriggerEvent::BlockingEnd => e == Event::BlockingEnd,
             TriggerEvent::LimitReached { .. } => e == Event::LimitReached,
             TriggerEvent::UpdateMTU { .. } => e == Event::UpdateMTU,
+            TriggerEvent::CounterZero { .. } => e == Event::CounterZero,
+            TriggerEvent::TimerBegin { .. } => e == Event::TimerBegin,
+            TriggerEvent::TimerEnd { .. } => e == Event::TimerEnd,
         }
     }
 }
@@ -253,6 +302,9 @@ impl fmt::Display for TriggerEvent {
             TriggerEvent::BlockingEnd => write!(f, "be"),
             TriggerEvent::LimitReached { .. } => write!(f, "lr"),
             TriggerEvent::UpdateMTU { new_mtu } => write!(f, "um,{}", new_mtu),
+            TriggerEvent::CounterZero { .. } => write!(f, "cz"),
+            TriggerEvent::TimerBegin { .. } => write!(f, "tb"),
+            TriggerEvent::TimerEnd { .. } => write!(f, "te"),
         }
     }
 }
@@ -300,16 +352,240 @@ pub enum Action {
         replace: bool,
         machine: MachineId,
     },
+    /// Update the timer of the machine to expire after the given duration. The
+    /// timer is independent of the timeout of any scheduled padding or blocking
+    /// action, and updating it does not affect such actions.
+    ///
+    /// The replace flag indicates if the duration should replace any currently
+    /// active timer of the machine. If the flag is false, the longest of the
+    /// two durations MUST be used.
+    ///
+    /// The caller MUST trigger [`TriggerEvent::TimerBegin`] for the machine if
+    /// the timer was started or its expiry changed, and
+    /// [`TriggerEvent::TimerEnd`] for the machine when the timer expires.
+    UpdateTimer {
+        duration: Duration,
+        replace: bool,
+        machine: MachineId,
+    },
+}
+
+impl Action {
+    /// Returns the [`MachineId`] of the machine that produced the action.
+    pub fn machine(&self) -> MachineId {
+        match self {
+            Action::Cancel { machine }
+            | Action::InjectPadding { machine, .. }
+            | Action::BlockOutgoing { machine, .. }
+            | Action::UpdateTimer { machine, .. } => *machine,
+        }
+    }
+}
+
+/// An [`Action`] produced by [`Framework::trigger_events_all()`], tagged with
+/// the index of the [`TriggerEvent`] (in the slice of events passed to the
+/// framework) that caused the action.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TriggeredAction {
+    /// The index of the triggering event in the batch of events.
+    pub event_index: usize,
+    /// The action to be taken.
+    pub action: Action,
 }
 
 #[derive(Debug, Clone)]
-struct MachineRuntime {
+struct MachineRuntime<T> {
+    current_state: usize,
+    state_limit: u64,
+    padding_sent: u64,
+    nonpadding_sent: u64,
+    blocking_duration: Duration,
+    machine_start: T,
+    counters: [u64; NUMCOUNTERS],
+    retired: bool,
+    windowed_padding: WindowCounter<T>,
+    windowed_blocking: WindowCounter<T>,
+    padding_bucket: TokenBucket<T>,
+}
+
+impl<T: Instant> MachineRuntime<T> {
+    fn new<R: RngCore>(
+        machine: &Machine,
+        compiled: &CompiledMachine,
+        current_time: T,
+        rng: &mut R,
+    ) -> Self {
+        MachineRuntime {
+            current_state: 0,
+            state_limit: compiled.state(0).sample_limit(rng),
+            padding_sent: 0,
+            nonpadding_sent: 0,
+            blocking_duration: Duration::from_secs(0),
+            machine_start: current_time,
+            counters: [0; NUMCOUNTERS],
+            retired: false,
+            windowed_padding: WindowCounter::new(current_time),
+            windowed_blocking: WindowCounter::new(current_time),
+            padding_bucket: TokenBucket::new(&machine.padding_rate_limit, current_time),
+        }
+    }
+}
+
+/// A snapshot of the runtime state of a [`Framework`], created by
+/// [`Framework::snapshot()`] and restored by [`Framework::restore()`]. All
+/// points in time are stored relative to a caller-provided reference point, so
+/// a snapshot can be restored in another process (with another clock). The
+/// machines are not part of the snapshot, only their names (see
+/// [`Machine::name()`]) to verify that the snapshot is restored against the
+/// same machines. Nor is the state of the random number generator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameworkSnapshot {
+    /// The version of the snapshot, see [`SNAPSHOTVERSION`].
+    pub version: u16,
+    machines: Vec<MachineSnapshot>,
+    mtu: u16,
+    global_max_padding_frac: f64,
+    global_nonpadding_sent_bytes: u64,
+    global_paddingsent_bytes: u64,
+    global_max_blocking_frac: f64,
+    global_blocking_duration: Duration,
+    global_blocking_active: bool,
+    global_padding_window: Window,
+    global_blocking_window: Window,
+    global_windowed_padding: WindowCounterSnapshot,
+    global_windowed_blocking: WindowCounterSnapshot,
+    global_padding_rate_limit: Option<RateLimit>,
+    global_padding_bucket: TokenBucketSnapshot,
+    // durations before the reference point
+    global_blocking_started: Duration,
+    global_framework_start: Duration,
+    current_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct MachineSnapshot {
+    // None for retired machines
+    name: Option<String>,
     current_state: usize,
     state_limit: u64,
     padding_sent: u64,
     nonpadding_sent: u64,
     blocking_duration: Duration,
-    machine_start: Instant,
+    // duration before the reference point
+    machine_start: Duration,
+    counters: [u64; NUMCOUNTERS],
+    retired: bool,
+    windowed_padding: WindowCounterSnapshot,
+    windowed_blocking: WindowCounterSnapshot,
+    padding_bucket: TokenBucketSnapshot,
+}
+
+/// Statistics on a machine running in a [`Framework`], as of the last time
+/// provided to the framework. See [`Framework::machine_stats()`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineStats {
+    /// The index of the current state of the machine, [`STATEEND`] if the
+    /// machine has ended or been retired.
+    pub current_state: usize,
+    /// The remaining number of actions allowed in the current state.
+    pub state_limit: u64,
+    /// The number of padding bytes sent by the machine.
+    pub padding_sent: u64,
+    /// The number of non-padding bytes sent while the machine was running.
+    pub nonpadding_sent: u64,
+    /// The duration blocking has been active while the machine was running,
+    /// including any ongoing blocking.
+    pub blocking_duration: Duration,
+    /// The duration the machine has been running.
+    pub running_duration: Duration,
+    /// The fraction of padding out of all bytes sent over the padding window
+    /// of the machine, zero if nothing has been sent. Compared against
+    /// [`Machine::max_padding_frac`] once the machine has used up its
+    /// [`Machine::allowed_padding_bytes`].
+    pub padding_frac: f64,
+    /// The fraction of time spent blocking over the blocking window of the
+    /// machine, zero if no time has passed. Compared against
+    /// [`Machine::max_blocking_frac`] once the machine has used up its
+    /// [`Machine::allowed_blocked_microsec`].
+    pub blocking_frac: f64,
+    /// The values of the counters of the machine.
+    pub counters: [u64; NUMCOUNTERS],
+    /// True if the machine has been retired with
+    /// [`Framework::remove_machine()`].
+    pub retired: bool,
+}
+
+/// Global statistics of a [`Framework`], across all of its machines, as of the
+/// last time provided to the framework. See [`Framework::stats()`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameworkStats {
+    /// The number of padding bytes sent.
+    pub padding_sent: u64,
+    /// The number of non-padding bytes sent.
+    pub nonpadding_sent: u64,
+    /// The duration blocking has been active, including any ongoing blocking.
+    pub blocking_duration: Duration,
+    /// True if blocking is currently active.
+    pub blocking_active: bool,
+    /// The duration the framework has been running.
+    pub running_duration: Duration,
+    /// The fraction of padding out of all bytes sent over the padding window
+    /// of the framework, zero if nothing has been sent.
+    pub padding_frac: f64,
+    /// The maximum fraction of padding, zero if unlimited.
+    pub max_padding_frac: f64,
+    /// The fraction of time spent blocking over the blocking window of the
+    /// framework, zero if no time has passed.
+    pub blocking_frac: f64,
+    /// The maximum fraction of blocking, zero if unlimited.
+    pub max_blocking_frac: f64,
+    /// The current MTU.
+    pub mtu: u16,
+}
+
+// (part, whole) as a fraction, zero if whole is zero
+fn frac((part, whole): (f64, f64)) -> f64 {
+    if whole == 0.0 {
+        return 0.0;
+    }
+    part / whole
+}
+
+// Picks the blocking and running microseconds from a window, if any, or else
+// from the lifetime durations.
+fn windowed_blocking(
+    blocked: Option<(f64, f64)>,
+    window: Window,
+    lifetime_blocked: Duration,
+    running: Duration,
+) -> (f64, f64) {
+    match (blocked, window) {
+        (Some((blocked, _)), Window::Microsec(w)) => (
+            blocked,
+            running.min(Duration::from_micros(w)).as_micros() as f64,
+        ),
+        _ => (
+            lifetime_blocked.as_micros() as f64,
+            running.as_micros() as f64,
+        ),
+    }
+}
+
+fn state_limit<T>(runtime: &MachineRuntime<T>) -> Option<ActionLimit> {
+    if runtime.state_limit > 0 {
+        None
+    } else {
+        Some(ActionLimit::State)
+    }
+}
+
+// Returns the compiled machine, from the machine set if compiled by it.
+fn compiled_machine<'a, M: MachineSet>(
+    machines: &'a M,
+    compiled: &'a [CompiledMachine],
+    mi: usize,
+) -> &'a CompiledMachine {
+    &machines.compiled().unwrap_or(compiled)[mi]
 }
 
 #[derive(PartialEq)]
@@ -324,25 +600,63 @@ enum StateChange {
 /// channel, and produces as *output* zero or more [`Action`], such as to inject
 /// *padding* traffic or *block* outgoing traffic. One or more [`Machine`]
 /// determine what [`Action`] to take based on [`TriggerEvent`].
-pub struct Framework<M> {
+///
+/// All randomness used by the machines (state transitions and sampled
+/// timeouts, sizes, limits, and block durations) is drawn from the random
+/// number generator `R`. See [`Framework::new_with_rng()`] for how to provide a
+/// seeded generator for reproducible results.
+///
+/// The machines are of type `M`, see [`MachineSet`]. Use [`ValidatedMachines`]
+/// to not validate the same machines every time a framework is created.
+///
+/// Time is of type `T`, see [`Instant`] for details. For regular use, this is
+/// [`std::time::Instant`].
+///
+/// Decisions made by the framework are reported to an [`Observer`] `O`, see
+/// [`Framework::with_observer()`]. By default, there is no observer.
+pub struct Framework<M, R, T, O = NoObserver> {
     actions: Vec<Option<Action>>,
-    current_time: Instant,
+    all_actions: Vec<Option<TriggeredAction>>,
+    record_all_actions: bool,
+    event_index: usize,
+    current_time: T,
     machines: M,
-    runtime: Vec<MachineRuntime>,
+    // compiled machines, unless provided by the machine set
+    compiled: Vec<CompiledMachine>,
+    runtime: Vec<MachineRuntime<T>>,
+    // machines with a pending CounterZero event, see Self::counter_zero()
+    counter_zero_queue: VecDeque<usize>,
     global_max_padding_frac: f64,
     global_nonpadding_sent_bytes: u64,
     global_paddingsent_bytes: u64,
     global_max_blocking_frac: f64,
     global_blocking_duration: Duration,
-    global_blocking_started: Instant,
+    global_blocking_started: T,
     global_blocking_active: bool,
-    global_framework_start: Instant,
+    global_framework_start: T,
+    global_padding_window: Window,
+    global_blocking_window: Window,
+    global_windowed_padding: WindowCounter<T>,
+    global_windowed_blocking: WindowCounter<T>,
+    global_padding_rate_limit: Option<RateLimit>,
+    global_padding_bucket: TokenBucket<T>,
+    #[cfg(target_has_atomic = "64")]
+    shared_budget: Option<Arc<SharedBudget>>,
     mtu: u16,
+    rng: R,
+    observer: O,
 }
 
-impl<M> Framework<M>
+/// A [`Framework`] using the thread-local random number generator and
+/// [`std::time::Instant`], as created by [`Framework::new()`].
+#[cfg(any(feature = "std", test))]
+pub type StdFramework<M, O = NoObserver> = Framework<M, ThreadRng, std::time::Instant, O>;
+
+#[cfg(any(feature = "std", test))]
+impl<M, T> Framework<M, ThreadRng, T>
 where
-    M: AsRef<[Machine]>,
+    M: MachineSet,
+    T: Instant,
 {
     /// Create a new framework instance with zero or more [`Machine`]. The max
     /// padding/blocking fractions are enforced as a total across all machines.
@@ -354,46 +668,90 @@ where
     /// some types of use-cases of the framework easier (weird machines and for
     /// simulation). Returns an error on any invalid [`Machine`] or limits not
     /// being fractions [0.0, 1.0].
+    ///
+    /// Randomness is taken from [`rand::thread_rng()`]. Use
+    /// [`Self::new_with_rng()`] to provide your own random number generator.
+    /// Only available with the `std` feature.
     pub fn new(
         machines: M,
         max_padding_frac: f64,
         max_blocking_frac: f64,
         mtu: u16,
-        current_time: Instant,
-    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        for m in machines.as_ref() {
-            m.validate()?;
-        }
+        current_time: T,
+    ) -> Result<Self, FrameworkError> {
+        Self::new_with_rng(
+            machines,
+            max_padding_frac,
+            max_blocking_frac,
+            mtu,
+            current_time,
+            rand::thread_rng(),
+        )
+    }
+}
 
-        if !(0.0..=1.0).contains(&max_padding_frac) {
-            bail!("max_padding_frac has to be beteen [0.0, 1.0]");
-        }
-        if !(0.0..=1.0).contains(&max_blocking_frac) {
-            bail!("max_blocking_frac has to be beteen [0.0, 1.0]");
+impl<M, R, T> Framework<M, R, T>
+where
+    M: MachineSet,
+    R: RngCore,
+    T: Instant,
+{
+    /// Like [`Framework::new()`], but with a caller-provided random number
+    /// generator that is used for all randomness in the framework. Providing a
+    /// seeded generator (e.g., `StdRng` created with
+    /// [`seed_from_u64()`](rand::SeedableRng::seed_from_u64)) makes the
+    /// framework deterministic: the same machines triggered with the same
+    /// events at the same times produce the same actions.
+    pub fn new_with_rng(
+        machines: M,
+        max_padding_frac: f64,
+        max_blocking_frac: f64,
+        mtu: u16,
+        current_time: T,
+        mut rng: R,
+    ) -> Result<Self, FrameworkError> {
+        if !machines.is_validated() {
+            for (index, m) in machines.as_slice().iter().enumerate() {
+                m.validate()
+                    .map_err(|error| FrameworkError::InvalidMachine { index, error })?;
+            }
         }
 
-        let mut runtime = vec![
-            MachineRuntime {
-                current_state: 0,
-                state_limit: 0,
-                padding_sent: 0,
-                nonpadding_sent: 0,
-                blocking_duration: Duration::from_secs(0),
-                machine_start: current_time,
-            };
-            machines.as_ref().len()
-        ];
+        validate_fractions(max_padding_frac, max_blocking_frac)?;
+
+        let compiled = match machines.compiled() {
+            Some(_) => vec![],
+            None => machines
+                .as_slice()
+                .iter()
+                .enumerate()
+                .map(|(index, m)| {
+                    CompiledMachine::new(m)
+                        .map_err(|error| FrameworkError::InvalidMachine { index, error })
+                })
+                .collect::<Result<_, _>>()?,
+        };
 
-        for (machine_runtime, machine) in runtime.iter_mut().zip(machines.as_ref()) {
-            machine_runtime.state_limit = machine.states[0].sample_limit();
-        }
+        let runtime = machines
+            .as_slice()
+            .iter()
+            .zip(machines.compiled().unwrap_or(&compiled))
+            .map(|(machine, compiled)| {
+                MachineRuntime::new(machine, compiled, current_time, &mut rng)
+            })
+            .collect();
 
-        let actions = vec![None; machines.as_ref().len()];
+        let actions = vec![None; machines.as_slice().len()];
 
         Ok(Self {
             actions,
+            all_actions: vec![],
+            record_all_actions: false,
+            event_index: 0,
             machines,
+            compiled,
             runtime,
+            counter_zero_queue: VecDeque::new(),
             mtu,
             current_time,
             global_max_blocking_frac: max_blocking_frac,
@@ -404,22 +762,437 @@ where
             global_blocking_duration: Duration::from_secs(0),
             global_paddingsent_bytes: 0,
             global_nonpadding_sent_bytes: 0,
+            global_padding_window: Window::Lifetime,
+            global_blocking_window: Window::Lifetime,
+            global_windowed_padding: WindowCounter::new(current_time),
+            global_windowed_blocking: WindowCounter::new(current_time),
+            global_padding_rate_limit: None,
+            global_padding_bucket: TokenBucket::new(&None, current_time),
+            #[cfg(target_has_atomic = "64")]
+            shared_budget: None,
+            rng,
+            observer: NoObserver,
         })
     }
+}
+
+impl<M, R, T, O> Framework<M, R, T, O>
+where
+    M: MachineSet,
+    R: RngCore,
+    T: Instant,
+    O: Observer,
+{
+    /// Returns the framework with the [`Observer`] set, replacing any previous
+    /// observer. The observer is called on state transitions, sampled limits
+    /// and actions, counter updates, and when limits are reached or prevent
+    /// actions.
+    pub fn with_observer<P: Observer>(self, observer: P) -> Framework<M, R, T, P> {
+        Framework {
+            actions: self.actions,
+            all_actions: self.all_actions,
+            record_all_actions: self.record_all_actions,
+            event_index: self.event_index,
+            current_time: self.current_time,
+            machines: self.machines,
+            compiled: self.compiled,
+            runtime: self.runtime,
+            counter_zero_queue: self.counter_zero_queue,
+            global_max_padding_frac: self.global_max_padding_frac,
+            global_nonpadding_sent_bytes: self.global_nonpadding_sent_bytes,
+            global_paddingsent_bytes: self.global_paddingsent_bytes,
+            global_max_blocking_frac: self.global_max_blocking_frac,
+            global_blocking_duration: self.global_blocking_duration,
+            global_blocking_started: self.global_blocking_started,
+            global_blocking_active: self.global_blocking_active,
+            global_framework_start: self.global_framework_start,
+            global_padding_window: self.global_padding_window,
+            global_blocking_window: self.global_blocking_window,
+            global_windowed_padding: self.global_windowed_padding,
+            global_windowed_blocking: self.global_windowed_blocking,
+            global_padding_rate_limit: self.global_padding_rate_limit,
+            global_padding_bucket: self.global_padding_bucket,
+            #[cfg(target_has_atomic = "64")]
+            shared_budget: self.shared_budget,
+            mtu: self.mtu,
+            rng: self.rng,
+            observer,
+        }
+    }
+
+    /// Returns the framework with the windows over which the max
+    /// padding/blocking fractions of the framework are computed set, see
+    /// [`Window`]. By default, the fractions are computed over the lifetime of
+    /// the framework. The windows start at the last time provided to the
+    /// framework, so they are typically set right after creating the
+    /// framework. Returns an error if a window is invalid.
+    pub fn with_windows(
+        mut self,
+        padding_window: Window,
+        blocking_window: Window,
+    ) -> Result<Self, FrameworkError> {
+        padding_window
+            .validate_padding()
+            .map_err(FrameworkError::InvalidLimit)?;
+        blocking_window
+            .validate_blocking()
+            .map_err(FrameworkError::InvalidLimit)?;
+        self.global_padding_window = padding_window;
+        self.global_blocking_window = blocking_window;
+        self.global_windowed_padding = WindowCounter::new(self.current_time);
+        self.global_windowed_blocking = WindowCounter::new(self.current_time);
+        Ok(self)
+    }
+
+    /// Returns the framework with a [`RateLimit`] on the padding of all
+    /// machines combined, applied on top of all other padding limits. The
+    /// bucket starts full at the last time provided to the framework. Returns
+    /// an error if the rate limit is invalid.
+    pub fn with_padding_rate_limit(
+        mut self,
+        rate_limit: RateLimit,
+    ) -> Result<Self, FrameworkError> {
+        rate_limit
+            .validate()
+            .map_err(FrameworkError::InvalidLimit)?;
+        self.global_padding_rate_limit = Some(rate_limit);
+        self.global_padding_bucket = TokenBucket::new(&Some(rate_limit), self.current_time);
+        Ok(self)
+    }
+
+    /// Returns the framework with a [`SharedBudget`], typically shared with
+    /// other frameworks. From now on, the framework accounts for its padding,
+    /// non-padding, blocking, and running time in the budget, and the limits
+    /// of the budget apply on top of the limits of the framework and its
+    /// machines. The budget is not part of a [`FrameworkSnapshot`].
+    #[cfg(target_has_atomic = "64")]
+    pub fn with_shared_budget(mut self, budget: Arc<SharedBudget>) -> Self {
+        self.shared_budget = Some(budget);
+        self
+    }
+
+    /// Returns a reference to the [`Observer`] of the framework.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the [`Observer`] of the framework.
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
 
-    /// Returns the number of machines in the framework.
+    /// Returns the number of machines in the framework, including any machines
+    /// retired with [`Framework::remove_machine()`].
     pub fn num_machines(&self) -> usize {
-        self.machines.as_ref().len()
+        self.machines.as_slice().len()
+    }
+
+    /// Returns [`MachineStats`] for the machine, or None if there is no such
+    /// machine in the framework.
+    pub fn machine_stats(&self, machine: MachineId) -> Option<MachineStats> {
+        let runtime = self.runtime.get(machine.into_raw())?;
+        let m = &self.machines.as_slice()[machine.into_raw()];
+        let blocking_duration = runtime.blocking_duration + self.machine_ongoing_blocking(runtime);
+        let running_duration = self.current_time.duration_since(runtime.machine_start);
+        Some(MachineStats {
+            current_state: runtime.current_state,
+            state_limit: runtime.state_limit,
+            padding_sent: runtime.padding_sent,
+            nonpadding_sent: runtime.nonpadding_sent,
+            blocking_duration,
+            running_duration,
+            padding_frac: frac(self.machine_padding(runtime, m)),
+            blocking_frac: frac(self.machine_blocking(runtime, m)),
+            counters: runtime.counters,
+            retired: runtime.retired,
+        })
+    }
+
+    /// Returns the global [`FrameworkStats`] of the framework.
+    pub fn stats(&self) -> FrameworkStats {
+        let blocking_duration = self.global_blocking_duration + self.ongoing_blocking();
+        let running_duration = self
+            .current_time
+            .duration_since(self.global_framework_start);
+        FrameworkStats {
+            padding_sent: self.global_paddingsent_bytes,
+            nonpadding_sent: self.global_nonpadding_sent_bytes,
+            blocking_duration,
+            blocking_active: self.global_blocking_active,
+            running_duration,
+            padding_frac: frac(self.global_padding()),
+            max_padding_frac: self.global_max_padding_frac,
+            blocking_frac: frac(self.global_blocking()),
+            max_blocking_frac: self.global_max_blocking_frac,
+            mtu: self.mtu,
+        }
+    }
+
+    fn ongoing_blocking(&self) -> Duration {
+        if self.global_blocking_active {
+            self.current_time
+                .duration_since(self.global_blocking_started)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    // Like ongoing_blocking(), but for the machine: a machine added while
+    // blocking is active is only blocked from when it was added.
+    fn machine_ongoing_blocking(&self, runtime: &MachineRuntime<T>) -> Duration {
+        self.ongoing_blocking()
+            .min(self.current_time.duration_since(runtime.machine_start))
+    }
+
+    // Moves all blocking windows to the current time, accruing blocking time if
+    // blocking is active. Called before blocking starts or stops.
+    fn advance_blocking_windows(&mut self) {
+        let (now, active) = (self.current_time, self.global_blocking_active);
+        self.global_windowed_blocking
+            .advance(self.global_blocking_window, now, active);
+        for (runtime, machine) in self.runtime.iter_mut().zip(self.machines.as_slice()) {
+            runtime
+                .windowed_blocking
+                .advance(machine.blocking_window, now, active);
+        }
+    }
+
+    // Returns the padding and total bytes sent that the padding fraction of the
+    // machine is computed over, according to its padding window.
+    fn machine_padding(&self, runtime: &MachineRuntime<T>, machine: &Machine) -> (f64, f64) {
+        match runtime
+            .windowed_padding
+            .sums(machine.padding_window, self.current_time, false)
+        {
+            Some((padding, nonpadding)) => (padding, padding + nonpadding),
+            None => (
+                runtime.padding_sent as f64,
+                (runtime.nonpadding_sent + runtime.padding_sent) as f64,
+            ),
+        }
+    }
+
+    // Like machine_padding(), but for the framework.
+    fn global_padding(&self) -> (f64, f64) {
+        match self.global_windowed_padding.sums(
+            self.global_padding_window,
+            self.current_time,
+            false,
+        ) {
+            Some((padding, nonpadding)) => (padding, padding + nonpadding),
+            None => (
+                self.global_paddingsent_bytes as f64,
+                (self.global_paddingsent_bytes + self.global_nonpadding_sent_bytes) as f64,
+            ),
+        }
+    }
+
+    // Returns the microseconds of blocking and running time that the blocking
+    // fraction of the machine is computed over, according to its blocking
+    // window.
+    fn machine_blocking(&self, runtime: &MachineRuntime<T>, machine: &Machine) -> (f64, f64) {
+        let running = self.current_time.duration_since(runtime.machine_start);
+        let blocked = runtime.windowed_blocking.sums(
+            machine.blocking_window,
+            self.current_time,
+            self.global_blocking_active,
+        );
+        windowed_blocking(
+            blocked,
+            machine.blocking_window,
+            runtime.blocking_duration + self.machine_ongoing_blocking(runtime),
+            running,
+        )
+    }
+
+    // Like machine_blocking(), but for the framework.
+    fn global_blocking(&self) -> (f64, f64) {
+        let running = self
+            .current_time
+            .duration_since(self.global_framework_start);
+        let blocked = self.global_windowed_blocking.sums(
+            self.global_blocking_window,
+            self.current_time,
+            self.global_blocking_active,
+        );
+        windowed_blocking(
+            blocked,
+            self.global_blocking_window,
+            self.global_blocking_duration + self.ongoing_blocking(),
+            running,
+        )
+    }
+
+    /// Take a [`FrameworkSnapshot`] of the runtime state of the framework, with
+    /// all points in time relative to the `reference` time. The reference
+    /// SHOULD NOT be earlier than the last time provided to the framework,
+    /// e.g., use the current time.
+    pub fn snapshot(&self, reference: T) -> FrameworkSnapshot {
+        let machines = self
+            .runtime
+            .iter()
+            .zip(self.machines.as_slice())
+            .map(|(runtime, machine)| MachineSnapshot {
+                name: (!runtime.retired).then(|| machine.name()),
+                current_state: runtime.current_state,
+                state_limit: runtime.state_limit,
+                padding_sent: runtime.padding_sent,
+                nonpadding_sent: runtime.nonpadding_sent,
+                blocking_duration: runtime.blocking_duration,
+                machine_start: reference.duration_since(runtime.machine_start),
+                counters: runtime.counters,
+                retired: runtime.retired,
+                windowed_padding: runtime.windowed_padding.snapshot(reference),
+                windowed_blocking: runtime.windowed_blocking.snapshot(reference),
+                padding_bucket: runtime.padding_bucket.snapshot(reference),
+            })
+            .collect();
+
+        FrameworkSnapshot {
+            version: SNAPSHOTVERSION,
+            machines,
+            mtu: self.mtu,
+            global_max_padding_frac: self.global_max_padding_frac,
+            global_nonpadding_sent_bytes: self.global_nonpadding_sent_bytes,
+            global_paddingsent_bytes: self.global_paddingsent_bytes,
+            global_max_blocking_frac: self.global_max_blocking_frac,
+            global_blocking_duration: self.global_blocking_duration,
+            global_blocking_active: self.global_blocking_active,
+            global_padding_window: self.global_padding_window,
+            global_blocking_window: self.global_blocking_window,
+            global_windowed_padding: self.global_windowed_padding.snapshot(reference),
+            global_windowed_blocking: self.global_windowed_blocking.snapshot(reference),
+            global_padding_rate_limit: self.global_padding_rate_limit,
+            global_padding_bucket: self.global_padding_bucket.snapshot(reference),
+            global_blocking_started: reference.duration_since(self.global_blocking_started),
+            global_framework_start: reference.duration_since(self.global_framework_start),
+            current_time: reference.duration_since(self.current_time),
+        }
+    }
+
+    /// Restore the runtime state of the framework from a [`FrameworkSnapshot`],
+    /// with all points in time relative to the `reference` time (typically,
+    /// the current time). The framework MUST run the same machines, in the
+    /// same order, as the framework the snapshot was taken of: this is
+    /// verified using [`Machine::name()`] for all machines that were not
+    /// retired. The limits and MTU of the snapshot replace those the framework
+    /// was created with. On error, the framework is left unchanged.
+    pub fn restore(
+        &mut self,
+        snapshot: &FrameworkSnapshot,
+        reference: T,
+    ) -> Result<(), FrameworkError> {
+        if snapshot.version != SNAPSHOTVERSION {
+            return Err(FrameworkError::UnsupportedSnapshotVersion(snapshot.version));
+        }
+        if snapshot.machines.len() != self.machines.as_slice().len() {
+            return Err(FrameworkError::SnapshotMismatch(format!(
+                "snapshot has {} machines, framework has {}",
+                snapshot.machines.len(),
+                self.machines.as_slice().len()
+            )));
+        }
+        for (i, (m, machine)) in snapshot
+            .machines
+            .iter()
+            .zip(self.machines.as_slice())
+            .enumerate()
+        {
+            if m.retired {
+                continue;
+            }
+            if m.name.as_ref() != Some(&machine.name()) {
+                return Err(FrameworkError::SnapshotMismatch(format!(
+                    "machine at index {} does not match",
+                    i
+                )));
+            }
+            if m.current_state >= machine.states.len() && m.current_state != STATEEND {
+                return Err(FrameworkError::SnapshotMismatch(format!(
+                    "invalid state {} for machine at index {}",
+                    m.current_state, i
+                )));
+            }
+        }
+        validate_fractions(
+            snapshot.global_max_padding_frac,
+            snapshot.global_max_blocking_frac,
+        )?;
+        snapshot
+            .global_padding_window
+            .validate_padding()
+            .map_err(FrameworkError::InvalidLimit)?;
+        snapshot
+            .global_blocking_window
+            .validate_blocking()
+            .map_err(FrameworkError::InvalidLimit)?;
+        if let Some(limit) = &snapshot.global_padding_rate_limit {
+            limit.validate().map_err(FrameworkError::InvalidLimit)?;
+        }
+
+        let before = |d: Duration| {
+            reference
+                .checked_sub(d)
+                .ok_or(FrameworkError::UnrepresentableTime)
+        };
+        let global_blocking_started = before(snapshot.global_blocking_started)?;
+        let global_framework_start = before(snapshot.global_framework_start)?;
+        let current_time = before(snapshot.current_time)?;
+        let global_windowed_padding =
+            WindowCounter::restore(&snapshot.global_windowed_padding, reference)?;
+        let global_windowed_blocking =
+            WindowCounter::restore(&snapshot.global_windowed_blocking, reference)?;
+        let global_padding_bucket =
+            TokenBucket::restore(&snapshot.global_padding_bucket, reference)?;
+        let mut runtime = Vec::with_capacity(snapshot.machines.len());
+        for m in &snapshot.machines {
+            runtime.push(MachineRuntime {
+                // retired machines are gone, never touch their states
+                current_state: if m.retired { STATEEND } else { m.current_state },
+                state_limit: m.state_limit,
+                padding_sent: m.padding_sent,
+                nonpadding_sent: m.nonpadding_sent,
+                blocking_duration: m.blocking_duration,
+                machine_start: before(m.machine_start)?,
+                counters: m.counters,
+                retired: m.retired,
+                windowed_padding: WindowCounter::restore(&m.windowed_padding, reference)?,
+                windowed_blocking: WindowCounter::restore(&m.windowed_blocking, reference)?,
+                padding_bucket: TokenBucket::restore(&m.padding_bucket, reference)?,
+            });
+        }
+
+        // all checks done, no more errors
+        self.actions.fill(None);
+        self.runtime = runtime;
+        self.mtu = snapshot.mtu;
+        self.global_max_padding_frac = snapshot.global_max_padding_frac;
+        self.global_nonpadding_sent_bytes = snapshot.global_nonpadding_sent_bytes;
+        self.global_paddingsent_bytes = snapshot.global_paddingsent_bytes;
+        self.global_max_blocking_frac = snapshot.global_max_blocking_frac;
+        self.global_blocking_duration = snapshot.global_blocking_duration;
+        self.global_blocking_active = snapshot.global_blocking_active;
+        self.global_padding_window = snapshot.global_padding_window;
+        self.global_blocking_window = snapshot.global_blocking_window;
+        self.global_windowed_padding = global_windowed_padding;
+        self.global_windowed_blocking = global_windowed_blocking;
+        self.global_padding_rate_limit = snapshot.global_padding_rate_limit;
+        self.global_padding_bucket = global_padding_bucket;
+        self.global_blocking_started = global_blocking_started;
+        self.global_framework_start = global_framework_start;
+        self.current_time = current_time;
+
+        Ok(())
     }
 
     /// Trigger zero or more [`TriggerEvent`] for all machines running in the
     /// framework. The current time SHOULD be the current time at time of
-    /// calling the method (e.g., [`Instant::now()`]). Returns an iterator of
-    /// zero or more [`Action`] that MUST be taken by the caller.
+    /// calling the method (e.g., [`std::time::Instant::now()`]). Returns an
+    /// iterator of zero or more [`Action`] that MUST be taken by the caller.
     pub fn trigger_events(
         &mut self,
         events: &[TriggerEvent],
-        current_time: Instant,
+        current_time: T,
     ) -> impl Iterator<Item = &Action> {
         // reset all actions
         self.actions.fill(None);
@@ -428,7 +1201,7 @@ where
         // per machine, but that future events may replace those actions.
         // Under load, this is preferable (because something already happened
         // before we could cause an action, so better to catch up).
-        self.current_time = current_time;
+        self.set_time(current_time);
         for e in events {
             self.process_event(e);
         }
@@ -437,6 +1210,80 @@ where
         self.actions.iter().filter_map(|action| action.as_ref())
     }
 
+    /// Like [`Self::trigger_events()`], but returns every [`Action`] produced
+    /// while processing the events, in the order they were produced, instead
+    /// of only the latest action per machine. Each action is tagged with the
+    /// index of the [`TriggerEvent`] in `events` that caused it. Actions are
+    /// only left out if the framework itself retracts them, i.e., when the
+    /// same event that caused the action also used up the limit of the state
+    /// (see [`State::limit`](crate::state::State::limit)).
+    ///
+    /// This is useful when batching events, since a later event in the batch
+    /// may otherwise replace an earlier action of the same machine. The
+    /// caller SHOULD take the actions in order.
+    pub fn trigger_events_all(
+        &mut self,
+        events: &[TriggerEvent],
+        current_time: T,
+    ) -> impl Iterator<Item = &TriggeredAction> {
+        self.actions.fill(None);
+        self.all_actions.clear();
+
+        self.record_all_actions = true;
+        self.set_time(current_time);
+        for (i, e) in events.iter().enumerate() {
+            self.event_index = i;
+            self.process_event(e);
+        }
+        self.record_all_actions = false;
+
+        self.all_actions.iter().filter_map(|action| action.as_ref())
+    }
+
+    fn set_time(&mut self, current_time: T) {
+        #[cfg(target_has_atomic = "64")]
+        if let Some(budget) = &self.shared_budget {
+            // blocking only starts or stops on events, i.e., after the time is
+            // updated, so whether we blocked is known here
+            budget.add_time(
+                current_time.duration_since(self.current_time),
+                self.global_blocking_active,
+            );
+        }
+        self.current_time = current_time;
+    }
+
+    fn set_action(&mut self, mi: usize, action: Option<Action>) {
+        if self.record_all_actions {
+            if let Some(action) = &action {
+                self.all_actions.push(Some(TriggeredAction {
+                    event_index: self.event_index,
+                    action: action.clone(),
+                }));
+            }
+        }
+        self.actions[mi] = action;
+    }
+
+    fn retract_action(&mut self, mi: usize) {
+        if self.record_all_actions && self.actions[mi].is_some() {
+            // only an action caused by the current event can be retracted
+            for recorded in self.all_actions.iter_mut().rev() {
+                let Some(a) = recorded else {
+                    continue;
+                };
+                if a.event_index != self.event_index {
+                    break;
+                }
+                if a.action.machine() == MachineId(mi) {
+                    *recorded = None;
+                    break;
+                }
+            }
+        }
+        self.actions[mi] = None;
+    }
+
     fn process_event(&mut self, e: &TriggerEvent) {
         match e {
             TriggerEvent::NonPaddingRecv { bytes_recv } => {
@@ -453,9 +1300,25 @@ where
             }
             TriggerEvent::NonPaddingSent { bytes_sent } => {
                 self.global_nonpadding_sent_bytes += *bytes_sent as u64;
+                #[cfg(target_has_atomic = "64")]
+                if let Some(budget) = &self.shared_budget {
+                    budget.add_nonpadding(*bytes_sent as u64);
+                }
+                self.global_windowed_padding.add(
+                    self.global_padding_window,
+                    self.current_time,
+                    0,
+                    *bytes_sent as u64,
+                );
 
                 for mi in 0..self.runtime.len() {
                     self.runtime[mi].nonpadding_sent += *bytes_sent as u64;
+                    self.runtime[mi].windowed_padding.add(
+                        self.machines.as_slice()[mi].padding_window,
+                        self.current_time,
+                        0,
+                        *bytes_sent as u64,
+                    );
 
                     // If the transition leaves the state unchanged and the limit of
                     // the machine includes nonpadding sent packets, decrement the
@@ -466,7 +1329,7 @@ where
                     {
                         let cs = self.runtime[mi].current_state;
                         if cs != STATEEND
-                            && self.machines.as_ref()[mi].states[cs].limit_includes_nonpadding
+                            && self.machines.as_slice()[mi].states[cs].limit_includes_nonpadding
                         {
                             self.decrement_limit(mi);
                         }
@@ -479,12 +1342,38 @@ where
             } => {
                 // accounting is global ...
                 self.global_paddingsent_bytes += *bytes_sent as u64;
+                #[cfg(target_has_atomic = "64")]
+                if let Some(budget) = &self.shared_budget {
+                    budget.add_padding(*bytes_sent as u64);
+                }
+                self.global_windowed_padding.add(
+                    self.global_padding_window,
+                    self.current_time,
+                    *bytes_sent as u64,
+                    0,
+                );
+                self.global_padding_bucket.take(
+                    &self.global_padding_rate_limit,
+                    self.current_time,
+                    *bytes_sent as u64,
+                );
 
                 for mi in 0..self.runtime.len() {
                     // ... but the event is per-machine
                     // TODO: we probably want a PaddingQueued (self) and PaddingSent (global)
                     if mi == machine.into_raw() {
                         self.runtime[mi].padding_sent += *bytes_sent as u64;
+                        self.runtime[mi].windowed_padding.add(
+                            self.machines.as_slice()[mi].padding_window,
+                            self.current_time,
+                            *bytes_sent as u64,
+                            0,
+                        );
+                        self.runtime[mi].padding_bucket.take(
+                            &self.machines.as_slice()[mi].padding_rate_limit,
+                            self.current_time,
+                            *bytes_sent as u64,
+                        );
 
                         if self.transition(mi, Event::PaddingSent, *bytes_sent as u64)
                             == StateChange::Unchanged
@@ -498,6 +1387,7 @@ where
             TriggerEvent::BlockingBegin { machine } => {
                 // keep track of when we start blocking (for accounting in BlockingEnd)
                 if !self.global_blocking_active {
+                    self.advance_blocking_windows();
                     self.global_blocking_active = true;
                     self.global_blocking_started = self.current_time;
                 }
@@ -516,6 +1406,7 @@ where
             TriggerEvent::BlockingEnd => {
                 let mut blocked: Duration = Duration::from_secs(0);
                 if self.global_blocking_active {
+                    self.advance_blocking_windows();
                     blocked = self
                         .current_time
                         .duration_since(self.global_blocking_started);
@@ -525,9 +1416,12 @@ where
 
                 for mi in 0..self.runtime.len() {
                     // since block is global, every machine was blocked the
-                    // same duration
+                    // same duration, except machines added while blocking
                     if !blocked.is_zero() {
-                        self.runtime[mi].blocking_duration += blocked;
+                        let running = self
+                            .current_time
+                            .duration_since(self.runtime[mi].machine_start);
+                        self.runtime[mi].blocking_duration += blocked.min(running);
                     }
                     self.transition(mi, Event::BlockingEnd, 0);
                 }
@@ -542,25 +1436,46 @@ where
                     self.transition(mi, Event::UpdateMTU, *new_mtu as u64);
                 }
             }
+            TriggerEvent::CounterZero { machine } => {
+                // counters are internal to each machine, ignore unknown machines
+                let mi = machine.into_raw();
+                if mi < self.runtime.len() {
+                    self.transition(mi, Event::CounterZero, 0);
+                }
+            }
+            TriggerEvent::TimerBegin { machine } => {
+                // the timer is machine internal, ignore unknown machines
+                let mi = machine.into_raw();
+                if mi < self.runtime.len()
+                    && self.transition(mi, Event::TimerBegin, 0) == StateChange::Unchanged
+                {
+                    // decrement only makes sense if we didn't change state
+                    self.decrement_limit(mi)
+                }
+            }
+            TriggerEvent::TimerEnd { machine } => {
+                let mi = machine.into_raw();
+                if mi < self.runtime.len() {
+                    self.transition(mi, Event::TimerEnd, 0);
+                }
+            }
         };
     }
 
     // FIXME: should probably just return the action instead and set mi outside?
     fn transition(&mut self, mi: usize, event: Event, n: u64) -> StateChange {
-        let machine = &self.machines.as_ref()[mi];
-
         // a machine in end state cannot transition
         if self.runtime[mi].current_state == STATEEND {
             return StateChange::Unchanged;
         }
 
         // ignore events generated by small packets if not included
-        if !machine.include_small_packets && n > 0 && n <= MAXSMALLPACKETSIZE {
+        if !self.machines.as_slice()[mi].include_small_packets && n > 0 && n <= MAXSMALLPACKETSIZE {
             return StateChange::Unchanged;
         }
 
         // sample next state
-        let (next_state, set) = self.next_state(&self.runtime[mi], machine, event);
+        let (next_state, set) = self.next_state(mi, event);
 
         // if no next state on event, done
         if !set {
@@ -568,12 +1483,21 @@ where
         }
 
         // we got a next state, act on it
+        self.observer.on_transition(
+            MachineId(mi),
+            event,
+            self.runtime[mi].current_state,
+            next_state,
+        );
         match next_state {
             STATECANCEL => {
                 // cancel any pending action, but doesn't count as a state change
-                self.actions[mi] = Some(Action::Cancel {
-                    machine: MachineId(mi),
-                });
+                self.set_action(
+                    mi,
+                    Some(Action::Cancel {
+                        machine: MachineId(mi),
+                    }),
+                );
                 StateChange::Unchanged
             }
             STATEEND => {
@@ -585,62 +1509,142 @@ where
             }
             _ => {
                 // transition to same or different state?
-                if self.runtime[mi].current_state == next_state {
-                    if self.below_action_limits(&self.runtime[mi], machine) {
-                        self.actions[mi] =
-                            self.schedule_action(&self.runtime[mi], machine, MachineId(mi));
+                let change = if self.runtime[mi].current_state == next_state {
+                    StateChange::Unchanged
+                } else {
+                    self.runtime[mi].current_state = next_state;
+                    self.runtime[mi].state_limit =
+                        compiled_machine(&self.machines, &self.compiled, mi)
+                            .state(next_state)
+                            .sample_limit(&mut self.rng);
+                    self.observer.on_limit_sampled(
+                        MachineId(mi),
+                        next_state,
+                        self.runtime[mi].state_limit,
+                    );
+                    StateChange::Changed
+                };
+                match self.action_limit(&self.runtime[mi], &self.machines.as_slice()[mi]) {
+                    None => {
+                        let action = self.schedule_action(mi);
+                        if let Some(action) = &action {
+                            self.observer.on_action(action);
+                        }
+                        self.set_action(mi, action);
+                    }
+                    Some(limit) => {
+                        self.observer
+                            .on_action_suppressed(MachineId(mi), next_state, limit);
                     }
-                    return StateChange::Unchanged;
                 }
-                self.runtime[mi].current_state = next_state;
-                self.runtime[mi].state_limit = machine.states[next_state].sample_limit();
-                if self.below_action_limits(&self.runtime[mi], machine) {
-                    self.actions[mi] =
-                        self.schedule_action(&self.runtime[mi], machine, MachineId(mi));
+
+                if self.update_counters(mi) {
+                    // trigger CounterZero internally, which may in turn change
+                    // the state of the machine
+                    self.counter_zero(mi);
+                    if self.runtime[mi].current_state != next_state {
+                        return StateChange::Changed;
+                    }
                 }
-                StateChange::Changed
+                change
             }
         }
     }
 
-    fn schedule_action(
-        &self,
-        runtime: &MachineRuntime,
-        machine: &Machine,
-        mi: MachineId,
-    ) -> Option<Action> {
-        let current = &machine.states[runtime.current_state];
+    // Triggers CounterZero for the machine. A transition on CounterZero may
+    // zero a counter again, so instead of recursing, the events are queued and
+    // handled in order by the outermost call. At most MAXCOUNTERZERO
+    // transitions are made before the remaining events are dropped, so a
+    // machine that keeps zeroing its counters cannot loop forever.
+    fn counter_zero(&mut self, mi: usize) {
+        self.counter_zero_queue.push_back(mi);
+        if self.counter_zero_queue.len() > 1 {
+            // queued for the call further up the stack
+            return;
+        }
 
-        if current.action_is_block {
+        let mut transitions = 0;
+        while let Some(&mi) = self.counter_zero_queue.front() {
+            // keep the event queued during the transition, see above
+            if transitions < MAXCOUNTERZERO {
+                self.transition(mi, Event::CounterZero, 0);
+                transitions += 1;
+            }
+            self.counter_zero_queue.pop_front();
+        }
+    }
+
+    // Applies the counter updates of the current state, returning true if any
+    // counter went from nonzero to zero.
+    fn update_counters(&mut self, mi: usize) -> bool {
+        let current_state = self.runtime[mi].current_state;
+        let state = &self.machines.as_slice()[mi].states[current_state];
+        let compiled = compiled_machine(&self.machines, &self.compiled, mi).state(current_state);
+        let mut zeroed = false;
+        for (index, (counter, update)) in self.runtime[mi]
+            .counters
+            .iter_mut()
+            .zip(&state.counters)
+            .enumerate()
+        {
+            let Some(update) = update else {
+                continue;
+            };
+            let old = *counter;
+            *counter = update.apply(old, compiled.sample_counter_value(index, &mut self.rng));
+            self.observer
+                .on_counter_update(MachineId(mi), index, old, *counter);
+            zeroed |= old != 0 && *counter == 0;
+        }
+        zeroed
+    }
+
+    fn schedule_action(&mut self, mi: usize) -> Option<Action> {
+        let current_state = self.runtime[mi].current_state;
+        let current = &self.machines.as_slice()[mi].states[current_state];
+        let sampler = compiled_machine(&self.machines, &self.compiled, mi).state(current_state);
+
+        if current.action_is_timer {
+            Some(Action::UpdateTimer {
+                duration: Duration::from_micros(sampler.sample_timer(&mut self.rng) as u64),
+                replace: current.replace,
+                machine: MachineId(mi),
+            })
+        } else if current.action_is_block {
             Some(Action::BlockOutgoing {
-                timeout: Duration::from_micros(current.sample_timeout() as u64),
-                duration: Duration::from_micros(current.sample_block() as u64),
+                timeout: Duration::from_micros(sampler.sample_timeout(&mut self.rng) as u64),
+                duration: Duration::from_micros(sampler.sample_block(&mut self.rng) as u64),
                 bypass: current.bypass,
                 replace: current.replace,
-                machine: mi,
+                machine: MachineId(mi),
             })
         } else {
             Some(Action::InjectPadding {
-                timeout: Duration::from_micros(current.sample_timeout() as u64),
-                size: current.sample_size(self.mtu as u64) as u16,
+                timeout: Duration::from_micros(sampler.sample_timeout(&mut self.rng) as u64),
+                size: sampler.sample_size(self.mtu as u64, &mut self.rng) as u16,
                 bypass: current.bypass,
                 replace: current.replace,
-                machine: mi,
+                machine: MachineId(mi),
             })
         }
     }
 
     fn decrement_limit(&mut self, mi: usize) {
+        // a machine in end state has no limit
+        if self.runtime[mi].current_state == STATEEND {
+            return;
+        }
         if self.runtime[mi].state_limit > 0 {
             self.runtime[mi].state_limit -= 1;
         }
         let cs = self.runtime[mi].current_state;
 
         if self.runtime[mi].state_limit == 0
-            && self.machines.as_ref()[mi].states[cs].limit.dist != DistType::None
+            && self.machines.as_slice()[mi].states[cs].limit.dist != DistType::None
         {
             // take no action and trigger limit reached
-            self.actions[mi] = None;
+            self.observer.on_limit_reached(MachineId(mi), cs);
+            self.retract_action(mi);
             // next, we trigger internally event LimitReached
             self.process_event(&TriggerEvent::LimitReached {
                 machine: MachineId(mi),
@@ -648,149 +1652,249 @@ where
         }
     }
 
-    fn next_state(
-        &self,
-        runtime: &MachineRuntime,
-        machine: &Machine,
-        event: Event,
-    ) -> (usize, bool) {
-        if !machine.states[runtime.current_state]
-            .next_state
-            .contains_key(&event)
-        {
+    fn next_state(&mut self, mi: usize, event: Event) -> (usize, bool) {
+        let compiled = compiled_machine(&self.machines, &self.compiled, mi);
+        let Some(transitions) = compiled.transitions(self.runtime[mi].current_state, event) else {
             return (0, false);
-        }
-        let next_prop = &machine.states[runtime.current_state].next_state[&event];
-
-        let p = rand::random::<f64>();
-        let mut total = 0.0;
-        for i in 0..next_prop.len() {
-            total += next_prop[i];
-            if p <= total {
-                // some events are machine-defined, others framework pseudo-states
-                match next_prop.len().cmp(&(i + 2)) {
-                    Ordering::Greater => return (i, true),
-                    Ordering::Less => return (STATEEND, true),
-                    Ordering::Equal => return (STATECANCEL, true),
-                }
-            }
-        }
+        };
 
-        (STATENOP, false)
+        let p = self.rng.gen::<f64>();
+        match sample_transition(transitions, p) {
+            Some(next) => (next, true),
+            None => (STATENOP, false),
+        }
     }
 
-    fn below_action_limits(&self, runtime: &MachineRuntime, machine: &Machine) -> bool {
+    // Returns the limit that prevents the machine from scheduling the action of
+    // its current state, if any.
+    fn action_limit(&self, runtime: &MachineRuntime<T>, machine: &Machine) -> Option<ActionLimit> {
         let current = &machine.states[runtime.current_state];
-        // either blocking or padding limits apply
+        // timers are only limited by the state limit, otherwise either
+        // blocking or padding limits apply
+        if current.action_is_timer {
+            return state_limit(runtime);
+        }
         if current.action_is_block {
-            return self.below_limit_blocking(runtime, machine);
+            return self.blocking_limit(runtime, machine);
         }
-        self.below_limit_padding(runtime, machine)
+        self.padding_limit(runtime, machine)
     }
 
-    fn below_limit_blocking(&self, runtime: &MachineRuntime, machine: &Machine) -> bool {
+    fn blocking_limit(
+        &self,
+        runtime: &MachineRuntime<T>,
+        machine: &Machine,
+    ) -> Option<ActionLimit> {
         let current = &machine.states[runtime.current_state];
         // blocking action
 
         // special case: we always allow overwriting existing blocking
         if current.replace && self.global_blocking_active {
             // we still check against state limit, because it's machine internal
-            return runtime.state_limit > 0;
+            return state_limit(runtime);
         }
 
-        // compute durations we've been blocking
-        let mut m_block_dur = runtime.blocking_duration;
-        let mut g_block_dur = self.global_blocking_duration;
-        if self.global_blocking_active {
-            // account for ongoing blocking as well, add duration
-            m_block_dur += self
-                .current_time
-                .duration_since(self.global_blocking_started);
-            g_block_dur += self
-                .current_time
-                .duration_since(self.global_blocking_started);
-        }
+        // compute duration we've been blocking, accounting for ongoing
+        // blocking as well
+        let m_block_dur = runtime.blocking_duration + self.machine_ongoing_blocking(runtime);
 
         // machine allowed blocking duration first, since it bypasses the
         // other two types of limits
         if m_block_dur < Duration::from_micros(machine.allowed_blocked_microsec) {
             // we still check against state limit, because it's machine internal
-            return runtime.state_limit > 0;
+            return state_limit(runtime);
         }
 
-        // does the machine limit say no, if set?
+        // does the machine limit say no, if set? (over its window)
         if machine.max_blocking_frac > 0.0 {
-            // TODO: swap to m_block_dur.div_duration_f64()
-            let f: f64 = m_block_dur.as_micros() as f64
-                / self
-                    .current_time
-                    .duration_since(runtime.machine_start)
-                    .as_micros() as f64;
-            if f >= machine.max_blocking_frac {
-                return false;
+            let (blocked, running) = self.machine_blocking(runtime, machine);
+            if blocked / running >= machine.max_blocking_frac {
+                return Some(ActionLimit::MachineBlocking);
             }
         }
 
         // does the framework say no?
         if self.global_max_blocking_frac > 0.0 {
-            // TODO: swap to g_block_dur.div_duration_f64()
-            let f: f64 = g_block_dur.as_micros() as f64
-                / self
-                    .current_time
-                    .duration_since(self.global_framework_start)
-                    .as_micros() as f64;
-            if f >= self.global_max_blocking_frac {
-                return false;
+            let (blocked, running) = self.global_blocking();
+            if blocked / running >= self.global_max_blocking_frac {
+                return Some(ActionLimit::FrameworkBlocking);
             }
         }
 
+        // does the shared budget say no?
+        #[cfg(target_has_atomic = "64")]
+        if self
+            .shared_budget
+            .as_ref()
+            .is_some_and(|b| b.blocking_frac_exceeded())
+        {
+            return Some(ActionLimit::SharedBlocking);
+        }
+
         // only state-limit left to consider
-        runtime.state_limit > 0
+        state_limit(runtime)
     }
 
-    fn below_limit_padding(&self, runtime: &MachineRuntime, machine: &Machine) -> bool {
-        // no limits apply if not made up padding count
+    fn padding_limit(&self, runtime: &MachineRuntime<T>, machine: &Machine) -> Option<ActionLimit> {
+        // rate limits are absolute, so they apply first
+        if runtime
+            .padding_bucket
+            .available(&machine.padding_rate_limit, self.current_time)
+            .is_some_and(|tokens| tokens <= 0.0)
+        {
+            return Some(ActionLimit::MachinePaddingRate);
+        }
+        if self
+            .global_padding_bucket
+            .available(&self.global_padding_rate_limit, self.current_time)
+            .is_some_and(|tokens| tokens <= 0.0)
+        {
+            return Some(ActionLimit::FrameworkPaddingRate);
+        }
+        #[cfg(target_has_atomic = "64")]
+        if self
+            .shared_budget
+            .as_ref()
+            .is_some_and(|b| b.padding_bytes_exceeded())
+        {
+            return Some(ActionLimit::SharedPaddingBytes);
+        }
+
+        // no other limits apply if not made up padding count
         if runtime.padding_sent < machine.allowed_padding_bytes {
-            return runtime.state_limit > 0;
+            return state_limit(runtime);
+        }
+
+        // hit shared limits? (checked first, since the fractions below allow
+        // padding if the machine or framework has sent nothing)
+        #[cfg(target_has_atomic = "64")]
+        if self
+            .shared_budget
+            .as_ref()
+            .is_some_and(|b| b.padding_frac_exceeded())
+        {
+            return Some(ActionLimit::SharedPadding);
         }
 
-        // hit machine limits?
+        // hit machine limits? (over its window)
         if machine.max_padding_frac > 0.0 {
-            let total = runtime.nonpadding_sent + runtime.padding_sent;
-            if total == 0 {
-                return true;
+            let (padding, total) = self.machine_padding(runtime, machine);
+            if total == 0.0 {
+                return None;
             }
-            if runtime.padding_sent as f64 / total as f64 >= machine.max_padding_frac {
-                return false;
+            if padding / total >= machine.max_padding_frac {
+                return Some(ActionLimit::MachinePadding);
             }
         }
 
         // hit global limits?
         if self.global_max_padding_frac > 0.0 {
-            let total = self.global_paddingsent_bytes + self.global_nonpadding_sent_bytes;
-            if total == 0 {
-                return true;
+            let (padding, total) = self.global_padding();
+            if total == 0.0 {
+                return None;
             }
-            if self.global_paddingsent_bytes as f64 / total as f64 >= self.global_max_padding_frac {
-                return false;
+            if padding / total >= self.global_max_padding_frac {
+                return Some(ActionLimit::FrameworkPadding);
             }
         }
 
         // only state-limit left to consider
-        runtime.state_limit > 0
+        state_limit(runtime)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::dist::*;
-    use crate::framework::*;
-    use crate::state::*;
-    use std::collections::HashMap;
-    use std::ops::Add;
-    use std::time::Duration;
-    use std::time::Instant;
-
+impl<R, T, O> Framework<Vec<Machine>, R, T, O>
+where
+    R: RngCore,
+    T: Instant,
+    O: Observer,
+{
+    /// Add a machine to the running framework, returning the [`MachineId`] of
+    /// the machine. The machine starts in its first state at the current time,
+    /// while the global padding and blocking accounting of the framework (and
+    /// all other machines) is left as-is, so the global limits still take all
+    /// prior overhead into account. The machine gets a new [`MachineId`], never
+    /// that of a retired machine, so events and actions of a retired machine
+    /// cannot be mistaken for those of the added machine. Returns an error if
+    /// the machine is invalid.
+    ///
+    /// Only available when the framework owns its machines (`Vec<Machine>`).
+    pub fn add_machine(
+        &mut self,
+        machine: Machine,
+        current_time: T,
+    ) -> Result<MachineId, FrameworkError> {
+        let index = self.machines.len();
+        let invalid = |error| FrameworkError::InvalidMachine { index, error };
+        machine.validate().map_err(invalid)?;
+
+        let compiled = CompiledMachine::new(&machine).map_err(invalid)?;
+        let runtime = MachineRuntime::new(&machine, &compiled, current_time, &mut self.rng);
+        self.compiled.push(compiled);
+        self.machines.push(machine);
+        self.runtime.push(runtime);
+        self.actions.push(None);
+
+        Ok(MachineId(self.machines.len() - 1))
+    }
+
+    /// Retire a machine from the running framework: the machine stops
+    /// responding to events and will never cause any more actions. The caller
+    /// should cancel any scheduled action and timer of the machine: events for
+    /// it are ignored from now on, and its [`MachineId`] is never reused. The
+    /// identifiers of all other machines remain valid. Global padding and
+    /// blocking accounting is kept.
+    /// Returns an error if the machine does not exist or is already retired.
+    pub fn remove_machine(&mut self, machine: MachineId) -> Result<(), FrameworkError> {
+        let mi = machine.into_raw();
+        match self.runtime.get(mi) {
+            None => return Err(FrameworkError::UnknownMachine(machine)),
+            Some(runtime) if runtime.retired => {
+                return Err(FrameworkError::RetiredMachine(machine))
+            }
+            Some(_) => {}
+        }
+
+        // a machine in end state never touches its states again, so free the
+        // compiled states (leaving the machine as-is)
+        self.runtime[mi].retired = true;
+        self.runtime[mi].current_state = STATEEND;
+        self.compiled[mi] = CompiledMachine::default();
+        self.actions[mi] = None;
+
+        Ok(())
+    }
+}
+
+// validates the max padding and blocking fractions of a framework
+pub(crate) fn validate_fractions(
+    max_padding_frac: f64,
+    max_blocking_frac: f64,
+) -> Result<(), FrameworkError> {
+    let check = |field, value: f64| {
+        if (0.0..=1.0).contains(&value) {
+            Ok(())
+        } else {
+            Err(FrameworkError::InvalidLimit(
+                MachineError::InvalidFraction { field, value },
+            ))
+        }
+    };
+    check("max_padding_frac", max_padding_frac)?;
+    check("max_blocking_frac", max_blocking_frac)
+}
+
+#[cfg(test)]
+#[allow(clippy::unnecessary_cast, clippy::nonminimal_bool)]
+mod tests {
+    use crate::dist::*;
+    use crate::framework::*;
+    use crate::state::*;
+    use std::collections::HashMap;
+    use std::ops::Add;
+    use std::time::Duration;
+    use std::time::Instant;
+
     #[test]
     fn no_machines() {
         let machines = vec![];
@@ -798,6 +1902,21 @@ mod tests {
         assert!(!f.is_err());
     }
 
+    #[test]
+    fn invalid_fractions() {
+        let machines = vec![];
+        let f = Framework::new(&machines, 0.0, 1.1, 150, Instant::now());
+        assert_eq!(
+            f.err(),
+            Some(FrameworkError::InvalidLimit(
+                MachineError::InvalidFraction {
+                    field: "max_blocking_frac",
+                    value: 1.1
+                }
+            ))
+        );
+    }
+
     #[test]
     fn reuse_machines() {
         let machines = vec![];
@@ -846,8 +1965,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -998,6 +2120,176 @@ mod tests {
         }
     }
 
+    #[test]
+    fn trigger_events_all_actions() {
+        // plan: create a machine that swaps between two states on padding
+        // sent and received, trigger a batch of events, and check that we get
+        // every action and not just the latest
+        let num_states = 2;
+
+        // state 0: go to state 1 on PaddingSent, pad after 10 usec
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s0 = State::new(t, num_states);
+        s0.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 10.0,
+            param2: 10.0,
+            start: 0.0,
+            max: 0.0,
+        };
+
+        // state 1: go to state 0 on PaddingRecv, pad after 1 usec
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::PaddingRecv, e);
+        let mut s1 = State::new(t, num_states);
+        s1.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 1.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+
+        let current_time = Instant::now();
+        let mtu = 150;
+        let machines = vec![m];
+        let mut f = Framework::new(&machines, 0.0, 0.0, mtu, current_time).unwrap();
+
+        let events = [
+            TriggerEvent::PaddingSent {
+                bytes_sent: 0,
+                machine: MachineId(0),
+            },
+            TriggerEvent::NonPaddingRecv { bytes_recv: 0 },
+            TriggerEvent::PaddingRecv { bytes_recv: 0 },
+            TriggerEvent::PaddingSent {
+                bytes_sent: 0,
+                machine: MachineId(0),
+            },
+        ];
+        let pad = |timeout| Action::InjectPadding {
+            timeout: Duration::from_micros(timeout),
+            size: mtu,
+            bypass: false,
+            replace: false,
+            machine: MachineId(0),
+        };
+
+        let actions: Vec<TriggeredAction> = f
+            .trigger_events_all(&events, current_time)
+            .cloned()
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                TriggeredAction {
+                    event_index: 0,
+                    action: pad(1),
+                },
+                TriggeredAction {
+                    event_index: 2,
+                    action: pad(10),
+                },
+                TriggeredAction {
+                    event_index: 3,
+                    action: pad(1),
+                },
+            ]
+        );
+        // the latest action per machine is still available
+        assert_eq!(f.actions[0], Some(pad(1)));
+
+        // the same batch with trigger_events only gives the latest action
+        let actions: Vec<Action> = f.trigger_events(&events, current_time).cloned().collect();
+        assert_eq!(actions, vec![pad(1)]);
+    }
+
+    #[test]
+    fn trigger_events_all_limit_retracts_action() {
+        // a machine that pads once per padding sent, with a limit of 2 padding
+        // packets in the padding state
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s1 = State::new(t, num_states);
+        s1.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 1.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.limit = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 2.0,
+            start: 0.0,
+            max: 0.0,
+        };
+
+        let m = Machine {
+            allowed_padding_bytes: 100000,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: false,
+        };
+
+        let current_time = Instant::now();
+        let mtu = 1500;
+        let machines = vec![m];
+        let mut f = Framework::new(&machines, 1.0, 0.0, mtu, current_time).unwrap();
+
+        let padding_sent = TriggerEvent::PaddingSent {
+            bytes_sent: mtu,
+            machine: MachineId(0),
+        };
+        let events = [
+            TriggerEvent::NonPaddingSent { bytes_sent: 100 },
+            padding_sent.clone(),
+            padding_sent.clone(),
+            padding_sent,
+        ];
+
+        // the padding sent at index 2 uses up the limit, so only the actions
+        // caused by the events at index 0 and 1 remain
+        let indices: Vec<usize> = f
+            .trigger_events_all(&events, current_time)
+            .map(|a| a.event_index)
+            .collect();
+        assert_eq!(indices, vec![0, 1]);
+        assert_eq!(f.actions[0], None);
+        assert_eq!(f.runtime[0].state_limit, 0);
+    }
+
     #[test]
     fn validate_machine() {
         let num_states = 1;
@@ -1020,8 +2312,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0.clone()],
             include_small_packets: true,
         };
@@ -1042,8 +2337,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0.clone()],
             include_small_packets: true,
         };
@@ -1056,8 +2354,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![],
             include_small_packets: true,
         };
@@ -1069,8 +2370,11 @@ mod tests {
         let mut m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0.clone()],
             include_small_packets: true,
         };
@@ -1137,8 +2441,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000 * 1024,
             max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -1191,6 +2498,91 @@ mod tests {
         }
     }
 
+    #[test]
+    fn virtual_clock() {
+        // a virtual clock counting microseconds, to show that the framework
+        // does not depend on std::time::Instant
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        struct Micros(u64);
+        impl Add<Duration> for Micros {
+            type Output = Micros;
+            fn add(self, rhs: Duration) -> Micros {
+                Micros(self.0 + rhs.as_micros() as u64)
+            }
+        }
+        impl crate::time::Instant for Micros {
+            fn duration_since(&self, earlier: Self) -> Duration {
+                Duration::from_micros(self.0.saturating_sub(earlier.0))
+            }
+            fn checked_add(&self, duration: Duration) -> Option<Self> {
+                self.0.checked_add(duration.as_micros() as u64).map(Micros)
+            }
+            fn checked_sub(&self, duration: Duration) -> Option<Self> {
+                self.0.checked_sub(duration.as_micros() as u64).map(Micros)
+            }
+        }
+
+        // a machine that blocks for 10us, 1us after NonPaddingSent
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let mut s1 = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), num_states);
+        s1.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 1.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.action = Dist {
+            dist: DistType::Uniform,
+            param1: 10.0,
+            param2: 10.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.action_is_block = true;
+
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+        let machines = vec![m];
+        let mut f = Framework::new(&machines, 0.0, 0.0, 1500, Micros(0)).unwrap();
+
+        _ = f.trigger_events(&[TriggerEvent::NonPaddingSent { bytes_sent: 0 }], Micros(0));
+        assert_eq!(
+            f.actions[0],
+            Some(Action::BlockOutgoing {
+                timeout: Duration::from_micros(1),
+                duration: Duration::from_micros(10),
+                bypass: false,
+                replace: false,
+                machine: MachineId(0),
+            })
+        );
+
+        _ = f.trigger_events(
+            &[TriggerEvent::BlockingBegin {
+                machine: MachineId(0),
+            }],
+            Micros(1),
+        );
+        _ = f.trigger_events(&[TriggerEvent::BlockingEnd], Micros(11));
+        assert_eq!(f.runtime[0].blocking_duration, Duration::from_micros(10));
+        assert_eq!(f.global_blocking_duration, Duration::from_micros(10));
+    }
+
     #[test]
     fn machine_max_padding_frac() {
         // We create a machine that should be allowed to send 100*MTU padding
@@ -1227,8 +2619,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 100 * (mtu as u64),
             max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -1337,8 +2732,11 @@ mod tests {
         let m1 = Machine {
             allowed_padding_bytes: 100 * (mtu as u64),
             max_padding_frac: 0.0, // NOTE
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -1494,8 +2892,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 10, // NOTE
             max_blocking_frac: 0.5,       // NOTE
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: false,
         };
@@ -1617,8 +3018,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 10, // NOTE
             max_blocking_frac: 0.0,       // NOTE, 0.0 here, 0.5 in framework below
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: false,
         };
@@ -1706,7 +3110,7 @@ mod tests {
         // Plan: create two machines. #0 will exceed its blocking limit
         // and no longer be allowed to block. #1 will then enable blocking,
         // so #0 should now be able to overwrite that blocking regardless
-        // of its limit (special case in below_limit_blocking).
+        // of its limit (special case in blocking_limit).
 
         // state 0, first machine
         let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
@@ -1737,8 +3141,11 @@ mod tests {
         let m0 = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 2, // NOTE
             max_blocking_frac: 0.5,      // NOTE
+            blocking_window: Window::Lifetime,
             states: vec![s0],
             include_small_packets: false,
         };
@@ -1771,8 +3178,11 @@ mod tests {
         let m1 = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0, // NOTE
             max_blocking_frac: 0.0,      // NOTE
+            blocking_window: Window::Lifetime,
             states: vec![s0],
             include_small_packets: false,
         };
@@ -1895,8 +3305,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 100000, // NOTE, will not apply
             max_padding_frac: 1.0,         // NOTE, will not apply
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: false,
         };
@@ -1944,4 +3357,1216 @@ mod tests {
         assert_eq!(f.actions[0], None);
         assert_eq!(f.runtime[0].state_limit, 0);
     }
+
+    #[test]
+    fn seeded_rng_is_deterministic() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        // a machine where every transition, timeout, size, and limit is
+        // random: state 0 pads, state 1 blocks, and padding sent moves between
+        // them with equal probability
+        let num_states = 2;
+        let mut states = vec![];
+        for i in 0..num_states {
+            let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+            let mut e: HashMap<usize, f64> = HashMap::new();
+            e.insert(0, 0.5);
+            e.insert(1, 0.5);
+            t.insert(Event::PaddingSent, e.clone());
+            t.insert(Event::NonPaddingSent, e);
+            let mut s = State::new(t, num_states);
+            s.timeout = Dist {
+                dist: DistType::Uniform,
+                param1: 0.0,
+                param2: 1000.0,
+                start: 0.0,
+                max: 0.0,
+            };
+            s.action = Dist {
+                dist: DistType::Normal,
+                param1: 500.0,
+                param2: 100.0,
+                start: 0.0,
+                max: 0.0,
+            };
+            s.limit = Dist {
+                dist: DistType::Poisson,
+                param1: 10.0,
+                param2: 0.0,
+                start: 1.0,
+                max: 0.0,
+            };
+            s.action_is_block = i == 1;
+            states.push(s);
+        }
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 1000 * 1000,
+            max_blocking_frac: 1.0,
+            blocking_window: Window::Lifetime,
+            states,
+            include_small_packets: true,
+        };
+        let machines = vec![m];
+
+        let run = |seed: u64| -> Vec<Action> {
+            let mut current_time = Instant::now();
+            let mut f = Framework::new_with_rng(
+                &machines,
+                0.0,
+                0.0,
+                1500,
+                current_time,
+                StdRng::seed_from_u64(seed),
+            )
+            .unwrap();
+            let mut actions = vec![];
+            for i in 0..100 {
+                current_time = current_time.add(Duration::from_micros(10));
+                let event = if i % 3 == 0 {
+                    TriggerEvent::NonPaddingSent { bytes_sent: 1000 }
+                } else {
+                    TriggerEvent::PaddingSent {
+                        bytes_sent: 1000,
+                        machine: MachineId(0),
+                    }
+                };
+                actions.extend(f.trigger_events(&[event], current_time).cloned());
+            }
+            actions
+        };
+
+        let a = run(1234);
+        assert!(!a.is_empty());
+        assert_eq!(a, run(1234));
+        assert_ne!(a, run(4321));
+    }
+
+    #[test]
+    fn counter_zero() {
+        // a machine that pads twice after NonPaddingSent, counted down by
+        // counter 0, then cancels once the counter reaches zero
+        let num_states = 3;
+        let padding = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 2.0,
+            start: 0.0,
+            max: 0.0,
+        };
+
+        // state 0: wait for NonPaddingSent
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+
+        // state 1: set counter 0 to 2 and pad
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(2, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s1 = State::new(t, num_states);
+        s1.timeout = padding;
+        s1.counters[0] = Some(CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(padding),
+        });
+
+        // state 2: decrement counter 0 by 1 and pad, cancel on zero
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(2, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(STATECANCEL, 1.0);
+        t.insert(Event::CounterZero, e);
+        let mut s2 = State::new(t, num_states);
+        s2.timeout = padding;
+        s2.counters[0] = Some(CounterUpdate {
+            operation: CounterOperation::Decrement,
+            value: None,
+        });
+
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1, s2],
+            include_small_packets: true,
+        };
+        let machines = vec![m];
+        let mut current_time = Instant::now();
+        let mut f = Framework::new(&machines, 0.0, 0.0, 150, current_time).unwrap();
+        let pad = Some(Action::InjectPadding {
+            timeout: Duration::from_micros(2),
+            size: 150,
+            bypass: false,
+            replace: false,
+            machine: MachineId(0),
+        });
+        let sent = TriggerEvent::PaddingSent {
+            bytes_sent: 150,
+            machine: MachineId(0),
+        };
+
+        _ = f.trigger_events(
+            &[TriggerEvent::NonPaddingSent { bytes_sent: 150 }],
+            current_time,
+        );
+        assert_eq!(f.actions[0], pad);
+        assert_eq!(f.runtime[0].counters, [2, 0]);
+
+        current_time = current_time.add(Duration::from_micros(2));
+        _ = f.trigger_events(std::slice::from_ref(&sent), current_time);
+        assert_eq!(f.actions[0], pad);
+        assert_eq!(f.runtime[0].counters, [1, 0]);
+
+        // reaches zero: CounterZero cancels the padding just scheduled
+        current_time = current_time.add(Duration::from_micros(2));
+        _ = f.trigger_events(std::slice::from_ref(&sent), current_time);
+        assert_eq!(
+            f.actions[0],
+            Some(Action::Cancel {
+                machine: MachineId(0)
+            })
+        );
+        assert_eq!(f.runtime[0].counters, [0, 0]);
+
+        // already zero, so no more CounterZero
+        current_time = current_time.add(Duration::from_micros(2));
+        _ = f.trigger_events(&[sent], current_time);
+        assert_eq!(f.actions[0], pad);
+        assert_eq!(f.runtime[0].counters, [0, 0]);
+    }
+
+    #[test]
+    fn counter_zero_loop() {
+        // a valid machine where each of states 1 and 2 zeroes the counter set
+        // by the other, transitioning between them on CounterZero forever
+        let num_states = 3;
+        let one = Dist {
+            dist: DistType::Uniform,
+            param1: 1.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let set = Some(CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(one),
+        });
+        let decrement = Some(CounterUpdate {
+            operation: CounterOperation::Decrement,
+            value: None,
+        });
+
+        // state 0: wait for NonPaddingSent
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+
+        // state 1: set counter 0, decrement counter 1
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(2, 1.0);
+        t.insert(Event::NonPaddingSent, e.clone());
+        t.insert(Event::CounterZero, e);
+        let mut s1 = State::new(t, num_states);
+        s1.counters = [set, decrement];
+
+        // state 2: decrement counter 0, set counter 1
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::CounterZero, e);
+        let mut s2 = State::new(t, num_states);
+        s2.counters = [decrement, set];
+
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1, s2],
+            include_small_packets: true,
+        };
+        m.validate().unwrap();
+        let machines = vec![m];
+        let current_time = Instant::now();
+        let mut f = Framework::new(&machines, 0.0, 0.0, 150, current_time).unwrap();
+
+        let sent = TriggerEvent::NonPaddingSent { bytes_sent: 150 };
+        _ = f.trigger_events(std::slice::from_ref(&sent), current_time);
+        assert_eq!(f.runtime[0].current_state, 1);
+        assert_eq!(f.runtime[0].counters, [1, 0]);
+
+        // moving to state 2 zeroes counter 0, starting the loop, which stops
+        // after MAXCOUNTERZERO transitions (an even number, so in state 2)
+        _ = f.trigger_events(std::slice::from_ref(&sent), current_time);
+        assert_eq!(f.runtime[0].current_state, 2);
+        assert_eq!(f.runtime[0].counters, [0, 1]);
+        assert!(f.counter_zero_queue.is_empty());
+
+        // the next event gets a new budget of transitions
+        _ = f.trigger_events(
+            &[TriggerEvent::CounterZero {
+                machine: MachineId(0),
+            }],
+            current_time,
+        );
+        assert_eq!(f.runtime[0].current_state, 1);
+        assert_eq!(f.runtime[0].counters, [1, 0]);
+
+        // CounterZero for unknown machines is ignored
+        _ = f.trigger_events(
+            &[TriggerEvent::CounterZero {
+                machine: MachineId::from_raw(99),
+            }],
+            current_time,
+        );
+        assert_eq!(f.runtime[0].current_state, 1);
+    }
+
+    #[test]
+    fn timer_action() {
+        // a machine that arms a 10us timer on NonPaddingSent and ends when the
+        // timer expires, without padding or blocking
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(STATEEND, 1.0);
+        t.insert(Event::TimerEnd, e);
+        let mut s1 = State::new(t, num_states);
+        s1.action = Dist {
+            dist: DistType::Uniform,
+            param1: 10.0,
+            param2: 10.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.action_is_timer = true;
+        s1.replace = true;
+
+        // no padding or blocking allowed, timers are not limited by that
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+        let machines = vec![m];
+        let mut current_time = Instant::now();
+        let mut f = Framework::new(&machines, 0.0, 0.0, 150, current_time).unwrap();
+
+        _ = f.trigger_events(
+            &[TriggerEvent::NonPaddingSent { bytes_sent: 150 }],
+            current_time,
+        );
+        assert_eq!(
+            f.actions[0],
+            Some(Action::UpdateTimer {
+                duration: Duration::from_micros(10),
+                replace: true,
+                machine: MachineId(0),
+            })
+        );
+
+        // the timer starting does not cause any transition
+        _ = f.trigger_events(
+            &[TriggerEvent::TimerBegin {
+                machine: MachineId(0),
+            }],
+            current_time,
+        );
+        assert_eq!(f.actions[0], None);
+        assert_eq!(f.runtime[0].current_state, 1);
+
+        // the timer of another machine does not matter
+        current_time = current_time.add(Duration::from_micros(10));
+        _ = f.trigger_events(
+            &[TriggerEvent::TimerEnd {
+                machine: MachineId(1),
+            }],
+            current_time,
+        );
+        assert_eq!(f.runtime[0].current_state, 1);
+
+        _ = f.trigger_events(
+            &[TriggerEvent::TimerEnd {
+                machine: MachineId(0),
+            }],
+            current_time,
+        );
+        assert_eq!(f.actions[0], None);
+        assert_eq!(f.runtime[0].current_state, STATEEND);
+    }
+
+    #[test]
+    fn add_and_remove_machines() {
+        // a machine that pads right away on every NonPaddingSent
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t.clone(), num_states);
+        let mut s1 = State::new(t, num_states);
+        s1.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 0.0,
+            param2: 0.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+
+        let current_time = Instant::now();
+        let mut f = Framework::new(vec![m.clone()], 0.5, 0.0, 150, current_time).unwrap();
+        _ = f.trigger_events(
+            &[
+                TriggerEvent::NonPaddingSent { bytes_sent: 150 },
+                TriggerEvent::PaddingSent {
+                    bytes_sent: 150,
+                    machine: MachineId(0),
+                },
+            ],
+            current_time,
+        );
+        assert_eq!(f.global_paddingsent_bytes, 150);
+        assert_eq!(f.global_nonpadding_sent_bytes, 150);
+
+        // adding a machine keeps the global accounting
+        let id = f.add_machine(m.clone(), current_time).unwrap();
+        assert_eq!(id, MachineId(1));
+        assert_eq!(f.num_machines(), 2);
+        assert_eq!(f.global_paddingsent_bytes, 150);
+        assert_eq!(f.global_nonpadding_sent_bytes, 150);
+        assert_eq!(f.runtime[1].padding_sent, 0);
+
+        // both machines act, the new one from its first state
+        let actions: Vec<Action> = f
+            .trigger_events(
+                &[TriggerEvent::NonPaddingSent { bytes_sent: 150 }],
+                current_time,
+            )
+            .cloned()
+            .collect();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1].machine(), id);
+
+        // a retired machine never acts again
+        f.remove_machine(MachineId(0)).unwrap();
+        assert_eq!(f.machines[0], m);
+        let actions: Vec<Action> = f
+            .trigger_events(
+                &[
+                    TriggerEvent::PaddingSent {
+                        bytes_sent: 150,
+                        machine: MachineId(0),
+                    },
+                    TriggerEvent::NonPaddingSent { bytes_sent: 150 },
+                ],
+                current_time,
+            )
+            .cloned()
+            .collect();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].machine(), id);
+        assert_eq!(f.global_paddingsent_bytes, 300);
+
+        // errors
+        assert_eq!(
+            f.remove_machine(MachineId(0)),
+            Err(FrameworkError::RetiredMachine(MachineId(0)))
+        );
+        assert_eq!(
+            f.remove_machine(MachineId(2)),
+            Err(FrameworkError::UnknownMachine(MachineId(2)))
+        );
+        let mut invalid = m.clone();
+        invalid.states.clear();
+        assert_eq!(
+            f.add_machine(invalid, current_time),
+            Err(FrameworkError::InvalidMachine {
+                index: 2,
+                error: MachineError::NoStates
+            })
+        );
+        assert_eq!(f.num_machines(), 2);
+
+        // the id of the retired machine is never reused, and stale events
+        // for it are not credited to a machine that reacts to them
+        let mut stale = m.clone();
+        let mut e = HashMap::new();
+        e.insert(1, 1.0);
+        let mut t = HashMap::new();
+        t.insert(Event::PaddingSent, e.clone());
+        t.insert(Event::TimerEnd, e);
+        stale.states[0] = State::new(t, num_states);
+        let id = f.add_machine(stale, current_time).unwrap();
+        assert_eq!(id, MachineId(2));
+        assert_eq!(f.num_machines(), 3);
+        let actions: Vec<Action> = f
+            .trigger_events(
+                &[
+                    TriggerEvent::PaddingSent {
+                        bytes_sent: 150,
+                        machine: MachineId(0),
+                    },
+                    TriggerEvent::TimerEnd {
+                        machine: MachineId(0),
+                    },
+                ],
+                current_time,
+            )
+            .cloned()
+            .collect();
+        assert!(actions.is_empty());
+        assert_eq!(f.runtime[2].padding_sent, 0);
+        assert_eq!(f.runtime[2].current_state, 0);
+
+        // while events for its own id are
+        let actions: Vec<Action> = f
+            .trigger_events(&[TriggerEvent::TimerEnd { machine: id }], current_time)
+            .cloned()
+            .collect();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].machine(), id);
+        assert_eq!(f.runtime[2].current_state, 1);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        // a machine that pads and blocks, to touch all runtime state
+        let num_states = 3;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(2, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s1 = State::new(t, num_states);
+        s1.limit = Dist {
+            dist: DistType::Uniform,
+            param1: 5.0,
+            param2: 5.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.counters[0] = Some(CounterUpdate {
+            operation: CounterOperation::Increment,
+            value: None,
+        });
+        let mut s2 = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), num_states);
+        s2.action_is_block = true;
+        let m = Machine {
+            allowed_padding_bytes: 1000 * 1024,
+            max_padding_frac: 1.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 1000,
+            max_blocking_frac: 1.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1, s2],
+            include_small_packets: true,
+        };
+        let machines = vec![m.clone(), m];
+
+        let start = Instant::now();
+        let mut f = Framework::new(&machines, 0.5, 0.5, 1420, start).unwrap();
+        _ = f.trigger_events(
+            &[
+                TriggerEvent::NonPaddingSent { bytes_sent: 100 },
+                TriggerEvent::PaddingSent {
+                    bytes_sent: 200,
+                    machine: MachineId(1),
+                },
+                TriggerEvent::BlockingBegin {
+                    machine: MachineId(1),
+                },
+            ],
+            start.add(Duration::from_micros(10)),
+        );
+        _ = f.trigger_events(
+            &[TriggerEvent::UpdateMTU { new_mtu: 1280 }],
+            start.add(Duration::from_micros(20)),
+        );
+
+        // snapshot, serialize, and restore in a "new process" at another time
+        let reference = start.add(Duration::from_micros(30));
+        let snapshot = f.snapshot(reference);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: FrameworkSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, parsed);
+
+        let later = start.add(Duration::from_secs(60));
+        let mut g = Framework::new(&machines, 0.0, 0.0, 150, later).unwrap();
+        g.restore(&parsed, later).unwrap();
+        assert_eq!(g.snapshot(later), snapshot);
+        assert_eq!(g.mtu, 1280);
+        assert_eq!(g.runtime[0].current_state, 1);
+        assert_eq!(g.runtime[0].counters, [1, 0]);
+        assert_eq!(g.runtime[1].current_state, 2);
+        assert_eq!(g.runtime[1].padding_sent, 200);
+        assert!(g.global_blocking_active);
+        assert_eq!(
+            later.duration_since(g.global_blocking_started),
+            Duration::from_micros(20)
+        );
+        assert_eq!(
+            later.duration_since(g.runtime[1].machine_start),
+            Duration::from_micros(30)
+        );
+
+        // blocking accounting continues across the snapshot
+        _ = g.trigger_events(
+            &[TriggerEvent::BlockingEnd],
+            later.add(Duration::from_micros(5)),
+        );
+        assert_eq!(g.global_blocking_duration, Duration::from_micros(25));
+
+        // the machines have to match
+        let mut other = machines.clone();
+        other[1].allowed_padding_bytes = 0;
+        let mut h = Framework::new(&other, 0.0, 0.0, 150, later).unwrap();
+        assert!(matches!(
+            h.restore(&snapshot, later),
+            Err(FrameworkError::SnapshotMismatch(_))
+        ));
+        let mut h = Framework::new(&machines[..1], 0.0, 0.0, 150, later).unwrap();
+        assert!(matches!(
+            h.restore(&snapshot, later),
+            Err(FrameworkError::SnapshotMismatch(_))
+        ));
+
+        // and so does the version
+        let mut future = snapshot.clone();
+        future.version = SNAPSHOTVERSION + 1;
+        assert_eq!(
+            g.restore(&future, later),
+            Err(FrameworkError::UnsupportedSnapshotVersion(
+                SNAPSHOTVERSION + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn introspection_stats() {
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let s0 = State::new(t, num_states);
+        let s1 = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), num_states);
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.5,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+
+        let start = Instant::now();
+        let mut f = Framework::new(vec![m.clone()], 0.25, 0.75, 1420, start).unwrap();
+        let id = f
+            .add_machine(m.clone(), start.add(Duration::from_micros(10)))
+            .unwrap();
+        _ = f.trigger_events(
+            &[
+                TriggerEvent::NonPaddingSent { bytes_sent: 300 },
+                TriggerEvent::PaddingSent {
+                    bytes_sent: 100,
+                    machine: MachineId(0),
+                },
+                TriggerEvent::BlockingBegin {
+                    machine: MachineId(0),
+                },
+            ],
+            start.add(Duration::from_micros(20)),
+        );
+        let late = f
+            .add_machine(m, start.add(Duration::from_micros(25)))
+            .unwrap();
+        _ = f.trigger_events(&[], start.add(Duration::from_micros(30)));
+
+        let g = f.stats();
+        assert_eq!(g.padding_sent, 100);
+        assert_eq!(g.nonpadding_sent, 300);
+        assert_eq!(g.padding_frac, 0.25);
+        assert_eq!(g.max_padding_frac, 0.25);
+        assert!(g.blocking_active);
+        assert_eq!(g.blocking_duration, Duration::from_micros(10));
+        assert_eq!(g.running_duration, Duration::from_micros(30));
+        assert_eq!(g.blocking_frac, 1.0 / 3.0);
+        assert_eq!(g.max_blocking_frac, 0.75);
+        assert_eq!(g.mtu, 1420);
+
+        let m0 = f.machine_stats(MachineId(0)).unwrap();
+        assert_eq!(m0.current_state, 1);
+        assert_eq!(m0.padding_sent, 100);
+        assert_eq!(m0.nonpadding_sent, 300);
+        assert_eq!(m0.padding_frac, 0.25);
+        assert_eq!(m0.blocking_duration, Duration::from_micros(10));
+        assert_eq!(m0.blocking_frac, 1.0 / 3.0);
+        assert!(!m0.retired);
+
+        // the added machine has been running for less time and sent no padding
+        let m1 = f.machine_stats(id).unwrap();
+        assert_eq!(m1.current_state, 1);
+        assert_eq!(m1.padding_sent, 0);
+        assert_eq!(m1.padding_frac, 0.0);
+        assert_eq!(m1.running_duration, Duration::from_micros(20));
+        assert_eq!(m1.blocking_frac, 0.5);
+
+        // the machine added while blocking is only blocked since it was added
+        let m2 = f.machine_stats(late).unwrap();
+        assert_eq!(m2.blocking_duration, Duration::from_micros(5));
+        assert_eq!(m2.blocking_frac, 1.0);
+
+        // blocking ends, only completed blocking is counted from now on
+        _ = f.trigger_events(
+            &[TriggerEvent::BlockingEnd],
+            start.add(Duration::from_micros(40)),
+        );
+        _ = f.trigger_events(&[], start.add(Duration::from_micros(80)));
+        let g = f.stats();
+        assert!(!g.blocking_active);
+        assert_eq!(g.blocking_duration, Duration::from_micros(20));
+        assert_eq!(g.blocking_frac, 0.25);
+        let m0 = f.machine_stats(MachineId(0)).unwrap();
+        assert_eq!(m0.blocking_duration, Duration::from_micros(20));
+        let m2 = f.machine_stats(late).unwrap();
+        assert_eq!(m2.blocking_duration, Duration::from_micros(15));
+
+        f.remove_machine(id).unwrap();
+        let m1 = f.machine_stats(id).unwrap();
+        assert!(m1.retired);
+        assert_eq!(m1.current_state, STATEEND);
+        assert!(f.machine_stats(MachineId(3)).is_none());
+    }
+
+    #[test]
+    fn observer_callbacks() {
+        #[derive(Debug, PartialEq)]
+        enum Record {
+            Transition(MachineId, Event, usize, usize),
+            LimitSampled(MachineId, usize, u64),
+            Action(Action),
+            Suppressed(MachineId, usize, ActionLimit),
+            LimitReached(MachineId, usize),
+            Counter(MachineId, usize, u64, u64),
+        }
+        #[derive(Default)]
+        struct Recorder(Vec<Record>);
+        impl Observer for Recorder {
+            fn on_transition(&mut self, machine: MachineId, event: Event, from: usize, to: usize) {
+                self.0.push(Record::Transition(machine, event, from, to));
+            }
+            fn on_limit_sampled(&mut self, machine: MachineId, state: usize, limit: u64) {
+                self.0.push(Record::LimitSampled(machine, state, limit));
+            }
+            fn on_action(&mut self, action: &Action) {
+                self.0.push(Record::Action(action.clone()));
+            }
+            fn on_action_suppressed(
+                &mut self,
+                machine: MachineId,
+                state: usize,
+                limit: ActionLimit,
+            ) {
+                self.0.push(Record::Suppressed(machine, state, limit));
+            }
+            fn on_limit_reached(&mut self, machine: MachineId, state: usize) {
+                self.0.push(Record::LimitReached(machine, state));
+            }
+            fn on_counter_update(
+                &mut self,
+                machine: MachineId,
+                counter: usize,
+                old: u64,
+                new: u64,
+            ) {
+                self.0.push(Record::Counter(machine, counter, old, new));
+            }
+        }
+
+        // pad once on NonPaddingRecv, then keep padding on PaddingSent, but
+        // limited by the machine padding fraction and the state limit
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingRecv, e);
+        let s0 = State::new(t, num_states);
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s1 = State::new(t, num_states);
+        s1.limit = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 2.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.timeout = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 2.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s1.counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Increment,
+            value: None,
+        });
+        let m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+        let machines = vec![m];
+        let current_time = Instant::now();
+        let mut f = Framework::new(&machines, 0.0, 0.0, 1420, current_time)
+            .unwrap()
+            .with_observer(Recorder::default());
+
+        _ = f.trigger_events(
+            &[TriggerEvent::NonPaddingRecv { bytes_recv: 100 }],
+            current_time,
+        );
+        _ = f.trigger_events(
+            &[TriggerEvent::PaddingSent {
+                bytes_sent: 1420,
+                machine: MachineId(0),
+            }],
+            current_time,
+        );
+        _ = f.trigger_events(
+            &[TriggerEvent::PaddingSent {
+                bytes_sent: 1420,
+                machine: MachineId(0),
+            }],
+            current_time,
+        );
+
+        let id = MachineId(0);
+        assert_eq!(
+            f.observer().0,
+            vec![
+                Record::Transition(id, Event::NonPaddingRecv, 0, 1),
+                Record::LimitSampled(id, 1, 2),
+                Record::Action(Action::InjectPadding {
+                    timeout: Duration::from_micros(2),
+                    size: 1420,
+                    bypass: false,
+                    replace: false,
+                    machine: id,
+                }),
+                Record::Counter(id, 1, 0, 1),
+                Record::Transition(id, Event::PaddingSent, 1, 1),
+                Record::Suppressed(id, 1, ActionLimit::MachinePadding),
+                Record::Counter(id, 1, 1, 2),
+                Record::Transition(id, Event::PaddingSent, 1, 1),
+                Record::Suppressed(id, 1, ActionLimit::MachinePadding),
+                Record::Counter(id, 1, 2, 3),
+                Record::LimitReached(id, 1),
+            ]
+        );
+
+        // the observer can be taken over and replaced
+        f.observer_mut().0.clear();
+        let f = f.with_observer(NoObserver);
+        assert_eq!(*f.observer(), NoObserver);
+    }
+
+    #[test]
+    fn windowed_budgets() {
+        let machine = |padding_window, blocking_window, action_is_block| {
+            let mut s0 = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), 1);
+            s0.action_is_block = action_is_block;
+            Machine {
+                allowed_padding_bytes: 0,
+                max_padding_frac: 0.5,
+                padding_window,
+                padding_rate_limit: None,
+                allowed_blocked_microsec: 0,
+                max_blocking_frac: 0.5,
+                blocking_window,
+                states: vec![s0],
+                include_small_packets: true,
+            }
+        };
+        let start = Instant::now();
+        let at = |us| start.add(Duration::from_micros(us));
+        let padding = |f: &mut Framework<_, _, _>, bytes, us| {
+            _ = f.trigger_events(
+                &[TriggerEvent::PaddingSent {
+                    bytes_sent: bytes,
+                    machine: MachineId(0),
+                }],
+                at(us),
+            );
+        };
+        let nonpadding = |f: &mut Framework<_, _, _>, bytes, us| {
+            _ = f.trigger_events(
+                &[TriggerEvent::NonPaddingSent { bytes_sent: bytes }],
+                at(us),
+            );
+        };
+
+        // machine padding over the last millisecond: headroom from sending a
+        // lot of nonpadding long ago does not count
+        let lifetime = vec![machine(Window::Lifetime, Window::Lifetime, false)];
+        let window = vec![machine(Window::Microsec(1000), Window::Lifetime, false)];
+        let mut f = Framework::new(&lifetime, 0.0, 0.0, 1420, start).unwrap();
+        let mut g = Framework::new(&window, 0.0, 0.0, 1420, start).unwrap();
+        for f in [&mut f, &mut g] {
+            nonpadding(f, 10000, 0);
+            padding(f, 1420, 5000);
+        }
+        assert_eq!(f.action_limit(&f.runtime[0], &lifetime[0]), None);
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &window[0]),
+            Some(ActionLimit::MachinePadding)
+        );
+        assert_eq!(g.machine_stats(MachineId(0)).unwrap().padding_frac, 1.0);
+        nonpadding(&mut g, 2000, 5100);
+        assert_eq!(g.action_limit(&g.runtime[0], &window[0]), None);
+
+        // framework padding over the last 4000 bytes
+        let unlimited = vec![Machine {
+            max_padding_frac: 0.0,
+            ..machine(Window::Lifetime, Window::Lifetime, false)
+        }];
+        let mut f = Framework::new(&unlimited, 0.5, 0.0, 1420, start).unwrap();
+        let mut g = Framework::new(&unlimited, 0.5, 0.0, 1420, start)
+            .unwrap()
+            .with_windows(Window::Bytes(4000), Window::Lifetime)
+            .unwrap();
+        for f in [&mut f, &mut g] {
+            nonpadding(f, 10000, 0);
+            padding(f, 3000, 10);
+        }
+        assert_eq!(f.action_limit(&f.runtime[0], &unlimited[0]), None);
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &unlimited[0]),
+            Some(ActionLimit::FrameworkPadding)
+        );
+
+        // machine blocking over the last millisecond, after a long idle time
+        let lifetime = vec![machine(Window::Lifetime, Window::Lifetime, true)];
+        let window = vec![machine(Window::Lifetime, Window::Microsec(1000), true)];
+        let mut f = Framework::new(&lifetime, 0.0, 0.0, 1420, start).unwrap();
+        let mut g = Framework::new(&window, 0.0, 0.0, 1420, start).unwrap();
+        for f in [&mut f, &mut g] {
+            _ = f.trigger_events(
+                &[TriggerEvent::BlockingBegin {
+                    machine: MachineId(0),
+                }],
+                at(100_000),
+            );
+            _ = f.trigger_events(&[], at(100_600));
+        }
+        assert_eq!(f.action_limit(&f.runtime[0], &lifetime[0]), None);
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &window[0]),
+            Some(ActionLimit::MachineBlocking)
+        );
+        _ = g.trigger_events(&[TriggerEvent::BlockingEnd], at(100_600));
+        _ = g.trigger_events(&[], at(101_500));
+        assert_eq!(g.machine_stats(MachineId(0)).unwrap().blocking_frac, 0.3);
+        assert_eq!(g.action_limit(&g.runtime[0], &window[0]), None);
+
+        // invalid windows
+        assert!(matches!(
+            Framework::new(&lifetime, 0.0, 0.0, 1420, start)
+                .unwrap()
+                .with_windows(Window::Lifetime, Window::Bytes(1)),
+            Err(FrameworkError::InvalidLimit(MachineError::InvalidWindow {
+                field: "blocking_window",
+                ..
+            }))
+        ));
+        assert!(Framework::new(&lifetime, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_windows(Window::Microsec(0), Window::Lifetime)
+            .is_err());
+    }
+
+    #[test]
+    fn padding_rate_limits() {
+        let rate = RateLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 1500,
+        };
+        // the rate limit applies even before the allowed padding is used up
+        let machine = |padding_rate_limit| Machine {
+            allowed_padding_bytes: 1_000_000,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![State::new(HashMap::<Event, HashMap<usize, f64>>::new(), 1)],
+            include_small_packets: true,
+        };
+        let start = Instant::now();
+        let at = |ms| start.add(Duration::from_millis(ms));
+        let padding = |f: &mut Framework<_, _, _>, ms| {
+            _ = f.trigger_events(
+                &[TriggerEvent::PaddingSent {
+                    bytes_sent: 1420,
+                    machine: MachineId(0),
+                }],
+                at(ms),
+            );
+        };
+
+        let limited = vec![machine(Some(rate))];
+        let unlimited = vec![machine(None)];
+        let mut f = Framework::new(&limited, 0.0, 0.0, 1420, start).unwrap();
+        let mut g = Framework::new(&unlimited, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_padding_rate_limit(rate)
+            .unwrap();
+        let mut h = Framework::new(&unlimited, 0.0, 0.0, 1420, start).unwrap();
+        for f in [&mut f, &mut g, &mut h] {
+            padding(f, 0);
+        }
+        assert_eq!(f.action_limit(&f.runtime[0], &limited[0]), None);
+        assert_eq!(g.action_limit(&g.runtime[0], &unlimited[0]), None);
+
+        // the bucket is empty after the second packet, until refilled at 1.34s
+        for f in [&mut f, &mut g, &mut h] {
+            padding(f, 100);
+        }
+        assert_eq!(
+            f.action_limit(&f.runtime[0], &limited[0]),
+            Some(ActionLimit::MachinePaddingRate)
+        );
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &unlimited[0]),
+            Some(ActionLimit::FrameworkPaddingRate)
+        );
+        assert_eq!(h.action_limit(&h.runtime[0], &unlimited[0]), None);
+        for f in [&mut f, &mut g] {
+            _ = f.trigger_events(&[], at(1300));
+        }
+        assert_eq!(
+            f.action_limit(&f.runtime[0], &limited[0]),
+            Some(ActionLimit::MachinePaddingRate)
+        );
+        for f in [&mut f, &mut g] {
+            _ = f.trigger_events(&[], at(1500));
+        }
+        assert_eq!(f.action_limit(&f.runtime[0], &limited[0]), None);
+        assert_eq!(g.action_limit(&g.runtime[0], &unlimited[0]), None);
+
+        // invalid rate limits
+        assert!(Framework::new(&limited, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_padding_rate_limit(RateLimit {
+                bytes_per_sec: 0,
+                burst_bytes: 1,
+            })
+            .is_err());
+        let mut invalid = machine(Some(rate));
+        invalid.padding_rate_limit.as_mut().unwrap().burst_bytes = 0;
+        assert!(matches!(
+            Framework::new(vec![invalid], 0.0, 0.0, 1420, start),
+            Err(FrameworkError::InvalidMachine {
+                index: 0,
+                error: MachineError::InvalidRateLimit(_)
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn shared_budget() {
+        let machine = |action_is_block| {
+            let mut s = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), 1);
+            s.action_is_block = action_is_block;
+            Machine {
+                allowed_padding_bytes: 0,
+                max_padding_frac: 0.0,
+                padding_window: Window::Lifetime,
+                padding_rate_limit: None,
+                allowed_blocked_microsec: 0,
+                max_blocking_frac: 0.0,
+                blocking_window: Window::Lifetime,
+                states: vec![s],
+                include_small_packets: true,
+            }
+        };
+        let padding = vec![machine(false)];
+        let blocking = vec![machine(true)];
+        let start = Instant::now();
+        let at = |us| start.add(Duration::from_micros(us));
+
+        // padding fraction across frameworks: one tunnel sends all the
+        // nonpadding, the other all the padding
+        let budget = Arc::new(SharedBudget::new(0.5, 0.5, 10_000).unwrap());
+        let mut f = Framework::new(&padding, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_shared_budget(budget.clone());
+        let mut g = Framework::new(&padding, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_shared_budget(budget.clone());
+        _ = f.trigger_events(&[TriggerEvent::NonPaddingSent { bytes_sent: 3000 }], at(0));
+        _ = g.trigger_events(
+            &[TriggerEvent::PaddingSent {
+                bytes_sent: 2000,
+                machine: MachineId(0),
+            }],
+            at(0),
+        );
+        assert_eq!(budget.padding_sent(), 2000);
+        assert_eq!(budget.nonpadding_sent(), 3000);
+        assert_eq!(f.action_limit(&f.runtime[0], &padding[0]), None);
+        _ = f.trigger_events(
+            &[TriggerEvent::PaddingSent {
+                bytes_sent: 1000,
+                machine: MachineId(0),
+            }],
+            at(0),
+        );
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &padding[0]),
+            Some(ActionLimit::SharedPadding)
+        );
+
+        // the byte ceiling is absolute
+        _ = f.trigger_events(
+            &[TriggerEvent::NonPaddingSent { bytes_sent: 60_000 }],
+            at(0),
+        );
+        assert_eq!(g.action_limit(&g.runtime[0], &padding[0]), None);
+        _ = f.trigger_events(
+            &[TriggerEvent::PaddingSent {
+                bytes_sent: 7000,
+                machine: MachineId(0),
+            }],
+            at(0),
+        );
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &padding[0]),
+            Some(ActionLimit::SharedPaddingBytes)
+        );
+
+        // blocking fraction across frameworks: g blocks 60 of the 200us the
+        // two frameworks run
+        let mut g = Framework::new(&blocking, 0.0, 0.0, 1420, start)
+            .unwrap()
+            .with_shared_budget(budget.clone());
+        _ = f.trigger_events(&[], at(100));
+        _ = g.trigger_events(
+            &[TriggerEvent::BlockingBegin {
+                machine: MachineId(0),
+            }],
+            at(40),
+        );
+        _ = g.trigger_events(&[TriggerEvent::BlockingEnd], at(100));
+        assert_eq!(budget.running_duration(), Duration::from_micros(200));
+        assert_eq!(budget.blocking_duration(), Duration::from_micros(60));
+        assert_eq!(g.action_limit(&g.runtime[0], &blocking[0]), None);
+        _ = g.trigger_events(
+            &[TriggerEvent::BlockingBegin {
+                machine: MachineId(0),
+            }],
+            at(100),
+        );
+        _ = g.trigger_events(&[], at(200));
+        assert_eq!(
+            g.action_limit(&g.runtime[0], &blocking[0]),
+            Some(ActionLimit::SharedBlocking)
+        );
+
+        // frameworks on many threads
+        let budget = Arc::new(SharedBudget::new(0.0, 0.0, 0).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let budget = budget.clone();
+                let padding = padding.clone();
+                std::thread::spawn(move || {
+                    let mut f = Framework::new(&padding, 0.0, 0.0, 1420, Instant::now())
+                        .unwrap()
+                        .with_shared_budget(budget);
+                    for _ in 0..100 {
+                        _ = f.trigger_events(
+                            &[TriggerEvent::NonPaddingSent { bytes_sent: 10 }],
+                            Instant::now(),
+                        );
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(budget.nonpadding_sent(), 4000);
+
+        assert_eq!(
+            SharedBudget::new(1.5, 0.0, 0).err(),
+            Some(FrameworkError::InvalidLimit(
+                MachineError::InvalidFraction {
+                    field: "max_padding_frac",
+                    value: 1.5
+                }
+            ))
+        );
+        assert!(SharedBudget::new(0.0, -0.5, 0).is_err());
+    }
 }
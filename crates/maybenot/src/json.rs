@@ -0,0 +1,644 @@
+//! A human-readable JSON format for machines, see [`Machine::to_json()`] and
+//! [`Machine::from_json()`].
+//!
+//! The format is versioned (the `version` field, currently
+//! [`JSONVERSION`]) and described by the JSON
+//! Schema in [`SCHEMA`]. An example machine with two states:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "allowed_padding_bytes": 1000,
+//!   "max_padding_frac": 0.5,
+//!   "allowed_blocked_microsec": 0,
+//!   "max_blocking_frac": 0.0,
+//!   "states": [
+//!     {
+//!       "name": "start",
+//!       "transitions": { "NonPaddingSent": { "pad": 1.0 } }
+//!     },
+//!     {
+//!       "name": "pad",
+//!       "timeout": { "type": "Uniform", "param1": 0.0, "param2": 1000.0 },
+//!       "limit": { "type": "Uniform", "param1": 1.0, "param2": 10.0 },
+//!       "transitions": { "PaddingSent": { "pad": 0.9, "END": 0.1 } }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Transitions are keyed by [`Event`] and then by target: the `name` of a
+//! state, the index of a state (as a string, e.g., `"1"`), or one of the
+//! pseudo-states `"END"` ([`STATEEND`]) and `"CANCEL"` ([`STATECANCEL`]).
+//! Only targets with a probability are listed, and any remaining probability
+//! up until 1.0 is a no-op transition. State names are only part of the JSON
+//! format: [`Machine::to_json()`] identifies states by index.
+//!
+//! Fields with defaults may be omitted: windows default to `"Lifetime"`,
+//! distributions to none, flags to false, and transitions and counter updates
+//! to empty. Unknown fields are errors. Syntax errors are reported with their
+//! line and column, and errors in the machine with the path to the field,
+//! e.g., `states[1].transitions.PaddingSent`.
+
+use crate::constants::*;
+use crate::dist::{Dist, DistType};
+use crate::error::MachineError;
+use crate::event::Event;
+use crate::machine::{Machine, MachineBuilder, StateBuilder, StateError, Target};
+use crate::rate::RateLimit;
+use crate::state::{CounterOperation, CounterUpdate};
+use crate::window::Window;
+use alloc::borrow::ToOwned;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The JSON Schema of the format.
+pub const SCHEMA: &str = include_str!("machine.schema.json");
+
+const TARGETEND: &str = "END";
+const TARGETCANCEL: &str = "CANCEL";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonMachine {
+    version: u32,
+    allowed_padding_bytes: u64,
+    max_padding_frac: f64,
+    #[serde(default)]
+    padding_window: Window,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padding_rate_limit: Option<RateLimit>,
+    allowed_blocked_microsec: u64,
+    max_blocking_frac: f64,
+    #[serde(default)]
+    blocking_window: Window,
+    #[serde(default)]
+    include_small_packets: bool,
+    states: Vec<JsonState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<JsonDist>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    action: Option<JsonDist>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit: Option<JsonDist>,
+    #[serde(default)]
+    action_is_block: bool,
+    #[serde(default)]
+    action_is_timer: bool,
+    #[serde(default)]
+    bypass: bool,
+    #[serde(default)]
+    replace: bool,
+    #[serde(default)]
+    limit_includes_nonpadding: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    counters: BTreeMap<usize, JsonCounterUpdate>,
+    #[serde(default)]
+    transitions: BTreeMap<Event, Targets>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonDist {
+    #[serde(rename = "type")]
+    dist: DistType,
+    #[serde(default)]
+    param1: f64,
+    #[serde(default)]
+    param2: f64,
+    #[serde(default)]
+    start: f64,
+    #[serde(default)]
+    max: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonCounterUpdate {
+    operation: CounterOperation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<JsonDist>,
+}
+
+/// The targets of transitions on an event, in order and without duplicates.
+#[derive(Debug, Default)]
+struct Targets(Vec<(String, f64)>);
+
+impl Serialize for Targets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (target, p) in &self.0 {
+            map.serialize_entry(target, p)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Targets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetsVisitor;
+
+        impl<'de> Visitor<'de> for TargetsVisitor {
+            type Value = Targets;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a map of target states to probabilities")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Targets, A::Error> {
+                let mut targets: Vec<(String, f64)> = Vec::new();
+                let mut seen = BTreeSet::new();
+                while let Some((target, p)) = map.next_entry::<String, f64>()? {
+                    if !seen.insert(target.clone()) {
+                        return Err(serde::de::Error::custom(format!(
+                            "duplicate target {}",
+                            target
+                        )));
+                    }
+                    targets.push((target, p));
+                }
+                Ok(Targets(targets))
+            }
+        }
+
+        deserializer.deserialize_map(TargetsVisitor)
+    }
+}
+
+impl From<Dist> for JsonDist {
+    fn from(d: Dist) -> Self {
+        // parameters not read by the type may be NaN or infinite, which JSON
+        // cannot represent
+        let param = |i, v: f64| {
+            if i >= d.dist.num_params() && !v.is_finite() {
+                0.0
+            } else {
+                v
+            }
+        };
+        JsonDist {
+            dist: d.dist,
+            param1: param(0, d.param1),
+            param2: param(1, d.param2),
+            start: d.start,
+            max: d.max,
+        }
+    }
+}
+
+impl From<JsonDist> for Dist {
+    fn from(d: JsonDist) -> Self {
+        Dist {
+            dist: d.dist,
+            param1: d.param1,
+            param2: d.param2,
+            start: d.start,
+            max: d.max,
+        }
+    }
+}
+
+// a distribution that is not set is omitted
+fn json_dist(d: Dist) -> Option<JsonDist> {
+    (d != Dist::new()).then(|| d.into())
+}
+
+impl Machine {
+    /// Serialize the machine into the human-readable JSON format, see
+    /// [`json`](crate::json). States are identified by index. The machine is
+    /// validated, so that it can be parsed by [`Machine::from_json()`].
+    pub fn to_json(&self) -> Result<String, MachineError> {
+        self.validate()?;
+        let num_states = self.states.len();
+        let states = self
+            .states
+            .iter()
+            .map(|state| JsonState {
+                name: None,
+                timeout: json_dist(state.timeout),
+                action: json_dist(state.action),
+                limit: json_dist(state.limit),
+                action_is_block: state.action_is_block,
+                action_is_timer: state.action_is_timer,
+                bypass: state.bypass,
+                replace: state.replace,
+                limit_includes_nonpadding: state.limit_includes_nonpadding,
+                counters: state
+                    .counters
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, update)| {
+                        update.map(|u| {
+                            (
+                                i,
+                                JsonCounterUpdate {
+                                    operation: u.operation,
+                                    value: u.value.map(JsonDist::from),
+                                },
+                            )
+                        })
+                    })
+                    .collect(),
+                transitions: state
+                    .next_state
+                    .iter()
+                    .map(|(event, probabilities)| {
+                        let targets = probabilities
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, p)| **p != 0.0)
+                            .map(|(i, p)| {
+                                let target = match i {
+                                    i if i < num_states => i.to_string(),
+                                    i if i == num_states => TARGETCANCEL.to_owned(),
+                                    _ => TARGETEND.to_owned(),
+                                };
+                                (target, *p)
+                            })
+                            .collect();
+                        (*event, Targets(targets))
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let m = JsonMachine {
+            version: JSONVERSION,
+            allowed_padding_bytes: self.allowed_padding_bytes,
+            max_padding_frac: self.max_padding_frac,
+            padding_window: self.padding_window,
+            padding_rate_limit: self.padding_rate_limit,
+            allowed_blocked_microsec: self.allowed_blocked_microsec,
+            max_blocking_frac: self.max_blocking_frac,
+            blocking_window: self.blocking_window,
+            include_small_packets: self.include_small_packets,
+            states,
+        };
+        serde_json::to_string_pretty(&m).map_err(|e| invalid("machine", e.to_string()))
+    }
+
+    /// Parse a machine from the human-readable JSON format, see
+    /// [`json`](crate::json). The machine is validated. Errors include the
+    /// line and column, or the path, of the problem. As when parsing
+    /// serialized machines, the size of the transition matrices of the states
+    /// is bounded by [`MAXMACHINESIZE`].
+    pub fn from_json(s: &str) -> Result<Machine, MachineError> {
+        let m: JsonMachine = serde_json::from_str(s).map_err(|e| {
+            let location = format!("line {} column {}", e.line(), e.column());
+            let reason = e.to_string();
+            let reason = reason
+                .strip_suffix(&format!(" at {}", location))
+                .unwrap_or(&reason);
+            invalid(location, reason)
+        })?;
+        if m.version != JSONVERSION {
+            return Err(invalid(
+                "version",
+                format!(
+                    "unsupported version {}, expected {}",
+                    m.version, JSONVERSION
+                ),
+            ));
+        }
+
+        // states are referred to by index with numbers
+        for (index, state) in m.states.iter().enumerate() {
+            let Some(name) = state.name.as_deref() else {
+                continue;
+            };
+            if name == TARGETEND || name == TARGETCANCEL || name.parse::<usize>().is_ok() {
+                return Err(invalid(
+                    format!("states[{}].name", index),
+                    format!(
+                        "{} is reserved or a number, which refer to states by index",
+                        name
+                    ),
+                ));
+            }
+        }
+
+        let mut builder = MachineBuilder::new()
+            .padding_budget(m.allowed_padding_bytes, m.max_padding_frac)
+            .padding_window(m.padding_window)
+            .blocking_budget(m.allowed_blocked_microsec, m.max_blocking_frac)
+            .blocking_window(m.blocking_window)
+            .include_small_packets(m.include_small_packets);
+        if let Some(rate_limit) = m.padding_rate_limit {
+            builder = builder.padding_rate_limit(rate_limit);
+        }
+        for state in &m.states {
+            let transitions = state
+                .transitions
+                .iter()
+                .flat_map(|(event, targets)| {
+                    targets
+                        .0
+                        .iter()
+                        .map(|(target, p)| (*event, target_of(target), *p))
+                })
+                .collect();
+            let s = StateBuilder {
+                timeout: state.timeout.map_or(Dist::new(), Dist::from),
+                action: state.action.map_or(Dist::new(), Dist::from),
+                action_is_block: state.action_is_block,
+                action_is_timer: state.action_is_timer,
+                bypass: state.bypass,
+                replace: state.replace,
+                limit: state.limit.map_or(Dist::new(), Dist::from),
+                limit_includes_nonpadding: state.limit_includes_nonpadding,
+                counters: state
+                    .counters
+                    .iter()
+                    .map(|(counter, update)| {
+                        (
+                            *counter,
+                            CounterUpdate {
+                                operation: update.operation,
+                                value: update.value.map(Dist::from),
+                            },
+                        )
+                    })
+                    .collect(),
+                transitions,
+            };
+            builder.states.push((state.name.clone(), s));
+        }
+
+        builder.build_with(|index, e| match e {
+            StateError::DuplicateName(other) => invalid(
+                format!("states[{}].name", index),
+                format!(
+                    "duplicate name {}, also used by states[{}]",
+                    m.states[index].name.as_deref().unwrap_or_default(),
+                    other
+                ),
+            ),
+            StateError::UnknownTarget(event, target) => invalid(
+                format!("states[{}].transitions.{}", index, event),
+                format!("unknown {}", target),
+            ),
+            StateError::DuplicateTarget(event, target) => invalid(
+                format!("states[{}].transitions.{}", index, event),
+                format!("{} is a target more than once", target),
+            ),
+            StateError::UnknownCounter(counter) => invalid(
+                format!("states[{}].counters", index),
+                format!("no counter {}, there are {}", counter, NUMCOUNTERS),
+            ),
+        })
+    }
+}
+
+// The target of a transition by name, index, or pseudo-state.
+fn target_of(target: &str) -> Target {
+    match target {
+        TARGETEND => Target::End,
+        TARGETCANCEL => Target::Cancel,
+        _ => match target.parse::<usize>() {
+            Ok(index) => Target::Index(index),
+            Err(_) => Target::State(target.to_owned()),
+        },
+    }
+}
+
+fn invalid(location: impl Into<String>, reason: impl Into<String>) -> MachineError {
+    MachineError::InvalidJson {
+        location: location.into(),
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::*;
+    use core::str::FromStr;
+
+    const EXAMPLE: &str = r#"{
+  "version": 1,
+  "allowed_padding_bytes": 1000,
+  "max_padding_frac": 0.5,
+  "allowed_blocked_microsec": 0,
+  "max_blocking_frac": 0.0,
+  "states": [
+    {
+      "name": "start",
+      "transitions": { "NonPaddingSent": { "pad": 1.0 } }
+    },
+    {
+      "name": "pad",
+      "timeout": { "type": "Uniform", "param1": 0.0, "param2": 1000.0 },
+      "limit": { "type": "Uniform", "param1": 1.0, "param2": 10.0 },
+      "transitions": { "PaddingSent": { "pad": 0.9, "END": 0.1 } }
+    }
+  ]
+}"#;
+
+    #[test]
+    fn example() {
+        let m = Machine::from_json(EXAMPLE).unwrap();
+        assert_eq!(m.states.len(), 2);
+        assert_eq!(
+            m.states[0].next_state[&Event::NonPaddingSent],
+            [0.0, 1.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            m.states[1].next_state[&Event::PaddingSent],
+            [0.0, 0.9, 0.0, 0.1]
+        );
+        assert_eq!(m.states[1].timeout.dist, DistType::Uniform);
+        assert_eq!(m.states[1].action, Dist::new());
+        assert_eq!(m.padding_window, Window::Lifetime);
+
+        // names are not kept, but the machine is the same
+        let json = m.to_json().unwrap();
+        assert!(!json.contains("pad\""));
+        assert_eq!(Machine::from_json(&json).unwrap(), m);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut m = Machine::from_json(EXAMPLE).unwrap();
+        m.padding_window = Window::Bytes(10_000);
+        m.blocking_window = Window::Microsec(1_000_000);
+        m.padding_rate_limit = Some(RateLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 1500,
+        });
+        m.include_small_packets = true;
+        m.states[0].counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(Dist {
+                dist: DistType::Poisson,
+                param1: 2.0,
+                param2: 0.0,
+                start: 1.0,
+                max: 10.0,
+            }),
+        });
+        m.states[0].action_is_timer = true;
+        m.states[0].action = Dist {
+            dist: DistType::Normal,
+            param1: 0.1,
+            param2: 1.0 / 3.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        m.states[0]
+            .next_state
+            .insert(Event::CounterZero, vec![0.25, 0.0, 0.75, 0.0]);
+
+        let json = m.to_json().unwrap();
+        assert_eq!(Machine::from_json(&json).unwrap(), m);
+
+        // to and from the binary format
+        let binary = Machine::from_json(&json).unwrap().serialize();
+        assert_eq!(Machine::from_str(&binary).unwrap().to_json().unwrap(), json);
+
+        // invalid machines, e.g., with parameters JSON cannot represent
+        m.states[1].limit.start = f64::INFINITY;
+        assert!(matches!(
+            m.to_json(),
+            Err(MachineError::InvalidDist {
+                state: 1,
+                field: "limit",
+                ..
+            })
+        ));
+
+        // parameters the dist does not read are written as zero
+        m.states[1].limit.start = 0.0;
+        m.states[0].counters[1]
+            .as_mut()
+            .unwrap()
+            .value
+            .as_mut()
+            .unwrap()
+            .param2 = f64::NAN;
+        let json = m.to_json().unwrap();
+        assert!(!json.contains("null"));
+        let parsed = Machine::from_json(&json).unwrap();
+        assert_eq!(
+            parsed.states[0].counters[1].unwrap().value.unwrap().param2,
+            0.0
+        );
+    }
+
+    #[test]
+    fn error_locations() {
+        // syntax and type errors have a line and column
+        let err = Machine::from_json(&EXAMPLE.replace("\"pad\": 0.9", "\"pad\": 0.9,"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 16 column"), "{}", err);
+        let err = Machine::from_json(&EXAMPLE.replace("\"limit\"", "\"limt\""))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `limt`"), "{}", err);
+        assert!(err.contains("line 15 column"), "{}", err);
+        let err = Machine::from_json(&EXAMPLE.replace("\"END\": 0.1", "\"pad\": 0.1"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("duplicate target pad"), "{}", err);
+        assert!(err.contains("line 16 column"), "{}", err);
+        let err = Machine::from_json(&EXAMPLE.replace("\"PaddingSent\"", "\"PaddingSend\""))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 16 column"), "{}", err);
+
+        // errors in the machine have a path
+        let err = Machine::from_json(&EXAMPLE.replace("\"END\"", "\"ned\""))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "states[1].transitions.PaddingSent: unknown state ned");
+        let err = Machine::from_json(&EXAMPLE.replace("\"END\"", "\"2\""))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "states[1].transitions.PaddingSent: unknown state 2");
+        let err = Machine::from_json(&EXAMPLE.replace("\"END\"", "\"1\""))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "states[1].transitions.PaddingSent: state 1 is a target more than once"
+        );
+        let err = Machine::from_json(&EXAMPLE.replace("\"start\"", "\"pad\""))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "states[1].name: duplicate name pad, also used by states[0]"
+        );
+        assert!(Machine::from_json(&EXAMPLE.replace("\"start\"", "\"END\"")).is_err());
+        assert!(Machine::from_json(&EXAMPLE.replace("\"version\": 1", "\"version\": 2")).is_err());
+        assert!(matches!(
+            Machine::from_json(&EXAMPLE.replace("\"version\": 1", "\"version\": 2")),
+            Err(MachineError::InvalidJson { location, .. }) if location == "version"
+        ));
+
+        // invalid machines are validation errors
+        assert!(matches!(
+            Machine::from_json(&EXAMPLE.replace("0.9", "0.95")),
+            Err(MachineError::InvalidProbabilities {
+                state: 1,
+                event: Event::PaddingSent,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn bounded_size() {
+        // small JSON, but transition matrices of more than MAXMACHINESIZE
+        let state = r#"{ "transitions": { "PaddingSent": { "END": 1.0 } } }"#;
+        let num_states = 6000;
+        let json = EXAMPLE.replace(
+            &EXAMPLE[EXAMPLE.find("\"states\"").unwrap()..EXAMPLE.len() - 1],
+            &format!("\"states\": [{}]\n", vec![state; num_states].join(",")),
+        );
+        assert!(json.len() < 1_000_000);
+        assert!(num_states * (num_states + 2) * 8 > MAXMACHINESIZE);
+        assert_eq!(
+            Machine::from_json(&json),
+            Err(MachineError::TooLarge {
+                max: MAXMACHINESIZE
+            })
+        );
+
+        // within the bound
+        let json = json.replace(&format!(",{}", state), "");
+        assert_eq!(Machine::from_json(&json).unwrap().states.len(), 1);
+    }
+
+    #[test]
+    fn schema() {
+        let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+        assert_eq!(schema["properties"]["version"]["const"], JSONVERSION);
+
+        // the schema covers the fields of the format
+        let m: serde_json::Value =
+            serde_json::from_str(&Machine::from_json(EXAMPLE).unwrap().to_json().unwrap()).unwrap();
+        for field in m.as_object().unwrap().keys() {
+            assert!(schema["properties"].get(field).is_some(), "{}", field);
+        }
+        for field in schema["required"].as_array().unwrap() {
+            assert!(m.get(field.as_str().unwrap()).is_some(), "{}", field);
+        }
+        let state = &schema["$defs"]["state"]["properties"];
+        for field in m["states"][1].as_object().unwrap().keys() {
+            assert!(state.get(field).is_some(), "{}", field);
+        }
+    }
+}
@@ -9,22 +9,59 @@
 //! If you want to use Maybenot, see [`framework`] for details. As a user, that
 //! is typically all that you need and the other modules can be ignored. Note
 //! that you create an existing [`machine::Machine`] (for use with the
-//! [`framework`]) using the [`core::str::FromStr`] trait.
+//! [`framework`]) using the [`core::str::FromStr`] trait, or from the
+//! human-readable format in [`json`] (with the default `json` feature).
 //!
 //! If you want to build machines for the [`framework`], take a look at all the
 //! modules. For top-down, start with [`machine`]. For bottom-down, start with
-//! [`dist`] and [`event`] before [`state`] and finally [`machine`].
+//! [`dist`] and [`event`] before [`state`] and finally [`machine`]. To review
+//! machines before use, see [`analysis`] and [`overhead`], to visualize them,
+//! [`dot`], and to compare them, [`equivalence`].
+//!
+//! The crate is `no_std` compatible (it requires `alloc`) when the default
+//! `std` feature is disabled. Without `std`, [`framework::Framework::new`] is
+//! unavailable: provide a source of randomness with
+//! [`framework::Framework::new_with_rng`] and your own clock by implementing
+//! [`time::Instant`].
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+/// Returns early with a boxed error created from a format string, like
+/// `simple_error::bail!` but usable without `std`.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(alloc::format!($($arg)*).into())
+    };
+}
+
+pub mod analysis;
+#[cfg(target_has_atomic = "64")]
+pub mod budget;
+pub mod compiled;
 pub mod constants;
 pub mod dist;
+pub mod dot;
+pub mod equivalence;
+pub mod error;
 pub mod event;
 pub mod framework;
+#[cfg(feature = "json")]
+pub mod json;
 pub mod machine;
+pub mod observer;
+pub mod overhead;
+pub mod rate;
 pub mod state;
+#[cfg(test)]
+mod test_support;
+pub mod time;
+pub mod window;
 
 #[cfg(test)]
 mod tests {
     #[test]
     fn constants_set() {
-        assert_eq!(crate::constants::VERSION, 1);
+        assert_eq!(crate::constants::VERSION, 4);
     }
 }
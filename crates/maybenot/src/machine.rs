@@ -1,21 +1,31 @@
 //! A machine determines when to inject and/or block outgoing traffic. Consists
 //! of zero or more [`State`] structs.
 
+use crate::compiled::CompiledMachine;
 use crate::constants::*;
-use crate::event::*;
+use crate::dist::{Dist, DistType};
+use crate::error::{MachineError, COUNTERFIELDS};
+use crate::event::Event;
+use crate::rate::RateLimit;
 use crate::state::*;
+use crate::window::Window;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use byteorder::ByteOrder;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::LittleEndian;
+use core::error::Error;
+use core::fmt;
+use core::str::FromStr;
+use core2::io::{Read, Write};
 use hex::{decode, encode};
 use libflate::zlib::{Decoder, Encoder};
 use ring::digest::{Context, SHA256};
 use serde::Deserialize;
 use serde::Serialize;
-use simple_error::{bail, map_err_with};
-use std::error::Error;
-use std::io::Read;
-use std::io::Write;
-use std::str::FromStr;
 
 /// A probabilistic state machine (Rabin automaton) consisting of zero or more
 /// [`State`] that determine when to inject and/or block outgoing traffic.
@@ -26,46 +36,85 @@ pub struct Machine {
     pub allowed_padding_bytes: u64,
     /// The maximum fraction of padding bytes to allow as actions.
     pub max_padding_frac: f64,
+    /// The window over which the fraction of padding bytes is computed.
+    pub padding_window: Window,
+    /// An optional limit on the rate of padding bytes, applied on top of all
+    /// other padding limits.
+    pub padding_rate_limit: Option<RateLimit>,
     /// The number of microseconds of blocking a machine is allowed to generate
     /// as actions before other limits apply.
     pub allowed_blocked_microsec: u64,
     /// The maximum fraction of blocking (microseconds) to allow as actions.
     pub max_blocking_frac: f64,
+    /// The window over which the fraction of blocking is computed. Cannot be
+    /// [`Window::Bytes`].
+    pub blocking_window: Window,
     /// The states that make up the machine.
     pub states: Vec<State>,
     pub include_small_packets: bool,
 }
 
 impl FromStr for Machine {
-    type Err = Box<dyn Error + Send + Sync>;
+    type Err = MachineError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // hex -> zlib -> vec
-        let compressed = map_err_with!(decode(s), "failed to decode hex")?;
+        let compressed = decode(s).map_err(|e| MachineError::InvalidHex(e.to_string()))?;
 
-        let mut decoder = map_err_with!(Decoder::new(&compressed[..]), "not in zlib format")?;
+        let mut decoder =
+            Decoder::new(&compressed[..]).map_err(|e| MachineError::InvalidZlib(e.to_string()))?;
         let mut buf = Vec::new();
-        decoder.read_to_end(&mut buf).unwrap();
-
+        read_at_most(&mut decoder, &mut buf, 2)?;
         if buf.len() < 2 {
-            bail!("cannot read version")
+            return Err(MachineError::Truncated("cannot read version".to_string()));
+        }
+        let version = u16::from_le_bytes([buf[0], buf[1]]);
+        if version == 0 || version > VERSION as u16 {
+            return Err(MachineError::UnsupportedVersion(version));
         }
 
-        let (version, payload) = buf.split_at(2);
+        // the header ends with the number of states, which bounds the size of
+        // the rest, so untrusted input cannot exhaust memory when decompressed
+        let header_len = 2 + header_size(version);
+        read_at_most(&mut decoder, &mut buf, header_len)?;
+        if buf.len() < header_len {
+            return Err(MachineError::Truncated(format!(
+                "not enough data for version {} machine",
+                version
+            )));
+        }
+        let num_states = match version {
+            4 => LittleEndian::read_u32(&buf[header_len - 4..header_len]) as usize,
+            _ => LittleEndian::read_u16(&buf[header_len - 2..header_len]) as usize,
+        };
+        let max = header_len
+            .saturating_add(max_states_size(num_states, version))
+            .min(MAXMACHINESIZE);
+        read_at_most(&mut decoder, &mut buf, max.saturating_add(1))?;
+        if buf.len() > max {
+            return Err(MachineError::TooLarge { max });
+        }
 
-        match u16::from_le_bytes(version.try_into().unwrap()) {
+        let payload = &buf[2..];
+        match version {
             1 => parse_v1_machine(payload),
-            v => bail!("unsupported version: {}", v),
+            2 => parse_v2_machine(payload),
+            3 => parse_v3_machine(payload),
+            _ => parse_v4_machine(payload),
         }
     }
 }
 
 impl Machine {
     /// Get a unique and deterministic string that represents the machine. The
-    /// name is generated by hashing the serialization of the machine.
+    /// name is generated by hashing the serialization of the machine in the
+    /// lowest version of the format that can represent it (see
+    /// [`Self::serialize()`]), so that the name does not change with new
+    /// versions of the format.
     pub fn name(&self) -> String {
+        let serialized = self.serialize();
         let mut context = Context::new(&SHA256);
-        context.update(self.serialize().as_bytes());
+        context.update(serialized.as_bytes());
         let d = context.finish();
         let s = encode(d);
         s[0..32].to_string()
@@ -73,49 +122,62 @@ impl Machine {
 
     /// Validates that the machine is in a valid state (machines that are
     /// mutated may get into an invalid state).
-    pub fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub fn validate(&self) -> Result<(), MachineError> {
         // sane limits
-        if self.max_padding_frac < 0.0 || self.max_padding_frac > 1.0 {
-            bail!(
-                "max_padding_frac has to be [0.0, 1.0], got {}",
-                self.max_padding_frac
-            )
+        if !(0.0..=1.0).contains(&self.max_padding_frac) {
+            return Err(MachineError::InvalidFraction {
+                field: "max_padding_frac",
+                value: self.max_padding_frac,
+            });
         }
-        if self.max_blocking_frac < 0.0 || self.max_blocking_frac > 1.0 {
-            bail!(
-                "max_blocking_frac has to be [0.0, 1.0], got {}",
-                self.max_blocking_frac
-            )
+        if !(0.0..=1.0).contains(&self.max_blocking_frac) {
+            return Err(MachineError::InvalidFraction {
+                field: "max_blocking_frac",
+                value: self.max_blocking_frac,
+            });
         }
 
+        self.padding_window.validate_padding()?;
+        if let Some(limit) = &self.padding_rate_limit {
+            limit.validate()?;
+        }
+        self.blocking_window.validate_blocking()?;
+
         // sane number of states
         if self.states.is_empty() {
-            bail!("a machine must have at least one state")
+            return Err(MachineError::NoStates);
         }
         if self.states.len() > STATEMAX {
-            bail!(
-                "too many states, max is {}, found {}",
-                STATEMAX,
-                self.states.len()
-            )
+            return Err(MachineError::TooManyStates {
+                max: STATEMAX,
+                found: self.states.len(),
+            });
         }
 
         // check each state
         for (index, state) in self.states.iter().enumerate() {
             // validate transitions
-            for next in &state.next_state {
-                if next.1.len() != self.states.len() + 2 {
-                    bail!(
+            for (event, next) in &state.next_state {
+                let invalid = |reason: String| MachineError::InvalidProbabilities {
+                    state: index,
+                    event: *event,
+                    reason,
+                };
+                if next.len() != self.states.len() + 2 {
+                    return Err(invalid(format!(
                         "found too small next_state vector, expected {}, got {}",
                         self.states.len() + 2,
-                        next.1.len()
-                    )
+                        next.len()
+                    )));
                 }
 
                 let mut p_total = 0.0;
-                for p in next.1 {
+                for p in next {
                     if !(&0.0..=&1.0).contains(&p) {
-                        bail!("found probability {}, has to be [0.0, 1.0]", &p)
+                        return Err(invalid(format!(
+                            "found probability {}, has to be [0.0, 1.0]",
+                            &p
+                        )));
                     }
                     p_total += p;
                 }
@@ -126,49 +188,141 @@ impl Machine {
                 // transition has implications in the framework, i.e., involving
                 // limits on padding sent in he state)
                 if p_total <= 0.0 || p_total > 1.0 {
-                    bail!(
-                        "found invalid total probability vector {} at index {}, must be (0.0, 1.0]",
-                        p_total,
-                        index
-                    )
+                    return Err(invalid(format!(
+                        "found invalid total probability vector {}, must be (0.0, 1.0]",
+                        p_total
+                    )));
                 }
             }
 
+            if state.action_is_block && state.action_is_timer {
+                return Err(MachineError::InvalidState {
+                    state: index,
+                    reason: "has both action_is_block and action_is_timer set".to_string(),
+                });
+            }
+
             // validate distribution parameters
-            state.action.validate()?;
-            state.limit.validate()?;
-            state.timeout.validate()?;
+            let invalid_dist = |field: &'static str| move |e: MachineError| e.in_dist(index, field);
+            state.action.validate().map_err(invalid_dist("action"))?;
+            state.limit.validate().map_err(invalid_dist("limit"))?;
+            state.timeout.validate().map_err(invalid_dist("timeout"))?;
+
+            // validate counter updates
+            for (counter, update) in state.counters.iter().enumerate() {
+                if let Some(value) = update.as_ref().and_then(|u| u.value) {
+                    let field = COUNTERFIELDS[counter];
+                    if value.dist == DistType::None {
+                        return Err(MachineError::InvalidDist {
+                            state: index,
+                            field,
+                            reason: "value dist of type None, leave the value unset for 1"
+                                .to_string(),
+                        });
+                    }
+                    value.validate().map_err(invalid_dist(field))?;
+                }
+            }
         }
 
         Ok(())
     }
 
-    /// Serialize the machine into a byte vector.
+    /// Serialize the machine into a byte vector, in the lowest version of the
+    /// format that can represent it, so that older versions of the framework
+    /// can parse it. Use [`Self::serialize_version()`] with [`VERSION`] for
+    /// the latest (sparse) version of the format.
     pub fn serialize(&self) -> String {
-        let mut wtr = vec![];
+        (1..VERSION as u16)
+            .find_map(|version| self.serialize_version(version).ok())
+            .unwrap_or_else(|| self.encode(VERSION as u16))
+    }
 
-        wtr.write_u16::<LittleEndian>(VERSION as u16).unwrap();
-        wtr.write_u64::<LittleEndian>(self.allowed_padding_bytes)
-            .unwrap();
-        wtr.write_f64::<LittleEndian>(self.max_padding_frac)
-            .unwrap();
-        wtr.write_u64::<LittleEndian>(self.allowed_blocked_microsec)
-            .unwrap();
-        wtr.write_f64::<LittleEndian>(self.max_blocking_frac)
-            .unwrap();
+    /// Serialize the machine in the given version of the format, e.g., for
+    /// older versions of the framework. Versions 1 to 3 store the full
+    /// transition matrix of every state, so their size grows quadratically
+    /// with the number of states (and are at most [`MAXMACHINESIZE`] bytes),
+    /// while version 4 only stores transitions with a probability and
+    /// distributions that are set. Returns an error if the version is not
+    /// supported or cannot represent the machine.
+    pub fn serialize_version(&self, version: u16) -> Result<String, MachineError> {
+        if version == 0 || version > VERSION as u16 {
+            return Err(MachineError::UnsupportedVersion(version));
+        }
+        let unrepresentable = |reason: String| MachineError::Unrepresentable { version, reason };
+        if version < 4 && self.states.len() > u16::MAX as usize {
+            return Err(unrepresentable(format!(
+                "at most {} states, found {}",
+                u16::MAX,
+                self.states.len()
+            )));
+        }
+        if version < 4
+            && (2 + header_size(version))
+                .saturating_add(max_states_size(self.states.len(), version))
+                > MAXMACHINESIZE
+        {
+            return Err(unrepresentable(format!(
+                "at most {} bytes of dense states, too many states",
+                MAXMACHINESIZE
+            )));
+        }
+        if version < 3
+            && (self.padding_window != Window::Lifetime
+                || self.blocking_window != Window::Lifetime
+                || self.padding_rate_limit.is_some())
+        {
+            return Err(unrepresentable(
+                "windows or rate limits are not supported".to_string(),
+            ));
+        }
+        if version < 2 {
+            for (index, state) in self.states.iter().enumerate() {
+                if state.action_is_timer || state.counters.iter().any(|c| c.is_some()) {
+                    return Err(unrepresentable(format!(
+                        "timers or counters are not supported, used by state at index {}",
+                        index
+                    )));
+                }
+                if let Some(event) = state
+                    .next_state
+                    .keys()
+                    .find(|e| !Event::v1_iterator().any(|v1| v1 == *e))
+                {
+                    return Err(unrepresentable(format!(
+                        "event {} is not supported, used by state at index {}",
+                        event, index
+                    )));
+                }
+            }
+        }
+        Ok(self.encode(version))
+    }
 
-        if self.include_small_packets {
-            wtr.write_u8(1).unwrap();
-        } else {
-            wtr.write_u8(0).unwrap();
+    fn encode(&self, version: u16) -> String {
+        let mut wtr = vec![];
+
+        wtr.extend_from_slice(&version.to_le_bytes());
+        wtr.extend_from_slice(&self.allowed_padding_bytes.to_le_bytes());
+        wtr.extend_from_slice(&self.max_padding_frac.to_le_bytes());
+        wtr.extend_from_slice(&self.allowed_blocked_microsec.to_le_bytes());
+        wtr.extend_from_slice(&self.max_blocking_frac.to_le_bytes());
+        wtr.push(self.include_small_packets as u8);
+        if version >= 3 {
+            wtr.extend_from_slice(&self.padding_window.serialize());
+            wtr.extend_from_slice(&self.blocking_window.serialize());
+            wtr.extend_from_slice(&RateLimit::serialize(&self.padding_rate_limit));
         }
 
         let num_states = self.states.len();
-        wtr.write_u16::<LittleEndian>(num_states as u16).unwrap();
+        if version >= 4 {
+            wtr.extend_from_slice(&(num_states as u32).to_le_bytes());
+        } else {
+            wtr.extend_from_slice(&(num_states as u16).to_le_bytes());
+        }
 
-        for i in 0..self.states.len() {
-            wtr.write_all(&self.states[i].serialize(num_states))
-                .unwrap();
+        for state in &self.states {
+            wtr.extend_from_slice(&state.serialize_version(num_states, version));
         }
 
         let mut encoder = Encoder::new(Vec::new()).unwrap();
@@ -180,10 +334,582 @@ impl Machine {
     }
 }
 
-fn parse_v1_machine(buf: &[u8]) -> Result<Machine, Box<dyn Error + Send + Sync>> {
-    // note that we already read 2 bytes of version in fn parse_machine()
-    if buf.len() < 4 * 8 + 1 + 2 {
-        bail!("not enough data for version 1 machine")
+/// A set of machines, as used by a [`Framework`](crate::framework::Framework).
+/// Implemented for anything that is [`AsRef<[Machine]>`] (such as
+/// `Vec<Machine>` or `&[Machine]`), which is validated when creating a
+/// framework, and for [`ValidatedMachines`], which is not. The trait is
+/// sealed: it cannot be implemented outside of this crate, so that only
+/// [`ValidatedMachines`] can skip validation.
+pub trait MachineSet: sealed::Sealed {
+    /// Returns the machines in the set.
+    fn as_slice(&self) -> &[Machine];
+
+    /// True if the machines are known to be valid.
+    fn is_validated(&self) -> bool {
+        false
+    }
+
+    /// Returns the machines compiled for sampling transitions, in the same
+    /// order, if already compiled. Otherwise the framework compiles them.
+    fn compiled(&self) -> Option<&[CompiledMachine]> {
+        None
+    }
+}
+
+mod sealed {
+    use super::{Machine, ValidatedMachines};
+
+    pub trait Sealed {}
+
+    impl<T: AsRef<[Machine]>> Sealed for T {}
+    impl<M: AsRef<[Machine]>> Sealed for ValidatedMachines<M> {}
+    impl<M: AsRef<[Machine]>> Sealed for &ValidatedMachines<M> {}
+}
+
+impl<T: AsRef<[Machine]>> MachineSet for T {
+    fn as_slice(&self) -> &[Machine] {
+        self.as_ref()
+    }
+}
+
+/// Machines that have been validated and compiled (see [`CompiledMachine`])
+/// once, so that creating a [`Framework`](crate::framework::Framework) with
+/// them skips both.
+/// Useful when creating many frameworks with the same machines: share them,
+/// e.g., as `&ValidatedMachines<Vec<Machine>>` or
+/// `ValidatedMachines<Arc<[Machine]>>`.
+///
+/// The machines cannot be modified once validated, so for machines from
+/// untrusted input, the only way to skip validation is to validate them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatedMachines<M> {
+    machines: M,
+    compiled: Vec<CompiledMachine>,
+}
+
+impl<M: AsRef<[Machine]>> ValidatedMachines<M> {
+    /// Validates all machines, see [`Machine::validate()`], and compiles them.
+    pub fn new(machines: M) -> Result<Self, MachineError> {
+        for m in machines.as_ref() {
+            m.validate()?;
+        }
+        let compiled = machines
+            .as_ref()
+            .iter()
+            .map(CompiledMachine::new)
+            .collect::<Result<_, _>>()?;
+        Ok(ValidatedMachines { machines, compiled })
+    }
+
+    /// Returns the validated machines.
+    pub fn into_inner(self) -> M {
+        self.machines
+    }
+}
+
+impl<M: AsRef<[Machine]>> MachineSet for ValidatedMachines<M> {
+    fn as_slice(&self) -> &[Machine] {
+        self.machines.as_ref()
+    }
+
+    fn is_validated(&self) -> bool {
+        true
+    }
+
+    fn compiled(&self) -> Option<&[CompiledMachine]> {
+        Some(&self.compiled)
+    }
+}
+
+impl<M: AsRef<[Machine]>> MachineSet for &ValidatedMachines<M> {
+    fn as_slice(&self) -> &[Machine] {
+        self.machines.as_ref()
+    }
+
+    fn is_validated(&self) -> bool {
+        true
+    }
+
+    fn compiled(&self) -> Option<&[CompiledMachine]> {
+        Some(&self.compiled)
+    }
+}
+
+/// A builder of a [`Machine`] with named states, so transitions are declared
+/// by name instead of by index into the probability vectors of each
+/// [`State`]. States are indexed in the order they are added, so the first
+/// state is the start state of the machine. For example:
+///
+/// ```
+/// use maybenot::dist::{Dist, DistType};
+/// use maybenot::event::Event;
+/// use maybenot::machine::{MachineBuilder, StateBuilder};
+///
+/// let uniform = |min, max| Dist {
+///     dist: DistType::Uniform,
+///     param1: min,
+///     param2: max,
+///     start: 0.0,
+///     max: 0.0,
+/// };
+/// let m = MachineBuilder::new()
+///     .padding_budget(1000, 0.5)
+///     .state(
+///         "start",
+///         StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+///     )
+///     .state(
+///         "pad",
+///         StateBuilder::new()
+///             .pad(uniform(100.0, 1000.0))
+///             .timeout(uniform(0.0, 1000.0))
+///             .limit(uniform(1.0, 10.0))
+///             .transition(Event::PaddingSent, "pad", 0.9)
+///             .end(Event::PaddingSent, 0.1),
+///     )
+///     .build()
+///     .unwrap();
+/// assert_eq!(m.states.len(), 2);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineBuilder {
+    allowed_padding_bytes: u64,
+    max_padding_frac: f64,
+    padding_window: Window,
+    padding_rate_limit: Option<RateLimit>,
+    allowed_blocked_microsec: u64,
+    max_blocking_frac: f64,
+    blocking_window: Window,
+    include_small_packets: bool,
+    // unnamed states are only from JSON
+    pub(crate) states: Vec<(Option<String>, StateBuilder)>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineBuilder {
+    /// Create a builder of a machine without states, padding, or blocking.
+    pub fn new() -> Self {
+        MachineBuilder {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            include_small_packets: false,
+            states: vec![],
+        }
+    }
+
+    /// Sets the padding bytes allowed before the max padding fraction applies,
+    /// and the fraction, see [`Machine::allowed_padding_bytes`] and
+    /// [`Machine::max_padding_frac`].
+    pub fn padding_budget(mut self, allowed_bytes: u64, max_frac: f64) -> Self {
+        self.allowed_padding_bytes = allowed_bytes;
+        self.max_padding_frac = max_frac;
+        self
+    }
+
+    /// Sets the blocking allowed before the max blocking fraction applies, and
+    /// the fraction, see [`Machine::allowed_blocked_microsec`] and
+    /// [`Machine::max_blocking_frac`].
+    pub fn blocking_budget(mut self, allowed_microsec: u64, max_frac: f64) -> Self {
+        self.allowed_blocked_microsec = allowed_microsec;
+        self.max_blocking_frac = max_frac;
+        self
+    }
+
+    /// Sets [`Machine::padding_window`].
+    pub fn padding_window(mut self, window: Window) -> Self {
+        self.padding_window = window;
+        self
+    }
+
+    /// Sets [`Machine::padding_rate_limit`].
+    pub fn padding_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.padding_rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Sets [`Machine::blocking_window`].
+    pub fn blocking_window(mut self, window: Window) -> Self {
+        self.blocking_window = window;
+        self
+    }
+
+    /// Sets [`Machine::include_small_packets`].
+    pub fn include_small_packets(mut self, include: bool) -> Self {
+        self.include_small_packets = include;
+        self
+    }
+
+    /// Adds a state with the given name, which transitions refer to.
+    pub fn state(mut self, name: impl Into<String>, state: StateBuilder) -> Self {
+        self.states.push((Some(name.into()), state));
+        self
+    }
+
+    /// Build and validate the machine (see [`Machine::validate()`]). Returns
+    /// an error for duplicate state names, transitions to unknown states or
+    /// to the same state more than once on an event, and unknown counters.
+    /// As for parsed machines, the size of the transition matrices of the
+    /// states is bounded by [`MAXMACHINESIZE`].
+    pub fn build(self) -> Result<Machine, MachineError> {
+        self.build_with(|index, e| {
+            let name = self.states[index].0.as_deref().unwrap_or_default();
+            match e {
+                StateError::DuplicateName(other) => MachineError::InvalidState {
+                    state: index,
+                    reason: format!(
+                        "duplicate name {}, also used by state at index {}",
+                        name, other
+                    ),
+                },
+                StateError::UnknownTarget(event, target) => MachineError::InvalidState {
+                    state: index,
+                    reason: format!("{}: transition on {} to unknown {}", name, event, target),
+                },
+                StateError::DuplicateTarget(event, target) => MachineError::InvalidProbabilities {
+                    state: index,
+                    event,
+                    reason: format!("{}: {} is a target more than once", name, target),
+                },
+                StateError::UnknownCounter(counter) => MachineError::InvalidState {
+                    state: index,
+                    reason: format!(
+                        "{}: no counter {}, there are {}",
+                        name, counter, NUMCOUNTERS
+                    ),
+                },
+            }
+        })
+    }
+
+    // Build and validate the machine, with the error for a problem with the
+    // state at the index. Shared with Machine::from_json(), which reports
+    // errors by their path in the JSON.
+    pub(crate) fn build_with(
+        &self,
+        state_error: impl Fn(usize, StateError) -> MachineError,
+    ) -> Result<Machine, MachineError> {
+        let num_states = self.states.len();
+        let mut names: BTreeMap<&str, usize> = BTreeMap::new();
+        for (index, (name, _)) in self.states.iter().enumerate() {
+            let Some(name) = name else {
+                continue;
+            };
+            if let Some(other) = names.insert(name, index) {
+                return Err(state_error(index, StateError::DuplicateName(other)));
+            }
+        }
+
+        let mut resolved = Vec::with_capacity(num_states);
+        for (index, (_, state)) in self.states.iter().enumerate() {
+            let mut transitions: Vec<(Event, Vec<(usize, f64)>)> = vec![];
+            let mut seen = BTreeSet::new();
+            for (event, target, p) in &state.transitions {
+                let next = match target {
+                    Target::State(name) => names.get(name.as_str()).copied(),
+                    Target::Index(i) => Some(*i).filter(|i| *i < num_states),
+                    Target::End => Some(STATEEND),
+                    Target::Cancel => Some(STATECANCEL),
+                }
+                .ok_or_else(|| {
+                    state_error(index, StateError::UnknownTarget(*event, target.clone()))
+                })?;
+                if !seen.insert((*event, next)) {
+                    return Err(state_error(
+                        index,
+                        StateError::DuplicateTarget(*event, target.clone()),
+                    ));
+                }
+                match transitions.iter_mut().find(|(e, _)| e == event) {
+                    Some((_, targets)) => targets.push((next, *p)),
+                    None => transitions.push((*event, vec![(next, *p)])),
+                }
+            }
+            resolved.push(transitions);
+        }
+
+        // the dense transition matrices are bounded before any is allocated
+        let num_matrices: usize = resolved.iter().map(Vec::len).sum();
+        let mut budget = MAXMACHINESIZE;
+        take_budget(
+            &mut budget,
+            num_matrices.saturating_mul((num_states + 2).saturating_mul(8)),
+        )?;
+
+        let mut states = Vec::with_capacity(num_states);
+        for (index, ((_, state), transitions)) in self.states.iter().zip(resolved).enumerate() {
+            let mut counters = [None; NUMCOUNTERS];
+            for (counter, update) in &state.counters {
+                let Some(c) = counters.get_mut(*counter) else {
+                    return Err(state_error(index, StateError::UnknownCounter(*counter)));
+                };
+                *c = Some(*update);
+            }
+
+            let mut s = State::new(transitions, num_states);
+            s.timeout = state.timeout;
+            s.action = state.action;
+            s.action_is_block = state.action_is_block;
+            s.action_is_timer = state.action_is_timer;
+            s.bypass = state.bypass;
+            s.replace = state.replace;
+            s.limit = state.limit;
+            s.limit_includes_nonpadding = state.limit_includes_nonpadding;
+            s.counters = counters;
+            states.push(s);
+        }
+
+        let machine = Machine {
+            allowed_padding_bytes: self.allowed_padding_bytes,
+            max_padding_frac: self.max_padding_frac,
+            padding_window: self.padding_window,
+            padding_rate_limit: self.padding_rate_limit,
+            allowed_blocked_microsec: self.allowed_blocked_microsec,
+            max_blocking_frac: self.max_blocking_frac,
+            blocking_window: self.blocking_window,
+            states,
+            include_small_packets: self.include_small_packets,
+        };
+        machine.validate()?;
+        Ok(machine)
+    }
+}
+
+// A problem with a state of a MachineBuilder, see MachineBuilder::build_with().
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum StateError {
+    // the name is also used by the state at the index
+    DuplicateName(usize),
+    // a transition on the event to a state that does not exist
+    UnknownTarget(Event, Target),
+    // a transition on the event to the same target more than once
+    DuplicateTarget(Event, Target),
+    // an update of a counter that does not exist
+    UnknownCounter(usize),
+}
+
+// The target of a transition of a StateBuilder.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Target {
+    State(String),
+    // only from JSON, where names cannot be numbers
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    Index(usize),
+    End,
+    Cancel,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::State(name) => write!(f, "state {}", name),
+            Target::Index(index) => write!(f, "state {}", index),
+            Target::End => write!(f, "END"),
+            Target::Cancel => write!(f, "CANCEL"),
+        }
+    }
+}
+
+/// A builder of a [`State`] as part of a [`MachineBuilder`], with transitions
+/// to other states by name. By default, the state pads with the MTU, without
+/// timeout or limit, see [`State::new()`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateBuilder {
+    pub(crate) timeout: Dist,
+    pub(crate) action: Dist,
+    pub(crate) action_is_block: bool,
+    pub(crate) action_is_timer: bool,
+    pub(crate) bypass: bool,
+    pub(crate) replace: bool,
+    pub(crate) limit: Dist,
+    pub(crate) limit_includes_nonpadding: bool,
+    pub(crate) counters: Vec<(usize, CounterUpdate)>,
+    pub(crate) transitions: Vec<(Event, Target, f64)>,
+}
+
+impl Default for StateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateBuilder {
+    /// Create a builder of a state without transitions.
+    pub fn new() -> Self {
+        StateBuilder {
+            timeout: Dist::new(),
+            action: Dist::new(),
+            action_is_block: false,
+            action_is_timer: false,
+            bypass: false,
+            replace: false,
+            limit: Dist::new(),
+            limit_includes_nonpadding: false,
+            counters: vec![],
+            transitions: vec![],
+        }
+    }
+
+    /// Sets the action to inject padding of a size sampled from the
+    /// distribution.
+    pub fn pad(mut self, size: Dist) -> Self {
+        self.action = size;
+        self.action_is_block = false;
+        self.action_is_timer = false;
+        self
+    }
+
+    /// Sets the action to block for a duration sampled from the distribution.
+    pub fn block(mut self, duration: Dist) -> Self {
+        self.action = duration;
+        self.action_is_block = true;
+        self.action_is_timer = false;
+        self
+    }
+
+    /// Sets the action to update the timer of the machine with a duration
+    /// sampled from the distribution, see [`State::action_is_timer`].
+    pub fn timer(mut self, duration: Dist) -> Self {
+        self.action = duration;
+        self.action_is_block = false;
+        self.action_is_timer = true;
+        self
+    }
+
+    /// Sets [`State::timeout`].
+    pub fn timeout(mut self, timeout: Dist) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets [`State::limit`].
+    pub fn limit(mut self, limit: Dist) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets [`State::limit_includes_nonpadding`].
+    pub fn limit_includes_nonpadding(mut self, include: bool) -> Self {
+        self.limit_includes_nonpadding = include;
+        self
+    }
+
+    /// Sets [`State::bypass`].
+    pub fn bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// Sets [`State::replace`].
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
+        self
+    }
+
+    /// Sets the update of the counter with the given index, see
+    /// [`State::counters`].
+    pub fn counter(mut self, counter: usize, update: CounterUpdate) -> Self {
+        self.counters.push((counter, update));
+        self
+    }
+
+    /// Adds a transition on the event to the state with the given name, with
+    /// the given probability.
+    pub fn transition(mut self, event: Event, to: impl Into<String>, probability: f64) -> Self {
+        self.transitions
+            .push((event, Target::State(to.into()), probability));
+        self
+    }
+
+    /// Adds a transition on the event to [`STATEEND`], with the given
+    /// probability.
+    pub fn end(mut self, event: Event, probability: f64) -> Self {
+        self.transitions.push((event, Target::End, probability));
+        self
+    }
+
+    /// Adds a transition on the event to [`STATECANCEL`], with the given
+    /// probability.
+    pub fn cancel(mut self, event: Event, probability: f64) -> Self {
+        self.transitions.push((event, Target::Cancel, probability));
+        self
+    }
+}
+
+fn parse_v1_machine(buf: &[u8]) -> Result<Machine, MachineError> {
+    parse_machine_with(buf, 1)
+}
+
+fn parse_v2_machine(buf: &[u8]) -> Result<Machine, MachineError> {
+    parse_machine_with(buf, 2)
+}
+
+fn parse_v3_machine(buf: &[u8]) -> Result<Machine, MachineError> {
+    parse_machine_with(buf, 3)
+}
+
+fn parse_v4_machine(buf: &[u8]) -> Result<Machine, MachineError> {
+    parse_machine_with(buf, 4)
+}
+
+// Read from the reader until it is exhausted or the buffer has the given
+// length, never more.
+fn read_at_most<R: Read>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), MachineError> {
+    let mut chunk = [0u8; 4096];
+    while buf.len() < len {
+        let n = chunk.len().min(len - buf.len());
+        match reader.read(&mut chunk[..n]) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) => return Err(MachineError::InvalidZlib(e.to_string())),
+        }
+    }
+    Ok(())
+}
+
+// The size of a serialized machine of the given version before its states,
+// without the version: 4 8-byte values, a flag, since version 3 windows and a
+// rate limit, and the number of states (a u32 since version 4).
+fn header_size(version: u16) -> usize {
+    let budgets_len = if version >= 3 {
+        2 * SERIALIZEDWINDOWSIZE + SERIALIZEDRATELIMITSIZE
+    } else {
+        0
+    };
+    let num_states_len = if version >= 4 { 4 } else { 2 };
+    4 * 8 + 1 + budgets_len + num_states_len
+}
+
+// The maximum size of the given number of serialized states, saturating.
+fn max_states_size(num_states: usize, version: u16) -> usize {
+    let state_size = match version {
+        4 => max_sparse_state_size(num_states),
+        _ => serialized_state_size(num_states, version),
+    };
+    num_states.saturating_mul(state_size)
+}
+
+fn parse_machine_with(buf: &[u8], version: u16) -> Result<Machine, MachineError> {
+    // note that we already read 2 bytes of version in fn parse_machine(), and
+    // since version 3 there are windows and a rate limit after the flag
+    if buf.len() < header_size(version) {
+        return Err(MachineError::Truncated(format!(
+            "not enough data for version {} machine",
+            version
+        )));
     }
 
     let mut r: usize = 0;
@@ -201,34 +927,80 @@ fn parse_v1_machine(buf: &[u8]) -> Result<Machine, Box<dyn Error + Send + Sync>>
     let include_small_packets = buf[r] == 1;
     r += 1;
 
-    // 2-byte num of states
-    let num_states: usize = LittleEndian::read_u16(&buf[r..r + 2]) as usize;
-    r += 2;
+    // 2 windows and a rate limit, lifetime and none before version 3
+    let (padding_window, blocking_window, padding_rate_limit) = if version >= 3 {
+        let invalid =
+            |e: Box<dyn Error + Send + Sync>| MachineError::InvalidEncoding(e.to_string());
+        let padding = Window::parse(&buf[r..]).map_err(invalid)?;
+        r += SERIALIZEDWINDOWSIZE;
+        let blocking = Window::parse(&buf[r..]).map_err(invalid)?;
+        r += SERIALIZEDWINDOWSIZE;
+        let rate = RateLimit::parse(&buf[r..]).map_err(invalid)?;
+        r += SERIALIZEDRATELIMITSIZE;
+        (padding, blocking, rate)
+    } else {
+        (Window::Lifetime, Window::Lifetime, None)
+    };
 
-    // each state has 3 distributions + 4 flags + next_state matrix
-    let expected_state_len: usize =
-        3 * SERIALIZEDDISTSIZE + 4 + (num_states + 2) * 8 * Event::iterator().len();
-    if buf[r..].len() != expected_state_len * num_states {
-        bail!(format!(
-            "expected {} bytes for {} states, but got {} bytes",
-            expected_state_len * num_states,
-            num_states,
-            buf[r..].len()
-        ))
-    }
+    // num of states
+    let num_states: usize = if version >= 4 {
+        LittleEndian::read_u32(&buf[r..r + 4]) as usize
+    } else {
+        LittleEndian::read_u16(&buf[r..r + 2]) as usize
+    };
+    r += if version >= 4 { 4 } else { 2 };
 
     let mut states = vec![];
-    for _ in 0..num_states {
-        let s = parse_state(buf[r..r + expected_state_len].to_vec(), num_states).unwrap();
-        r += expected_state_len;
-        states.push(s);
+    if version >= 4 {
+        // sparse states of variable size, with a budget for the size of the
+        // states and their dense transition matrices, since a small input
+        // can claim many states
+        let mut budget = MAXMACHINESIZE;
+        take_budget(
+            &mut budget,
+            num_states.saturating_mul(core::mem::size_of::<State>()),
+        )?;
+        states.reserve_exact(num_states);
+        for _ in 0..num_states {
+            let (s, n) = parse_sparse_state(&buf[r..], num_states, &mut budget)?;
+            r += n;
+            states.push(s);
+        }
+        if r != buf.len() {
+            return Err(MachineError::Truncated(format!(
+                "{} bytes left after {} states",
+                buf.len() - r,
+                num_states
+            )));
+        }
+    } else {
+        // each state has 3 distributions + flags + next_state matrix, and
+        // since version 2 also counter updates
+        let expected_state_len: usize = serialized_state_size(num_states, version);
+        if buf[r..].len() != max_states_size(num_states, version) {
+            return Err(MachineError::Truncated(format!(
+                "expected {} bytes for {} states, but got {} bytes",
+                max_states_size(num_states, version),
+                num_states,
+                buf[r..].len()
+            )));
+        }
+
+        for _ in 0..num_states {
+            let s = parse_state_version(&buf[r..r + expected_state_len], num_states, version)?;
+            r += expected_state_len;
+            states.push(s);
+        }
     }
 
     let m = Machine {
         allowed_padding_bytes,
         max_padding_frac,
+        padding_window,
+        padding_rate_limit,
         allowed_blocked_microsec,
         max_blocking_frac,
+        blocking_window,
         include_small_packets,
         states,
     };
@@ -237,8 +1009,10 @@ fn parse_v1_machine(buf: &[u8]) -> Result<Machine, Box<dyn Error + Send + Sync>>
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::dist::*;
+    use crate::event::*;
     use crate::machine::*;
     use std::collections::HashMap;
 
@@ -313,8 +1087,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000,
             max_padding_frac: 0.123,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 2000,
             max_blocking_frac: 0.456,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -358,6 +1135,9 @@ mod tests {
         assert_eq!(m.states[0].timeout.start, 0.0);
 
         assert_eq!(m.states[0].next_state.len(), 0);
+
+        // the name is of the version 1 serialization, as before version 2
+        assert_eq!(m.name(), "47200747f5745aca0aee03ab2901441c");
     }
 
     #[test]
@@ -385,8 +1165,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 1000,
             max_padding_frac: 0.123,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 0,
             max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
             states: vec![s0],
             include_small_packets: false,
         };
@@ -427,8 +1210,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 100000,
             max_blocking_frac: 0.9999,
+            blocking_window: Window::Lifetime,
             states: vec![s0],
             include_small_packets: true,
         };
@@ -488,8 +1274,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 100000,
             max_blocking_frac: 0.9999,
+            blocking_window: Window::Lifetime,
             states: vec![s0, s1],
             include_small_packets: true,
         };
@@ -534,8 +1323,11 @@ mod tests {
         let m = Machine {
             allowed_padding_bytes: 0,
             max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
             allowed_blocked_microsec: 100000,
             max_blocking_frac: 0.9999,
+            blocking_window: Window::Lifetime,
             states,
             include_small_packets: true,
         };
@@ -548,4 +1340,754 @@ mod tests {
         let m_hardcoded = Machine::from_str(&hardcoded).unwrap();
         assert_eq!(m, m_hardcoded);
     }
+
+    #[test]
+    fn parse_v2_machine_counters() {
+        // make a 2-state machine with counters, serialize, and compare
+        let num_states = 2;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 1.0);
+        t.insert(Event::NonPaddingSent, e);
+        let mut s0 = State::new(t, num_states);
+        s0.counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Increment,
+            value: None,
+        });
+
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::CounterZero, e);
+        let mut s1 = State::new(t, num_states);
+        s1.counters[0] = Some(CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(Dist {
+                dist: DistType::Poisson,
+                param1: 2.0,
+                param2: 0.0,
+                start: 1.0,
+                max: 10.0,
+            }),
+        });
+        s1.counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Decrement,
+            value: None,
+        });
+        s1.action_is_timer = true;
+        s1.replace = true;
+
+        let m = Machine {
+            allowed_padding_bytes: 1000,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: false,
+        };
+        let s = m.serialize();
+        let m_parsed = Machine::from_str(&s).unwrap();
+        assert_eq!(m, m_parsed);
+        let s = m.serialize_version(2).unwrap();
+        assert_eq!(m, Machine::from_str(&s).unwrap());
+        // but not representable in version 1
+        assert!(m.serialize_version(1).is_err());
+    }
+
+    #[test]
+    fn sparse_serialization() {
+        // a machine with many states but few transitions per state, as in
+        // most large machines
+        let num_states = 200;
+        let states = (0..num_states)
+            .map(|i| {
+                let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+                let mut e: HashMap<usize, f64> = HashMap::new();
+                e.insert((i + 1) % num_states, 0.5);
+                e.insert(STATEEND, 0.1);
+                t.insert(Event::PaddingSent, e);
+                let mut e: HashMap<usize, f64> = HashMap::new();
+                e.insert(0, 1.0);
+                t.insert(Event::TimerEnd, e);
+                let mut s = State::new(t, num_states);
+                s.timeout = Dist {
+                    dist: DistType::Uniform,
+                    param1: 10.0,
+                    param2: 100.0,
+                    start: 0.0,
+                    max: 0.0,
+                };
+                s
+            })
+            .collect();
+        let m = Machine {
+            allowed_padding_bytes: 1000,
+            max_padding_frac: 0.5,
+            padding_window: Window::Bytes(1000),
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states,
+            include_small_packets: false,
+        };
+
+        let v3 = m.serialize_version(3).unwrap();
+        let v4 = m.serialize_version(4).unwrap();
+        // the sparse version is opt-in
+        assert_eq!(v3, m.serialize());
+        assert_eq!(m, Machine::from_str(&v3).unwrap());
+        assert_eq!(m, Machine::from_str(&v4).unwrap());
+        // even compressed, the full transition matrices are much larger
+        assert!(v4.len() * 5 < v3.len());
+    }
+
+    #[test]
+    fn serialize_versions() {
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 0.5);
+        e.insert(STATECANCEL, 0.5);
+        t.insert(Event::NonPaddingSent, e);
+        let mut s0 = State::new(t, 1);
+        s0.action = Dist {
+            dist: DistType::Uniform,
+            param1: 100.0,
+            param2: 1000.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let mut m = Machine {
+            allowed_padding_bytes: 1000,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0],
+            include_small_packets: false,
+        };
+
+        for version in 1..=VERSION as u16 {
+            let s = m.serialize_version(version).unwrap();
+            assert_eq!(m, Machine::from_str(&s).unwrap());
+        }
+        assert!(m.serialize_version(0).is_err());
+        assert!(m.serialize_version(VERSION as u16 + 1).is_err());
+
+        // serialized in the lowest version that can represent the machine
+        let version = |s: &str| {
+            let compressed = decode(s).unwrap();
+            let mut decoder = Decoder::new(&compressed[..]).unwrap();
+            let mut buf = [0; 2];
+            decoder.read_exact(&mut buf).unwrap();
+            u16::from_le_bytes(buf)
+        };
+        assert_eq!(version(&m.serialize()), 1);
+        assert_eq!(m.serialize(), m.serialize_version(1).unwrap());
+        assert_eq!(Machine::from_str(&m.serialize()).unwrap(), m);
+
+        // version 1 has no timer events, and versions before 3 no windows
+        m.states[0]
+            .next_state
+            .insert(Event::TimerBegin, vec![1.0, 0.0, 0.0]);
+        assert!(m.serialize_version(1).is_err());
+        assert!(m.serialize_version(2).is_ok());
+        assert_eq!(version(&m.serialize()), 2);
+        let name = m.name();
+        m.padding_window = Window::Microsec(1000);
+        assert!(m.serialize_version(2).is_err());
+        assert!(m.serialize_version(3).is_ok());
+        assert_eq!(version(&m.serialize()), 3);
+        assert_eq!(Machine::from_str(&m.serialize()).unwrap(), m);
+        assert_ne!(m.name(), name);
+
+        // dense states of many states are too large to parse
+        let n = 2000;
+        m.states = vec![State::new(HashMap::<Event, HashMap<usize, f64>>::new(), n); n];
+        assert!(matches!(
+            m.serialize_version(3),
+            Err(MachineError::Unrepresentable { version: 3, .. })
+        ));
+        assert_eq!(version(&m.serialize()), 4);
+        assert_eq!(Machine::from_str(&m.serialize()).unwrap(), m);
+    }
+
+    #[test]
+    fn errors() {
+        let compress = |buf: &[u8]| {
+            let mut encoder = Encoder::new(Vec::new()).unwrap();
+            encoder.write_all(buf).unwrap();
+            encode(encoder.finish().into_result().unwrap())
+        };
+
+        // parsing
+        assert!(matches!(
+            Machine::from_str("zz"),
+            Err(MachineError::InvalidHex(_))
+        ));
+        assert!(matches!(
+            Machine::from_str("00ff"),
+            Err(MachineError::InvalidZlib(_))
+        ));
+        assert!(matches!(
+            Machine::from_str(&compress(&[1])),
+            Err(MachineError::Truncated(_))
+        ));
+        assert_eq!(
+            Machine::from_str(&compress(&[9, 0])),
+            Err(MachineError::UnsupportedVersion(9))
+        );
+        assert!(matches!(
+            Machine::from_str(&compress(&[4, 0, 1, 2, 3])),
+            Err(MachineError::Truncated(_))
+        ));
+
+        // validation
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![State::new(t, 1)],
+            include_small_packets: false,
+        };
+        assert!(m.validate().is_ok());
+
+        m.states[0].limit = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        assert!(matches!(
+            m.validate(),
+            Err(MachineError::InvalidDist {
+                state: 0,
+                field: "limit",
+                ..
+            })
+        ));
+        assert!(matches!(
+            Machine::from_str(&m.serialize()),
+            Err(MachineError::InvalidDist { state: 0, .. })
+        ));
+        m.states[0].limit = Dist::new();
+
+        m.states[0]
+            .next_state
+            .insert(Event::PaddingSent, vec![0.5, 0.5, 0.5]);
+        assert!(matches!(
+            m.validate(),
+            Err(MachineError::InvalidProbabilities {
+                state: 0,
+                event: Event::PaddingSent,
+                ..
+            })
+        ));
+        m.states[0]
+            .next_state
+            .insert(Event::PaddingSent, vec![1.0, 0.0, 0.0]);
+
+        m.max_padding_frac = 1.5;
+        assert_eq!(
+            m.validate(),
+            Err(MachineError::InvalidFraction {
+                field: "max_padding_frac",
+                value: 1.5
+            })
+        );
+        m.max_padding_frac = 0.0;
+
+        let states = m.states.clone();
+        m.states = vec![];
+        assert_eq!(m.validate(), Err(MachineError::NoStates));
+        m.states = states;
+
+        // a machine that cannot be serialized in an old version
+        m.padding_window = Window::Bytes(1000);
+        assert!(matches!(
+            m.serialize_version(2),
+            Err(MachineError::Unrepresentable { version: 2, .. })
+        ));
+        assert_eq!(
+            m.serialize_version(5),
+            Err(MachineError::UnsupportedVersion(5))
+        );
+    }
+
+    // A machine using every feature of the format, in every version of the
+    // format that can represent it, decompressed.
+    fn decompressed_machines() -> Vec<Vec<u8>> {
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(1, 0.5);
+        e.insert(STATECANCEL, 0.2);
+        e.insert(STATEEND, 0.3);
+        t.insert(Event::NonPaddingSent, e);
+        let mut s0 = State::new(t, 2);
+        s0.action = Dist {
+            dist: DistType::Uniform,
+            param1: 10.0,
+            param2: 100.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::PaddingSent, e);
+        let mut s1 = State::new(t, 2);
+        s1.timeout = Dist {
+            dist: DistType::Poisson,
+            param1: 1.5,
+            param2: 0.0,
+            start: 2.0,
+            max: 100.0,
+        };
+        s1.limit = Dist {
+            dist: DistType::Geometric,
+            param1: 0.5,
+            param2: 0.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let mut m = Machine {
+            allowed_padding_bytes: 1000,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 100,
+            max_blocking_frac: 0.1,
+            blocking_window: Window::Lifetime,
+            states: vec![s0, s1],
+            include_small_packets: true,
+        };
+        let mut serialized = vec![m.serialize_version(1).unwrap()];
+
+        m.states[1].counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(Dist {
+                dist: DistType::Uniform,
+                param1: 1.0,
+                param2: 5.0,
+                start: 0.0,
+                max: 0.0,
+            }),
+        });
+        m.states[1].action_is_timer = true;
+        serialized.push(m.serialize_version(2).unwrap());
+
+        m.padding_window = Window::Bytes(10_000);
+        m.blocking_window = Window::Microsec(1_000_000);
+        m.padding_rate_limit = Some(RateLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 1500,
+        });
+        serialized.push(m.serialize_version(3).unwrap());
+        serialized.push(m.serialize_version(4).unwrap());
+
+        serialized
+            .iter()
+            .map(|s| {
+                let compressed = decode(s).unwrap();
+                let mut buf = Vec::new();
+                Decoder::new(&compressed[..])
+                    .unwrap()
+                    .read_to_end(&mut buf)
+                    .unwrap();
+                buf
+            })
+            .collect()
+    }
+
+    fn compress(buf: &[u8]) -> String {
+        let mut encoder = Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(buf).unwrap();
+        encode(encoder.finish().into_result().unwrap())
+    }
+
+    // Any machine that parses must be valid and usable by the framework.
+    fn check_parsed(s: &str) {
+        if let Ok(m) = Machine::from_str(s) {
+            assert!(m.validate().is_ok());
+            assert!(CompiledMachine::new(&m).is_ok());
+            // all values can be represented in JSON
+            #[cfg(feature = "json")]
+            assert!(!m.to_json().unwrap().contains("null"));
+        }
+    }
+
+    #[test]
+    fn from_str_random_input() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..200);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // random hex, random zlib, and random machines of each version
+            check_parsed(&encode(&bytes));
+            check_parsed(&compress(&bytes));
+            let mut versioned = vec![rng.gen_range(0..=VERSION + 1), 0];
+            versioned.extend_from_slice(&bytes);
+            check_parsed(&compress(&versioned));
+        }
+        for s in ["", "0", "zz", "789c", "789c00", "789cffffffff"] {
+            check_parsed(s);
+        }
+    }
+
+    #[test]
+    fn from_str_mutated_machines() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for machine in decompressed_machines() {
+            assert!(Machine::from_str(&compress(&machine)).is_ok());
+            for _ in 0..1000 {
+                let mut buf = machine.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let i = rng.gen_range(0..buf.len());
+                    match rng.gen_range(0..5) {
+                        0 => buf[i] ^= 1 << rng.gen_range(0..8),
+                        1 => buf[i] = rng.gen(),
+                        2 => buf.truncate(i),
+                        3 => buf.insert(i, rng.gen()),
+                        _ => {
+                            // a random f64, e.g., a probability or parameter
+                            let v: f64 = match rng.gen_range(0..4) {
+                                0 => f64::NAN,
+                                1 => f64::INFINITY,
+                                2 => -rng.gen::<f64>(),
+                                _ => rng.gen::<f64>() * 1e300,
+                            };
+                            let end = buf.len().min(i + 8);
+                            buf[i..end].copy_from_slice(&v.to_le_bytes()[..end - i]);
+                        }
+                    }
+                    if buf.is_empty() {
+                        break;
+                    }
+                }
+                check_parsed(&compress(&buf));
+            }
+        }
+    }
+
+    #[test]
+    fn from_str_bounded_size() {
+        let machines = decompressed_machines();
+
+        // trailing data after the declared states, even if highly compressible
+        for machine in &machines {
+            let mut buf = machine.clone();
+            buf.push(0);
+            assert!(Machine::from_str(&compress(&buf)).is_err());
+            buf.resize(buf.len() + 1_000_000, 0);
+            assert!(matches!(
+                Machine::from_str(&compress(&buf)),
+                Err(MachineError::TooLarge { .. })
+            ));
+        }
+
+        // the maximum number of states, but no data for them
+        let v3 = &machines[2];
+        let mut buf = v3[..v3.len() - 2 * serialized_state_size(2, 3)].to_vec();
+        let n = buf.len();
+        buf[n - 2..].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            Machine::from_str(&compress(&buf)),
+            Err(MachineError::Truncated(_))
+        ));
+        let v4 = &machines[3];
+        let header = 2 + header_size(4);
+        let mut buf = v4[..header].to_vec();
+        buf[header - 4..].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(
+            Machine::from_str(&compress(&buf)),
+            Err(MachineError::Truncated(_))
+        ));
+
+        // more sparse states than fit in memory, before parsing any
+        let num_states = MAXMACHINESIZE / core::mem::size_of::<State>() + 1;
+        for n in [num_states as u32, u32::MAX] {
+            buf[header - 4..].copy_from_slice(&n.to_le_bytes());
+            assert_eq!(
+                Machine::from_str(&compress(&buf)),
+                Err(MachineError::TooLarge {
+                    max: MAXMACHINESIZE
+                })
+            );
+        }
+        // also with tiny states without transitions: no distributions,
+        // flags, counters, or events
+        let mut tiny = buf.clone();
+        tiny[header - 4..].copy_from_slice(&(num_states as u32).to_le_bytes());
+        tiny.resize(header + num_states * 9, 0);
+        assert!(compress(&tiny).len() < 100_000);
+        assert_eq!(
+            Machine::from_str(&compress(&tiny)),
+            Err(MachineError::TooLarge {
+                max: MAXMACHINESIZE
+            })
+        );
+
+        // many tiny sparse states with transitions, which would be large with
+        // dense transition matrices
+        let num_states: u32 = 200_000;
+        buf[header - 4..].copy_from_slice(&num_states.to_le_bytes());
+        for _ in 0..num_states {
+            // no distributions, flags, or counters, and an event without any
+            // transitions
+            buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        }
+        assert_eq!(
+            Machine::from_str(&compress(&buf)),
+            Err(MachineError::TooLarge {
+                max: MAXMACHINESIZE
+            })
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let size = Dist {
+            dist: DistType::Uniform,
+            param1: 100.0,
+            param2: 1000.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let update = CounterUpdate {
+            operation: CounterOperation::Increment,
+            value: None,
+        };
+        let m = MachineBuilder::new()
+            .padding_budget(1000, 0.5)
+            .blocking_window(Window::Microsec(1000))
+            .state(
+                "start",
+                StateBuilder::new()
+                    .transition(Event::NonPaddingSent, "pad", 0.5)
+                    .cancel(Event::NonPaddingSent, 0.5),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .pad(size)
+                    .counter(1, update)
+                    .transition(Event::PaddingSent, "pad", 0.9)
+                    .end(Event::PaddingSent, 0.1)
+                    .transition(Event::CounterZero, "start", 1.0),
+            )
+            .build()
+            .unwrap();
+
+        // the same machine by hand
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        t.insert(Event::NonPaddingSent, [(1, 0.5), (STATECANCEL, 0.5)].into());
+        let s0 = State::new(t, 2);
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        t.insert(Event::PaddingSent, [(1, 0.9), (STATEEND, 0.1)].into());
+        t.insert(Event::CounterZero, [(0, 1.0)].into());
+        let mut s1 = State::new(t, 2);
+        s1.action = size;
+        s1.counters[1] = Some(update);
+        let expected = Machine {
+            allowed_padding_bytes: 1000,
+            max_padding_frac: 0.5,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Microsec(1000),
+            states: vec![s0, s1],
+            include_small_packets: false,
+        };
+        assert_eq!(m, expected);
+
+        // errors
+        let pad = || StateBuilder::new().transition(Event::PaddingSent, "pad", 1.0);
+        assert!(matches!(
+            MachineBuilder::new()
+                .state("pad", pad())
+                .state("pad", pad())
+                .build(),
+            Err(MachineError::InvalidState { state: 1, .. })
+        ));
+        assert_eq!(
+            MachineBuilder::new()
+                .state("pad", pad().transition(Event::PaddingRecv, "ned", 1.0))
+                .build()
+                .unwrap_err()
+                .to_string(),
+            "state at index 0: pad: transition on PaddingRecv to unknown state ned"
+        );
+        assert!(matches!(
+            MachineBuilder::new()
+                .state("pad", pad().transition(Event::PaddingSent, "pad", 0.5))
+                .build(),
+            Err(MachineError::InvalidProbabilities {
+                state: 0,
+                event: Event::PaddingSent,
+                ..
+            })
+        ));
+        assert!(matches!(
+            MachineBuilder::new()
+                .state("pad", pad().counter(NUMCOUNTERS, update))
+                .build(),
+            Err(MachineError::InvalidState { state: 0, .. })
+        ));
+        assert!(matches!(
+            MachineBuilder::new()
+                .state("pad", pad().end(Event::PaddingSent, 0.5))
+                .build(),
+            Err(MachineError::InvalidProbabilities { state: 0, .. })
+        ));
+        assert_eq!(MachineBuilder::new().build(), Err(MachineError::NoStates));
+    }
+
+    #[test]
+    fn validate_counters() {
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::CounterZero, e);
+        let mut s0 = State::new(t, 1);
+        s0.counters[0] = Some(CounterUpdate {
+            operation: CounterOperation::Decrement,
+            value: Some(Dist {
+                dist: DistType::Uniform,
+                param1: 1.0,
+                param2: 2.0,
+                start: 0.0,
+                max: 0.0,
+            }),
+        });
+        let mut m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0],
+            include_small_packets: false,
+        };
+        assert!(m.validate().is_ok());
+
+        // invalid value dist
+        m.states[0].counters[0].as_mut().unwrap().value = Some(Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        });
+        assert!(m.validate().is_err());
+
+        // a value dist of type None would always be sampled as u64::MAX
+        m.states[0].counters[0].as_mut().unwrap().value = Some(Dist::new());
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    fn validate_timer() {
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(0, 1.0);
+        t.insert(Event::TimerEnd, e);
+        let mut s0 = State::new(t, 1);
+        s0.action_is_timer = true;
+        let mut m = Machine {
+            allowed_padding_bytes: 0,
+            max_padding_frac: 0.0,
+            padding_window: Window::Lifetime,
+            padding_rate_limit: None,
+            allowed_blocked_microsec: 0,
+            max_blocking_frac: 0.0,
+            blocking_window: Window::Lifetime,
+            states: vec![s0],
+            include_small_packets: false,
+        };
+        assert!(m.validate().is_ok());
+
+        // an action cannot both update the timer and block
+        m.states[0].action_is_block = true;
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    fn budget_serialization() {
+        let mut m =
+            Machine::from_str("789cedca2101000000c230e85f1a8387009f9e351d051503ca0003").unwrap();
+        assert_eq!(m.padding_window, Window::Lifetime);
+        assert_eq!(m.blocking_window, Window::Lifetime);
+
+        m.padding_window = Window::Bytes(100_000);
+        m.blocking_window = Window::Microsec(1_000_000);
+        m.padding_rate_limit = Some(RateLimit {
+            bytes_per_sec: 10_000,
+            burst_bytes: 15_000,
+        });
+        let parsed = Machine::from_str(&m.serialize()).unwrap();
+        assert_eq!(parsed, m);
+
+        // blocking is measured in time, not bytes
+        m.blocking_window = Window::Bytes(100);
+        assert!(m.validate().is_err());
+        m.blocking_window = Window::Lifetime;
+        m.padding_window = Window::Microsec(0);
+        assert!(m.validate().is_err());
+        m.padding_window = Window::Lifetime;
+        m.padding_rate_limit = Some(RateLimit {
+            bytes_per_sec: 10_000,
+            burst_bytes: 0,
+        });
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    fn validated_machines() {
+        let m =
+            Machine::from_str("789cedca2101000000c230e85f1a8387009f9e351d051503ca0003").unwrap();
+        let mut invalid = m.clone();
+        invalid.max_padding_frac = 2.0;
+        assert!(ValidatedMachines::new(vec![m.clone(), invalid.clone()]).is_err());
+
+        let now = std::time::Instant::now();
+        let validated = ValidatedMachines::new(vec![m.clone(), m.clone()]).unwrap();
+        let f = crate::framework::Framework::new(&validated, 0.0, 0.0, 1420, now).unwrap();
+        assert_eq!(f.num_machines(), 2);
+        let shared: ValidatedMachines<std::sync::Arc<[Machine]>> =
+            ValidatedMachines::new(vec![m].into()).unwrap();
+        assert!(crate::framework::Framework::new(shared.clone(), 0.0, 0.0, 1420, now).is_ok());
+        assert_eq!(shared.into_inner().len(), 1);
+
+        // the framework trusts validated machines, while unvalidated ones are
+        // always checked
+        let skipped = ValidatedMachines {
+            compiled: vec![CompiledMachine::new(&invalid).unwrap()],
+            machines: vec![invalid.clone()],
+        };
+        assert!(crate::framework::Framework::new(&skipped, 0.0, 0.0, 1420, now).is_ok());
+        assert!(crate::framework::Framework::new(vec![invalid], 0.0, 0.0, 1420, now).is_err());
+    }
 }
@@ -0,0 +1,75 @@
+//! Observing the decisions made by a [`Framework`](crate::framework::Framework),
+//! for tracing, debugging, and audit logs.
+//!
+//! An [`Observer`] is given to a framework with
+//! [`Framework::with_observer()`](crate::framework::Framework::with_observer)
+//! and is called synchronously while events are triggered. All methods have
+//! empty default implementations, so an observer only implements what it
+//! needs. Frameworks without an observer use [`NoObserver`], whose calls are
+//! compiled away.
+
+use crate::event::Event;
+use crate::framework::{Action, MachineId};
+
+/// A limit that prevented a machine from scheduling an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActionLimit {
+    /// The limit on the number of actions in the current state.
+    State,
+    /// The maximum padding fraction of the machine.
+    MachinePadding,
+    /// The maximum padding fraction of the framework.
+    FrameworkPadding,
+    /// The maximum blocking fraction of the machine.
+    MachineBlocking,
+    /// The maximum blocking fraction of the framework.
+    FrameworkBlocking,
+    /// The padding rate limit of the machine.
+    MachinePaddingRate,
+    /// The padding rate limit of the framework.
+    FrameworkPaddingRate,
+    /// The maximum padding fraction of the shared budget.
+    SharedPadding,
+    /// The maximum padding bytes of the shared budget.
+    SharedPaddingBytes,
+    /// The maximum blocking fraction of the shared budget.
+    SharedBlocking,
+}
+
+/// Receives callbacks on the decisions made by a framework. States are
+/// identified by index, where [`STATEEND`](crate::constants::STATEEND) and
+/// [`STATECANCEL`](crate::constants::STATECANCEL) are the pseudo-states.
+pub trait Observer {
+    /// A machine took a transition on an event from one state to another (or
+    /// the same) state.
+    fn on_transition(&mut self, _machine: MachineId, _event: Event, _from: usize, _to: usize) {}
+
+    /// A limit on the number of actions was sampled for the state a machine
+    /// entered.
+    fn on_limit_sampled(&mut self, _machine: MachineId, _state: usize, _limit: u64) {}
+
+    /// A machine scheduled an action, with all values sampled. This is called
+    /// for every scheduled action, including those the framework does not
+    /// return in the end: a later event in the same batch may replace the
+    /// action, and an action is retracted if the event that caused it also
+    /// used up the limit of the state (see [`Self::on_limit_reached()`]).
+    /// Always take the actions returned by the framework.
+    fn on_action(&mut self, _action: &Action) {}
+
+    /// A machine would have scheduled an action in its state, but a limit
+    /// prevented it.
+    fn on_action_suppressed(&mut self, _machine: MachineId, _state: usize, _limit: ActionLimit) {}
+
+    /// A machine used up the limit on the number of actions in its state,
+    /// triggering [`Event::LimitReached`].
+    fn on_limit_reached(&mut self, _machine: MachineId, _state: usize) {}
+
+    /// A machine updated one of its counters.
+    fn on_counter_update(&mut self, _machine: MachineId, _counter: usize, _old: u64, _new: u64) {}
+}
+
+/// An [`Observer`] that does nothing, the default for a framework.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoObserver;
+
+impl Observer for NoObserver {}
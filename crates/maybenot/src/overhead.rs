@@ -0,0 +1,745 @@
+//! Analytical estimates of the overhead of machines before deployment, see
+//! [`estimate()`].
+//!
+//! A machine and its traffic are modeled as a continuous-time Markov chain.
+//! Non-padding packets are sent and received, and padding packets received,
+//! as Poisson processes with the rates of a [`TrafficModel`]. Distributions
+//! are replaced by their means (see [`Dist::mean()`]): timeouts and blocking
+//! durations are exponentially distributed with the mean (timeouts of less
+//! than a microsecond take a microsecond), padding packets have the mean size,
+//! and each decrement of a limit reaches it with a probability of one over
+//! the mean limit. Estimates are therefore exact for machines with
+//! exponentially distributed timeouts and durations (e.g.,
+//! [`DistType::Weibull`] with a shape of 1.0), constant padding sizes, and no
+//! limits, and approximate otherwise.
+//!
+//! Not modeled are the padding and blocking limits of machines and the
+//! framework, blocking delaying packets, blocking actions overlapping, actions
+//! still pending when the machine reaches [`STATEEND`], and small packets
+//! (see [`Machine::include_small_packets`]). Machines with timers or counters
+//! are not supported.
+
+use crate::constants::*;
+use crate::dist::{Dist, DistType};
+use crate::event::Event;
+use crate::machine::Machine;
+use crate::state::padding_size;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt;
+use core::time::Duration;
+
+/// The traffic of a connection assumed by [`estimate()`]: packets sent and
+/// received as Poisson processes, with rates in packets per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrafficModel {
+    /// The rate of sent non-padding packets.
+    pub nonpadding_sent: f64,
+    /// The rate of received non-padding packets.
+    pub nonpadding_recv: f64,
+    /// The rate of received padding packets.
+    pub padding_recv: f64,
+    /// The MTU, the size of padding packets without a size distribution.
+    pub mtu: u16,
+}
+
+/// The result of [`estimate()`]: expected values for a machine that starts
+/// at the beginning of the duration, and the expected time for it to end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// The expected number of padding packets sent.
+    pub padding_packets: f64,
+    /// The expected number of padding bytes sent.
+    pub padding_bytes: f64,
+    /// The expected time spent blocking in microseconds, counted in full when
+    /// blocking begins.
+    pub blocking_microsec: f64,
+    /// The expected number of non-padding packets sent.
+    pub nonpadding_packets: f64,
+    /// The probability that the machine has reached [`STATEEND`] at the end
+    /// of the duration.
+    pub end_probability: f64,
+    /// The expected time in microseconds until the machine reaches
+    /// [`STATEEND`], regardless of the duration. Infinite if the machine may
+    /// never reach it.
+    pub microsec_to_end: f64,
+}
+
+impl Estimate {
+    /// Returns the expected number of padding packets per sent non-padding
+    /// packet.
+    pub fn padding_per_nonpadding(&self) -> f64 {
+        self.padding_packets / self.nonpadding_packets
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "padding packets: {:.2}", self.padding_packets)?;
+        writeln!(f, "padding bytes: {:.2}", self.padding_bytes)?;
+        writeln!(f, "blocking microsec: {:.2}", self.blocking_microsec)?;
+        writeln!(f, "nonpadding packets: {:.2}", self.nonpadding_packets)?;
+        writeln!(f, "end probability: {:.4}", self.end_probability)?;
+        write!(f, "microsec to end: {:.2}", self.microsec_to_end)
+    }
+}
+
+/// Estimate the overhead of a valid machine with the traffic over the
+/// duration, see the [module documentation](crate::overhead) for the model.
+/// Returns an error if the machine is not valid, uses timers or counters, if
+/// a rate is negative or not finite, if the chain has more than
+/// [`MAXESTIMATENODES`] nodes, or if the duration needs more than
+/// [`MAXESTIMATESTEPS`] steps of the chain.
+///
+/// The estimate takes time linear in the duration times the largest rate of
+/// the chain (e.g., one over the smallest mean timeout) times the number of
+/// transitions, and the expected time to end cubic time in the number of
+/// states of the chain (a few per state of the machine).
+pub fn estimate(
+    machine: &Machine,
+    traffic: &TrafficModel,
+    duration: Duration,
+) -> Result<Estimate, Box<dyn Error + Send + Sync>> {
+    machine.validate()?;
+    for (index, state) in machine.states.iter().enumerate() {
+        if state.action_is_timer {
+            bail!("state at index {}: timers are not supported", index)
+        }
+        if state.counters.iter().any(Option::is_some) {
+            bail!("state at index {}: counters are not supported", index)
+        }
+    }
+    for (name, rate) in [
+        ("nonpadding_sent", traffic.nonpadding_sent),
+        ("nonpadding_recv", traffic.nonpadding_recv),
+        ("padding_recv", traffic.padding_recv),
+    ] {
+        if !rate.is_finite() || rate < 0.0 {
+            bail!(
+                "{} has to be a finite rate of at least 0.0, got {}",
+                name,
+                rate
+            )
+        }
+    }
+
+    let chain = Chain::new(machine, traffic)?;
+    let (totals, end_probability) = chain.transient(duration.as_secs_f64() * 1_000_000.0)?;
+    Ok(Estimate {
+        padding_packets: totals[PADDINGPACKETS],
+        padding_bytes: totals[PADDINGBYTES],
+        blocking_microsec: totals[BLOCKING],
+        nonpadding_packets: totals[NONPADDINGPACKETS],
+        end_probability,
+        microsec_to_end: chain.time_to_end(),
+    })
+}
+
+// the indices of the rewards of nodes
+const PADDINGPACKETS: usize = 0;
+const PADDINGBYTES: usize = 1;
+const BLOCKING: usize = 2;
+const NONPADDINGPACKETS: usize = 3;
+
+// A state of the chain: a state of the machine and what the framework keeps
+// track of for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Node {
+    state: usize,
+    // if the action of the state is scheduled
+    pending: bool,
+    // if the limit of the state has been reached
+    exhausted: bool,
+    // the state that began blocking, while blocking
+    blocking: Option<usize>,
+}
+
+// The means of the distributions of a state, as used in the chain.
+struct Means {
+    // one over the mean timeout
+    timeout_rate: f64,
+    size: f64,
+    block: f64,
+    // one over the mean blocking duration
+    block_rate: f64,
+    // None without a limit, otherwise the probability that the limit is zero
+    // when entering the state and that a decrement reaches the limit
+    limit: Option<(f64, f64)>,
+}
+
+impl Means {
+    fn new(state: &crate::state::State, mtu: u16) -> Self {
+        let rate = |mean: f64| 1.0 / mean.max(1.0);
+        let timeout = state.timeout.mean().min(MAXSAMPLEDTIMEOUT);
+        let block = state.action.mean().min(MAXSAMPLEDBLOCK);
+        let size = match state.action.dist {
+            DistType::None => mtu as u64,
+            _ => padding_size(state.action.mean(), mtu as u64),
+        };
+        Means {
+            timeout_rate: rate(timeout),
+            size: size as f64,
+            block,
+            block_rate: rate(block),
+            limit: limit(&state.limit),
+        }
+    }
+}
+
+fn limit(limit: &Dist) -> Option<(f64, f64)> {
+    if limit.dist == DistType::None {
+        return None;
+    }
+    let mean = limit.mean();
+    Some(((1.0 - mean).clamp(0.0, 1.0), 1.0 / mean.max(1.0)))
+}
+
+// Transitions from a node, to another node or STATEEND (None), with rates per
+// microsecond.
+type Outcomes = Vec<(Option<Node>, f64)>;
+
+struct Builder<'a> {
+    machine: &'a Machine,
+    means: Vec<Means>,
+}
+
+impl Builder<'_> {
+    // The outcomes of an event that occurs with the rate in the node.
+    fn transition(&self, node: Node, event: Event, rate: f64, out: &mut Outcomes) {
+        let num_states = self.machine.states.len();
+        let state = &self.machine.states[node.state];
+        // if the limit is decremented if the state is unchanged
+        let decrement = match event {
+            Event::PaddingSent | Event::BlockingBegin => true,
+            Event::NonPaddingSent => state.limit_includes_nonpadding,
+            _ => false,
+        };
+
+        let mut nop = 1.0;
+        for (next, p) in state
+            .next_state
+            .get(&event)
+            .into_iter()
+            .flat_map(|p| p.iter().enumerate())
+            .filter(|(_, p)| **p > 0.0)
+        {
+            nop -= p;
+            match next {
+                next if next == node.state => {
+                    // a new action is scheduled, unless the limit is reached
+                    let node = Node {
+                        pending: !node.exhausted,
+                        ..node
+                    };
+                    self.unchanged(node, decrement, rate * p, out);
+                }
+                next if next < num_states => self.enter(next, node.blocking, rate * p, out),
+                next if next == num_states => {
+                    let node = Node {
+                        pending: false,
+                        ..node
+                    };
+                    self.unchanged(node, decrement, rate * p, out);
+                }
+                _ => out.push((None, rate * p)),
+            }
+        }
+        if nop > 0.0 {
+            self.unchanged(node, decrement, rate * nop, out);
+        }
+    }
+
+    // The outcomes of an event that leaves the state unchanged, decrementing
+    // the limit if set.
+    fn unchanged(&self, node: Node, decrement: bool, rate: f64, out: &mut Outcomes) {
+        let Some((_, reached)) = self.means[node.state].limit.filter(|_| decrement) else {
+            out.push((Some(node), rate));
+            return;
+        };
+        // once reached, every decrement triggers LimitReached again
+        let reached = if node.exhausted { 1.0 } else { reached };
+        if reached < 1.0 {
+            out.push((Some(node), rate * (1.0 - reached)));
+        }
+        let node = Node {
+            pending: false,
+            exhausted: true,
+            ..node
+        };
+        self.transition(node, Event::LimitReached, rate * reached, out);
+    }
+
+    // The outcomes of entering a state, sampling its limit.
+    fn enter(&self, state: usize, blocking: Option<usize>, rate: f64, out: &mut Outcomes) {
+        let zero = self.means[state].limit.map_or(0.0, |(zero, _)| zero);
+        let node = Node {
+            state,
+            pending: true,
+            exhausted: false,
+            blocking,
+        };
+        if zero < 1.0 {
+            out.push((Some(node), rate * (1.0 - zero)));
+        }
+        if zero > 0.0 {
+            let node = Node {
+                pending: false,
+                exhausted: true,
+                ..node
+            };
+            out.push((Some(node), rate * zero));
+        }
+    }
+}
+
+// The chain of the reachable nodes, starting with node 0. STATEEND is the
+// node after the last. There are at most MAXESTIMATENODES nodes.
+struct Chain {
+    // the transitions of each node to other nodes, with rates per microsecond
+    transitions: Vec<Vec<(usize, f64)>>,
+    // the expected rewards per microsecond in each node and STATEEND
+    rewards: Vec<[f64; 4]>,
+}
+
+impl Chain {
+    fn new(
+        machine: &Machine,
+        traffic: &TrafficModel,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let builder = Builder {
+            machine,
+            means: machine
+                .states
+                .iter()
+                .map(|s| Means::new(s, traffic.mtu))
+                .collect(),
+        };
+        let events = [
+            (Event::NonPaddingSent, traffic.nonpadding_sent / 1_000_000.0),
+            (Event::NonPaddingRecv, traffic.nonpadding_recv / 1_000_000.0),
+            (Event::PaddingRecv, traffic.padding_recv / 1_000_000.0),
+        ];
+
+        let start = Node {
+            state: 0,
+            pending: false,
+            exhausted: false,
+            blocking: None,
+        };
+        let mut nodes = vec![start];
+        let mut indices = BTreeMap::from([(start, 0)]);
+        let mut transitions = vec![];
+        let mut rewards = vec![];
+        while let Some(&node) = nodes.get(transitions.len()) {
+            if nodes.len() > MAXESTIMATENODES {
+                bail!("the chain has more than {} nodes", MAXESTIMATENODES)
+            }
+            let means = &builder.means[node.state];
+            let mut out = vec![];
+            let mut reward = [0.0; 4];
+            reward[NONPADDINGPACKETS] = events[0].1;
+
+            for (event, rate) in events {
+                if rate > 0.0 {
+                    builder.transition(node, event, rate, &mut out);
+                }
+            }
+            if node.pending {
+                let rate = means.timeout_rate;
+                let fired = Node {
+                    pending: false,
+                    ..node
+                };
+                if machine.states[node.state].action_is_block {
+                    reward[BLOCKING] = rate * means.block;
+                    let fired = Node {
+                        blocking: Some(node.state),
+                        ..fired
+                    };
+                    builder.transition(fired, Event::BlockingBegin, rate, &mut out);
+                } else {
+                    reward[PADDINGPACKETS] = rate;
+                    reward[PADDINGBYTES] = rate * means.size;
+                    builder.transition(fired, Event::PaddingSent, rate, &mut out);
+                }
+            }
+            if let Some(blocking) = node.blocking {
+                let ended = Node {
+                    blocking: None,
+                    ..node
+                };
+                let rate = builder.means[blocking].block_rate;
+                builder.transition(ended, Event::BlockingEnd, rate, &mut out);
+            }
+
+            let index = transitions.len();
+            let mut edges = vec![];
+            for (next, rate) in out {
+                let next = match next {
+                    Some(next) => *indices.entry(next).or_insert_with(|| {
+                        nodes.push(next);
+                        nodes.len() - 1
+                    }),
+                    None => usize::MAX,
+                };
+                if next != index && rate > 0.0 {
+                    edges.push((next, rate));
+                }
+            }
+            transitions.push(edges);
+            rewards.push(reward);
+        }
+
+        // STATEEND is the node after the last, where only non-padding packets
+        // are sent
+        let end = nodes.len();
+        let mut reward = [0.0; 4];
+        reward[NONPADDINGPACKETS] = events[0].1;
+        rewards.push(reward);
+        for edges in &mut transitions {
+            for (next, _) in edges.iter_mut() {
+                if *next == usize::MAX {
+                    *next = end;
+                }
+            }
+        }
+        Ok(Chain {
+            transitions,
+            rewards,
+        })
+    }
+
+    fn exit_rates(&self) -> Vec<f64> {
+        self.transitions
+            .iter()
+            .map(|edges| edges.iter().map(|(_, rate)| rate).sum())
+            .collect()
+    }
+
+    // The expected total rewards over the duration in microseconds and the
+    // probability of being in STATEEND at the end, by uniformization: the
+    // chain as a discrete-time chain stepped by a Poisson process with the
+    // largest exit rate. Returns an error if more than MAXESTIMATESTEPS steps
+    // are needed.
+    fn transient(&self, duration: f64) -> Result<([f64; 4], f64), Box<dyn Error + Send + Sync>> {
+        let n = self.transitions.len();
+        let exit = self.exit_rates();
+        let uniform = exit.iter().fold(0.0, |a: f64, b| a.max(*b));
+        if uniform <= 0.0 || duration <= 0.0 {
+            return Ok((self.rewards[0].map(|r| r * duration.max(0.0)), 0.0));
+        }
+
+        let mean = uniform * duration;
+        let steps = libm::ceil(mean + 10.0 * libm::sqrt(mean) + 10.0);
+        if steps > MAXESTIMATESTEPS as f64 {
+            bail!(
+                "the duration needs {} steps, more than {}",
+                steps,
+                MAXESTIMATESTEPS
+            )
+        }
+        let steps = steps as u64;
+        let mut totals = [0.0; 4];
+        let mut end_probability = 0.0;
+        let mut probabilities = vec![0.0; n + 1];
+        probabilities[0] = 1.0;
+        let mut cdf = 0.0;
+        for k in 0..=steps {
+            // the probability of exactly k steps, and of more than k steps
+            let k = k as f64;
+            let pmf = libm::exp(-mean + k * libm::log(mean) - libm::lgamma(k + 1.0));
+            cdf += pmf;
+            let more = (1.0 - cdf).max(0.0);
+            for (p, rewards) in probabilities.iter().zip(&self.rewards) {
+                for (total, reward) in totals.iter_mut().zip(rewards) {
+                    *total += more * p * reward / uniform;
+                }
+            }
+            end_probability += pmf * probabilities[n];
+
+            let mut next = vec![0.0; n + 1];
+            next[n] = probabilities[n];
+            for (i, edges) in self.transitions.iter().enumerate() {
+                let p = probabilities[i];
+                next[i] += p * (1.0 - exit[i] / uniform);
+                for (j, rate) in edges {
+                    next[*j] += p * rate / uniform;
+                }
+            }
+            probabilities = next;
+        }
+        Ok((totals, end_probability.min(1.0)))
+    }
+
+    // The expected time in microseconds to reach STATEEND from the start, by
+    // solving for the expected time from each node.
+    fn time_to_end(&self) -> f64 {
+        let n = self.transitions.len();
+        let mut reaches = vec![false; n];
+        loop {
+            let mut changed = false;
+            for (i, edges) in self.transitions.iter().enumerate() {
+                if !reaches[i] && edges.iter().any(|(j, _)| *j == n || reaches[*j]) {
+                    reaches[i] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // all nodes are reachable from the start
+        if reaches.contains(&false) {
+            return f64::INFINITY;
+        }
+
+        // exit rate * time - sum of rate * time of next = 1, for each node
+        let exit = self.exit_rates();
+        let mut a = vec![vec![0.0; n + 1]; n];
+        for (i, edges) in self.transitions.iter().enumerate() {
+            a[i][i] = exit[i];
+            for (j, rate) in edges {
+                if *j < n {
+                    a[i][*j] -= rate;
+                }
+            }
+            a[i][n] = 1.0;
+        }
+        solve(a)[0]
+    }
+}
+
+// Solve the linear equations of the augmented matrix by Gaussian elimination
+// with partial pivoting.
+fn solve(mut a: Vec<Vec<f64>>) -> Vec<f64> {
+    let n = a.len();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|x, y| a[*x][col].abs().total_cmp(&a[*y][col].abs()))
+            .unwrap();
+        a.swap(col, pivot);
+        let (above, below) = a.split_at_mut(col + 1);
+        let pivot = &above[col];
+        for row in below {
+            let factor = row[col] / pivot[col];
+            if factor == 0.0 {
+                continue;
+            }
+            for (x, p) in row[col..].iter_mut().zip(&pivot[col..]) {
+                *x -= factor * p;
+            }
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (a[row][n] - sum) / a[row][row];
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::{MachineBuilder, StateBuilder};
+    use crate::overhead::*;
+    use crate::state::{CounterOperation, CounterUpdate};
+    use crate::test_support::constant;
+
+    fn traffic() -> TrafficModel {
+        TrafficModel {
+            nonpadding_sent: 100.0,
+            nonpadding_recv: 0.0,
+            padding_recv: 0.0,
+            mtu: 1420,
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= 1e-6 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn padding_until_end() {
+        // after the first packet, pad 10 times on average and end
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .pad(constant(500.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "pad", 0.9)
+                    .end(Event::PaddingSent, 0.1),
+            )
+            .build()
+            .unwrap();
+
+        let e = estimate(&m, &traffic(), Duration::from_secs(10)).unwrap();
+        assert_close(e.padding_packets, 10.0);
+        assert_close(e.padding_bytes, 5000.0);
+        assert_close(e.blocking_microsec, 0.0);
+        assert_close(e.nonpadding_packets, 1000.0);
+        assert_close(e.end_probability, 1.0);
+        assert_close(e.microsec_to_end, 10_000.0 + 10.0 * 1000.0);
+        assert_close(e.padding_per_nonpadding(), 0.01);
+
+        // nothing happens without time
+        let e = estimate(&m, &traffic(), Duration::ZERO).unwrap();
+        assert_eq!(e.padding_packets, 0.0);
+        assert_eq!(e.end_probability, 0.0);
+        assert_close(e.microsec_to_end, 20_000.0);
+    }
+
+    #[test]
+    fn padding_forever() {
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .pad(Dist::new())
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "pad", 1.0),
+            )
+            .build()
+            .unwrap();
+
+        // padding every millisecond after the first packet, on average after
+        // 10 milliseconds
+        let e = estimate(&m, &traffic(), Duration::from_secs(10)).unwrap();
+        let padding = (10_000_000.0 - 10_000.0 * (1.0 - libm::exp(-1000.0))) / 1000.0;
+        assert_close(e.padding_packets, padding);
+        assert_close(e.padding_bytes, 1420.0 * padding);
+        assert_eq!(e.end_probability, 0.0);
+        assert_eq!(e.microsec_to_end, f64::INFINITY);
+    }
+
+    #[test]
+    fn limits_and_blocking() {
+        // pad 5 times on average, then block for 2 ms and end
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new().transition(Event::NonPaddingSent, "pad", 1.0),
+            )
+            .state(
+                "pad",
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .limit(constant(5.0))
+                    .transition(Event::PaddingSent, "pad", 1.0)
+                    .transition(Event::LimitReached, "block", 1.0),
+            )
+            .state(
+                "block",
+                StateBuilder::new()
+                    .block(constant(2000.0))
+                    .timeout(constant(0.0))
+                    .end(Event::BlockingEnd, 1.0),
+            )
+            .build()
+            .unwrap();
+
+        let e = estimate(&m, &traffic(), Duration::from_secs(1)).unwrap();
+        assert_close(e.padding_packets, 5.0);
+        assert_close(e.blocking_microsec, 2000.0);
+        assert_close(e.end_probability, 1.0);
+        // timeouts take at least a microsecond
+        assert_close(e.microsec_to_end, 10_000.0 + 5.0 * 1000.0 + 1.0 + 2000.0);
+        // a step per microsecond, too many for a long duration
+        assert!(estimate(&m, &traffic(), Duration::from_secs(100)).is_err());
+
+        // a limit that is always zero
+        let mut m = m;
+        m.states[1].limit = constant(0.0);
+        let e = estimate(&m, &traffic(), Duration::from_secs(1)).unwrap();
+        assert_eq!(e.padding_packets, 0.0);
+        assert_eq!(e.microsec_to_end, f64::INFINITY);
+    }
+
+    #[test]
+    fn unsupported() {
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .timer(Dist::new())
+                    .transition(Event::TimerEnd, "start", 1.0),
+            )
+            .build()
+            .unwrap();
+        assert!(estimate(&m, &traffic(), Duration::from_secs(1)).is_err());
+
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new().counter(
+                    0,
+                    CounterUpdate {
+                        operation: CounterOperation::Increment,
+                        value: None,
+                    },
+                ),
+            )
+            .build()
+            .unwrap();
+        assert!(estimate(&m, &traffic(), Duration::from_secs(1)).is_err());
+
+        let m = MachineBuilder::new()
+            .state("start", StateBuilder::new())
+            .build()
+            .unwrap();
+        assert!(estimate(&m, &traffic(), Duration::from_secs(1)).is_ok());
+        let mut t = traffic();
+        t.nonpadding_recv = -1.0;
+        assert!(estimate(&m, &t, Duration::from_secs(1)).is_err());
+        t.nonpadding_recv = f64::NAN;
+        assert!(estimate(&m, &t, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn too_many_nodes() {
+        // a chain of padding states, each at least one node
+        let mut builder = MachineBuilder::new();
+        for i in 0..=MAXESTIMATENODES {
+            let next = (i + 1).to_string();
+            builder = builder.state(
+                i.to_string(),
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::NonPaddingSent, &next, 1.0)
+                    .transition(Event::PaddingSent, &next, 1.0),
+            );
+        }
+        let m = builder
+            .state((MAXESTIMATENODES + 1).to_string(), StateBuilder::new())
+            .build()
+            .unwrap();
+        let e = estimate(&m, &traffic(), Duration::from_secs(1)).unwrap_err();
+        assert!(e.to_string().contains("nodes"), "{}", e);
+
+        // but the chain of a small machine is fine
+        let m = MachineBuilder::new()
+            .state(
+                "start",
+                StateBuilder::new()
+                    .pad(constant(100.0))
+                    .timeout(constant(1000.0))
+                    .transition(Event::PaddingSent, "start", 1.0),
+            )
+            .build()
+            .unwrap();
+        assert!(estimate(&m, &traffic(), Duration::from_secs(1)).is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+//! Token-bucket rate limits on padding, see [`RateLimit`].
+//!
+//! Fractions limit padding relative to non-padding traffic, while a rate limit
+//! is an absolute ceiling, e.g., for metered links. A rate limit applies on top
+//! of all other padding limits, including
+//! [`Machine::allowed_padding_bytes`](crate::machine::Machine::allowed_padding_bytes).
+
+use crate::constants::*;
+use crate::error::{FrameworkError, MachineError};
+use crate::time::Instant;
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use core::error::Error;
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// A token-bucket limit on padding: the bucket holds up to `burst_bytes` and
+/// is refilled at `bytes_per_sec`. Padding sent takes from the bucket, and no
+/// padding actions are scheduled while the bucket is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// The rate at which the bucket is refilled, in bytes per second.
+    pub bytes_per_sec: u64,
+    /// The size of the bucket, in bytes.
+    pub burst_bytes: u64,
+}
+
+impl RateLimit {
+    /// Validates that the rate limit can ever allow padding.
+    pub fn validate(&self) -> Result<(), MachineError> {
+        let invalid = |reason: &str| Err(MachineError::InvalidRateLimit(reason.to_string()));
+        if self.bytes_per_sec == 0 {
+            return invalid("rate limit must have a rate above zero");
+        }
+        if self.burst_bytes == 0 {
+            return invalid("rate limit must have a burst above zero");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn serialize(limit: &Option<Self>) -> [u8; SERIALIZEDRATELIMITSIZE] {
+        let mut buf = [0; SERIALIZEDRATELIMITSIZE];
+        if let Some(limit) = limit {
+            buf[0] = 1;
+            buf[1..9].copy_from_slice(&limit.bytes_per_sec.to_le_bytes());
+            buf[9..].copy_from_slice(&limit.burst_bytes.to_le_bytes());
+        }
+        buf
+    }
+
+    pub(crate) fn parse(buf: &[u8]) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        if buf.len() < SERIALIZEDRATELIMITSIZE {
+            bail!("not enough data for rate limit")
+        }
+        match buf[0] {
+            0 => Ok(None),
+            1 => Ok(Some(RateLimit {
+                bytes_per_sec: u64::from_le_bytes(buf[1..9].try_into().unwrap()),
+                burst_bytes: u64::from_le_bytes(buf[9..17].try_into().unwrap()),
+            })),
+            f => bail!("invalid rate limit flag {}", f),
+        }
+    }
+}
+
+/// The runtime state of a [`RateLimit`]. The bucket starts full and is
+/// refilled lazily. Padding larger than the remaining tokens puts the bucket
+/// in debt, so the rate holds over time.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenBucket<T> {
+    tokens: f64,
+    last: T,
+}
+
+impl<T: Instant> TokenBucket<T> {
+    pub(crate) fn new(limit: &Option<RateLimit>, now: T) -> Self {
+        TokenBucket {
+            tokens: limit.map_or(0.0, |l| l.burst_bytes as f64),
+            last: now,
+        }
+    }
+
+    /// Returns the tokens in the bucket at `now`, or `None` if there is no
+    /// limit.
+    pub(crate) fn available(&self, limit: &Option<RateLimit>, now: T) -> Option<f64> {
+        let limit = limit.as_ref()?;
+        let refill = now.duration_since(self.last).as_secs_f64() * limit.bytes_per_sec as f64;
+        Some((self.tokens + refill).min(limit.burst_bytes as f64))
+    }
+
+    /// Takes `bytes` tokens from the bucket at `now`.
+    pub(crate) fn take(&mut self, limit: &Option<RateLimit>, now: T, bytes: u64) {
+        if let Some(tokens) = self.available(limit, now) {
+            self.tokens = tokens - bytes as f64;
+            self.last = self.last.max(now);
+        }
+    }
+
+    pub(crate) fn snapshot(&self, reference: T) -> TokenBucketSnapshot {
+        TokenBucketSnapshot {
+            tokens: self.tokens,
+            last: reference.duration_since(self.last),
+        }
+    }
+
+    pub(crate) fn restore(
+        snapshot: &TokenBucketSnapshot,
+        reference: T,
+    ) -> Result<Self, FrameworkError> {
+        Ok(TokenBucket {
+            tokens: snapshot.tokens,
+            last: reference
+                .checked_sub(snapshot.last)
+                .ok_or(FrameworkError::UnrepresentableTime)?,
+        })
+    }
+}
+
+/// A [`TokenBucket`] in a snapshot, with the last time as a duration before
+/// the reference point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TokenBucketSnapshot {
+    tokens: f64,
+    last: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate::*;
+    use std::time::Instant;
+
+    #[test]
+    fn token_bucket() {
+        let limit = Some(RateLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 1500,
+        });
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut b = TokenBucket::new(&limit, start);
+        assert_eq!(b.available(&None, start), None);
+        assert_eq!(b.available(&limit, start), Some(1500.0));
+
+        // into debt, then refilled at the rate
+        b.take(&limit, start, 2000);
+        assert_eq!(b.available(&limit, start), Some(-500.0));
+        assert_eq!(b.available(&limit, at(1000)), Some(500.0));
+        // but never above the burst
+        assert_eq!(b.available(&limit, at(60_000)), Some(1500.0));
+        b.take(&limit, at(60_000), 1000);
+        assert_eq!(b.available(&limit, at(60_000)), Some(500.0));
+    }
+
+    #[test]
+    fn rate_limit_serialization() {
+        let rate = RateLimit {
+            bytes_per_sec: 1,
+            burst_bytes: 2,
+        };
+        let limit = Some(rate);
+        assert_eq!(
+            RateLimit::parse(&RateLimit::serialize(&limit)).unwrap(),
+            limit
+        );
+        assert_eq!(
+            RateLimit::parse(&RateLimit::serialize(&None)).unwrap(),
+            None
+        );
+        let mut buf = RateLimit::serialize(&limit);
+        buf[0] = 2;
+        assert!(RateLimit::parse(&buf).is_err());
+        assert!(rate.validate().is_ok());
+        assert!(RateLimit {
+            bytes_per_sec: 0,
+            burst_bytes: 2
+        }
+        .validate()
+        .is_err());
+    }
+}
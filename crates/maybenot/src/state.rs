@@ -2,15 +2,101 @@
 
 use crate::constants::*;
 use crate::dist::*;
+use crate::error::MachineError;
 use crate::event::*;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
 use byteorder::ByteOrder;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::LittleEndian;
+use rand::RngCore;
 use serde::Deserialize;
 use serde::Serialize;
-use simple_error::bail;
-use std::collections::HashMap;
-use std::error::Error;
-use std::io::Write;
+
+/// The operation of a [`CounterUpdate`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum CounterOperation {
+    /// Increment the counter by the value (saturating at `u64::MAX`).
+    Increment,
+    /// Decrement the counter by the value (saturating at zero).
+    Decrement,
+    /// Set the counter to the value.
+    Set,
+}
+
+/// An update of one of the counters of a [`Machine`](crate::machine), applied
+/// on transition to a [`State`]. When a counter goes from nonzero to zero,
+/// [`Event::CounterZero`] is triggered for the machine.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct CounterUpdate {
+    /// The operation to perform on the counter.
+    pub operation: CounterOperation,
+    /// The distribution sampled for the value of the operation. If not set,
+    /// the value is 1.
+    pub value: Option<Dist>,
+}
+
+impl CounterUpdate {
+    /// Sample the value of the operation. May panic if the distribution is
+    /// not valid.
+    pub fn sample_value<R: RngCore + ?Sized>(&self, rng: &mut R) -> u64 {
+        self.try_sample_value(rng).unwrap()
+    }
+
+    /// Like [`Self::sample_value()`], but returns an error if the
+    /// distribution is not valid.
+    pub fn try_sample_value<R: RngCore + ?Sized>(&self, rng: &mut R) -> Result<u64, MachineError> {
+        let value = self.value.as_ref().map(Dist::prepare).transpose()?;
+        Ok(sample_counter_value(value.as_ref(), rng))
+    }
+
+    /// Apply the update with the given (sampled) value to a counter, returning
+    /// the new value of the counter.
+    pub fn apply(&self, counter: u64, value: u64) -> u64 {
+        match self.operation {
+            CounterOperation::Increment => counter.saturating_add(value),
+            CounterOperation::Decrement => counter.saturating_sub(value),
+            CounterOperation::Set => value,
+        }
+    }
+
+    fn serialize(update: &Option<Self>) -> Vec<u8> {
+        let mut wtr = Vec::with_capacity(SERIALIZEDCOUNTERUPDATESIZE);
+        let (op, value) = match update {
+            None => (0u8, None),
+            Some(u) => match u.operation {
+                CounterOperation::Increment => (1, u.value),
+                CounterOperation::Decrement => (2, u.value),
+                CounterOperation::Set => (3, u.value),
+            },
+        };
+        wtr.push(op);
+        wtr.extend_from_slice(&value.unwrap_or_default().serialize());
+        wtr
+    }
+
+    fn parse(buf: &[u8]) -> Result<Option<Self>, MachineError> {
+        let operation = match buf[0] {
+            0 => return Ok(None),
+            1 => CounterOperation::Increment,
+            2 => CounterOperation::Decrement,
+            3 => CounterOperation::Set,
+            op => {
+                return Err(MachineError::InvalidEncoding(format!(
+                    "unknown counter operation {}",
+                    op
+                )))
+            }
+        };
+        let value = parse_encoded_dist(&buf[1..SERIALIZEDCOUNTERUPDATESIZE])?;
+        Ok(Some(CounterUpdate {
+            operation,
+            value: (value.dist != DistType::None).then_some(value),
+        }))
+    }
+}
 
 /// A state as part of a [`Machine`](crate::machine).
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +109,15 @@ pub struct State {
     /// A flag that determines the action. If true, the action on timeout is to
     /// block. If false, the action is to inject padding.
     pub action_is_block: bool,
+    /// A flag that determines if the action is to update the timer of the
+    /// machine instead of padding or blocking. If true, the action on
+    /// transition to this state is an
+    /// [`Action::UpdateTimer`](crate::framework::Action::UpdateTimer) with a
+    /// duration sampled from `action` (see [`Self::replace`] for the replace
+    /// flag of the action). The timeout is not used. When the timer expires,
+    /// [`Event::TimerEnd`] is triggered for the machine. Cannot be combined
+    /// with [`Self::action_is_block`].
+    pub action_is_timer: bool,
     /// If the action is to block, this flag determines if padding actions are
     /// allowed to bypass this block action. If the action is to pad, this flag
     /// determines if the padding packet bypasses any existing blocking (that
@@ -34,7 +129,9 @@ pub struct State {
     /// If the action is to block, this flag determines if the action duration
     /// should replace any existing blocking. If the action is to pad, this flag
     /// determines if the padding packet MAY be replaced by a non-padding packet
-    /// queued at the time the padding packet would have been sent.
+    /// queued at the time the padding packet would have been sent. If the
+    /// action is to update the timer, this flag determines if the duration
+    /// should replace any active timer of the machine.
     pub replace: bool,
     /// A sampled limit on the number of actions allowed on repeated transitions
     /// to the same state.
@@ -42,61 +139,107 @@ pub struct State {
     /// A flag that specifies if the sampled limit should also be decremented on
     /// nonpadding (normal) traffic sent.
     pub limit_includes_nonpadding: bool,
+    /// Updates of the counters of the machine, by counter index, applied on
+    /// every transition to this state (including transitions from the state to
+    /// itself). Counters start at zero.
+    pub counters: [Option<CounterUpdate>; NUMCOUNTERS],
     /// A map of all possible events associated to a probability vector. This is
     /// a transition matrix, so the length of the probability vector is a
     /// function of the total number of states in a machine. The structure of
     /// the map is created by [`make_next_state()`].
-    pub next_state: HashMap<Event, Vec<f64>>,
+    pub next_state: BTreeMap<Event, Vec<f64>>,
 }
 
 impl State {
     /// Create a new [`State`] with the given map of transitions ([`Event`] to probability vector)
-    /// and number of total states in the [`Machine`](crate::machine).
-    pub fn new(t: HashMap<Event, HashMap<usize, f64>>, num_states: usize) -> Self {
+    /// and number of total states in the [`Machine`](crate::machine). The
+    /// transitions can be given as any map (or iterator) of [`Event`] to a map
+    /// of state index to probability, e.g., a `HashMap<Event, HashMap<usize,
+    /// f64>>`.
+    pub fn new<T, P>(t: T, num_states: usize) -> Self
+    where
+        T: IntoIterator<Item = (Event, P)>,
+        P: IntoIterator<Item = (usize, f64)>,
+    {
         State {
             timeout: Dist::new(),
             action: Dist::new(),
             action_is_block: false,
+            action_is_timer: false,
             bypass: false,
             replace: false,
             limit: Dist::new(),
             limit_includes_nonpadding: false,
+            counters: [None; NUMCOUNTERS],
             next_state: make_next_state(t, num_states),
         }
     }
 
-    /// Sample a timeout.
-    pub fn sample_timeout(&self) -> f64 {
-        self.timeout.sample().min(MAXSAMPLEDTIMEOUT)
+    /// Sample a timeout. May panic if the distribution is not valid. The
+    /// distribution is prepared on every call, unlike when sampled by the
+    /// [`Framework`](crate::framework::Framework).
+    pub fn sample_timeout<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        self.try_sample_timeout(rng).unwrap()
     }
 
-    /// Sample a limit.
-    pub fn sample_limit(&self) -> u64 {
-        if self.limit.dist == DistType::None {
-            return STATELIMITMAX;
-        }
-        self.limit.sample().round() as u64
+    /// Like [`Self::sample_timeout()`], but returns an error if the
+    /// distribution is not valid.
+    pub fn try_sample_timeout<R: RngCore + ?Sized>(
+        &self,
+        rng: &mut R,
+    ) -> Result<f64, MachineError> {
+        Ok(sample_timeout(&self.timeout.prepare()?, rng))
     }
 
-    /// Sample a size for a padding action.
-    pub fn sample_size(&self, mtu: u64) -> u64 {
-        if self.action.dist == DistType::None {
-            return mtu;
-        }
-        let s = self.action.sample().round() as u64;
-        if s > mtu {
-            return mtu;
-        }
-        if s == 0 {
-            // never send empty padding
-            return 1;
-        }
-        s
+    /// Sample a limit. May panic if the distribution is not valid.
+    pub fn sample_limit<R: RngCore + ?Sized>(&self, rng: &mut R) -> u64 {
+        self.try_sample_limit(rng).unwrap()
     }
 
-    /// Sample a block duration for a blocking action.
-    pub fn sample_block(&self) -> f64 {
-        self.action.sample().min(MAXSAMPLEDBLOCK)
+    /// Like [`Self::sample_limit()`], but returns an error if the
+    /// distribution is not valid.
+    pub fn try_sample_limit<R: RngCore + ?Sized>(&self, rng: &mut R) -> Result<u64, MachineError> {
+        Ok(sample_limit(&self.limit.prepare()?, rng))
+    }
+
+    /// Sample a size for a padding action. May panic if the distribution is
+    /// not valid.
+    pub fn sample_size<R: RngCore + ?Sized>(&self, mtu: u64, rng: &mut R) -> u64 {
+        self.try_sample_size(mtu, rng).unwrap()
+    }
+
+    /// Like [`Self::sample_size()`], but returns an error if the distribution
+    /// is not valid.
+    pub fn try_sample_size<R: RngCore + ?Sized>(
+        &self,
+        mtu: u64,
+        rng: &mut R,
+    ) -> Result<u64, MachineError> {
+        Ok(sample_size(&self.action.prepare()?, mtu, rng))
+    }
+
+    /// Sample a block duration for a blocking action. May panic if the
+    /// distribution is not valid.
+    pub fn sample_block<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        self.try_sample_block(rng).unwrap()
+    }
+
+    /// Like [`Self::sample_block()`], but returns an error if the
+    /// distribution is not valid.
+    pub fn try_sample_block<R: RngCore + ?Sized>(&self, rng: &mut R) -> Result<f64, MachineError> {
+        Ok(sample_block(&self.action.prepare()?, rng))
+    }
+
+    /// Sample a timer duration for a timer action. May panic if the
+    /// distribution is not valid.
+    pub fn sample_timer<R: RngCore + ?Sized>(&self, rng: &mut R) -> f64 {
+        self.try_sample_timer(rng).unwrap()
+    }
+
+    /// Like [`Self::sample_timer()`], but returns an error if the
+    /// distribution is not valid.
+    pub fn try_sample_timer<R: RngCore + ?Sized>(&self, rng: &mut R) -> Result<f64, MachineError> {
+        Ok(sample_timer(&self.action.prepare()?, rng))
     }
 
     /// Serialize the state into a byte vector.
@@ -104,42 +247,128 @@ impl State {
         let mut wtr = vec![];
 
         // distributions
-        wtr.write_all(&self.action.serialize()).unwrap();
-        wtr.write_all(&self.limit.serialize()).unwrap();
-        wtr.write_all(&self.timeout.serialize()).unwrap();
+        wtr.extend_from_slice(&self.action.serialize());
+        wtr.extend_from_slice(&self.limit.serialize());
+        wtr.extend_from_slice(&self.timeout.serialize());
 
         // flags
-        if self.action_is_block {
-            wtr.write_u8(1).unwrap();
-        } else {
-            wtr.write_u8(0).unwrap();
-        }
-        if self.bypass {
-            wtr.write_u8(1).unwrap();
-        } else {
-            wtr.write_u8(0).unwrap();
-        }
-        if self.replace {
-            wtr.write_u8(1).unwrap();
-        } else {
-            wtr.write_u8(0).unwrap();
+        wtr.push(self.action_is_block as u8);
+        wtr.push(self.bypass as u8);
+        wtr.push(self.replace as u8);
+        wtr.push(self.limit_includes_nonpadding as u8);
+        wtr.push(self.action_is_timer as u8);
+
+        // counter updates
+        for update in &self.counters {
+            wtr.extend_from_slice(&CounterUpdate::serialize(update));
         }
-        if self.limit_includes_nonpadding {
-            wtr.write_u8(1).unwrap();
-        } else {
-            wtr.write_u8(0).unwrap();
+
+        self.serialize_dense_next_state(&mut wtr, num_states, Event::iterator().as_slice());
+
+        wtr
+    }
+
+    /// Serialize the state as part of a machine of the given version of the
+    /// format. The state MUST be representable in the version, see
+    /// [`Machine::serialize_version()`](crate::machine::Machine::serialize_version).
+    pub(crate) fn serialize_version(&self, num_states: usize, version: u16) -> Vec<u8> {
+        match version {
+            1 => {
+                let mut wtr = vec![];
+                wtr.extend_from_slice(&self.action.serialize());
+                wtr.extend_from_slice(&self.limit.serialize());
+                wtr.extend_from_slice(&self.timeout.serialize());
+                wtr.push(self.action_is_block as u8);
+                wtr.push(self.bypass as u8);
+                wtr.push(self.replace as u8);
+                wtr.push(self.limit_includes_nonpadding as u8);
+                self.serialize_dense_next_state(
+                    &mut wtr,
+                    num_states,
+                    Event::v1_iterator().as_slice(),
+                );
+                wtr
+            }
+            2 | 3 => self.serialize(num_states),
+            _ => self.serialize_sparse(),
         }
+    }
 
-        // next_state, ugly, encodes every possible event to be constant size
-        for event in Event::iterator() {
+    // next_state, ugly, encodes every possible event to be constant size
+    fn serialize_dense_next_state(&self, wtr: &mut Vec<u8>, num_states: usize, events: &[Event]) {
+        for event in events {
             let exists = self.next_state.contains_key(event);
             for i in 0..num_states + 2 {
-                if exists {
-                    wtr.write_f64::<LittleEndian>(self.next_state[event][i])
-                        .unwrap();
+                let p = if exists {
+                    self.next_state[event][i]
                 } else {
-                    wtr.write_f64::<LittleEndian>(0.0).unwrap();
-                }
+                    0.0
+                };
+                wtr.extend_from_slice(&p.to_le_bytes());
+            }
+        }
+    }
+
+    /// Serialize the state sparsely (version 4 of the format): a byte with a
+    /// bit set for each of the action, limit, and timeout distributions that
+    /// are set (not [`Dist::new()`]) followed by those distributions, 5 flags,
+    /// counter updates as an operation byte followed by a value distribution
+    /// byte and distribution if set, and then the number of events with
+    /// transitions, each as an event byte, a u32 number of transitions, and
+    /// (u32 index, f64 probability) for each transition with a probability
+    /// above zero.
+    fn serialize_sparse(&self) -> Vec<u8> {
+        let mut wtr = vec![];
+
+        // distributions
+        let dists = [&self.action, &self.limit, &self.timeout];
+        let mut present = 0u8;
+        for (i, dist) in dists.iter().enumerate() {
+            if **dist != Dist::new() {
+                present |= 1 << i;
+            }
+        }
+        wtr.push(present);
+        for dist in dists.into_iter().filter(|d| **d != Dist::new()) {
+            wtr.extend_from_slice(&Dist::serialize(*dist));
+        }
+
+        // flags
+        wtr.push(self.action_is_block as u8);
+        wtr.push(self.bypass as u8);
+        wtr.push(self.replace as u8);
+        wtr.push(self.limit_includes_nonpadding as u8);
+        wtr.push(self.action_is_timer as u8);
+
+        // counter updates
+        for update in &self.counters {
+            let buf = CounterUpdate::serialize(update);
+            wtr.push(buf[0]);
+            if let Some(value) = update.and_then(|u| u.value) {
+                wtr.push(1);
+                wtr.extend_from_slice(&value.serialize());
+            } else if update.is_some() {
+                wtr.push(0);
+            }
+        }
+
+        // next_state, only transitions with a probability
+        wtr.push(self.next_state.len() as u8);
+        for (event, probabilities) in &self.next_state {
+            let index = Event::iterator()
+                .position(|e| e == event)
+                .unwrap_or_default();
+            wtr.push(index as u8);
+            let transitions: Vec<(usize, f64)> = probabilities
+                .iter()
+                .copied()
+                .enumerate()
+                .filter(|(_, p)| *p != 0.0)
+                .collect();
+            wtr.extend_from_slice(&(transitions.len() as u32).to_le_bytes());
+            for (i, p) in transitions {
+                wtr.extend_from_slice(&(i as u32).to_le_bytes());
+                wtr.extend_from_slice(&p.to_le_bytes());
             }
         }
 
@@ -147,23 +376,256 @@ impl State {
     }
 }
 
+/// Attempt to parse a sparse [`State`] (see [`State::serialize_sparse()`]) as
+/// part of a machine with the specified number of states, returning the state
+/// and the number of bytes read.
+pub(crate) fn parse_sparse_state(
+    buf: &[u8],
+    num_states: usize,
+    budget: &mut usize,
+) -> Result<(State, usize), MachineError> {
+    let mut r: usize = 0;
+    let mut take = |n: usize| -> Result<&[u8], MachineError> {
+        if buf.len() < r + n {
+            return Err(MachineError::Truncated(
+                "not enough data for sparse state".to_string(),
+            ));
+        }
+        r += n;
+        Ok(&buf[r - n..r])
+    };
+
+    // distributions
+    let present = take(1)?[0];
+    if present >> 3 != 0 {
+        return Err(MachineError::InvalidEncoding(format!(
+            "invalid distributions byte {}",
+            present
+        )));
+    }
+    let mut dists = [Dist::new(); 3];
+    for (i, dist) in dists.iter_mut().enumerate() {
+        if present & (1 << i) != 0 {
+            *dist = parse_encoded_dist(take(SERIALIZEDDISTSIZE)?)?;
+        }
+    }
+    let [action, limit, timeout] = dists;
+
+    // flags
+    let flags = take(5)?;
+    let action_is_block = flags[0] == 1;
+    let bypass = flags[1] == 1;
+    let replace = flags[2] == 1;
+    let limit_includes_nonpadding = flags[3] == 1;
+    let action_is_timer = flags[4] == 1;
+
+    // counter updates
+    let mut counters = [None; NUMCOUNTERS];
+    for counter in counters.iter_mut() {
+        let op = take(1)?[0];
+        let mut update = [0; SERIALIZEDCOUNTERUPDATESIZE];
+        update[0] = op;
+        if op != 0 {
+            match take(1)?[0] {
+                0 => {}
+                1 => update[1..].copy_from_slice(take(SERIALIZEDDISTSIZE)?),
+                v => {
+                    return Err(MachineError::InvalidEncoding(format!(
+                        "invalid counter value byte {}",
+                        v
+                    )))
+                }
+            }
+        }
+        *counter = CounterUpdate::parse(&update)?;
+    }
+
+    // next state
+    let mut next_state: BTreeMap<Event, Vec<f64>> = BTreeMap::new();
+    let num_events = take(1)?[0];
+    for _ in 0..num_events {
+        let index = take(1)?[0];
+        let Some(event) = Event::iterator().nth(index as usize) else {
+            return Err(MachineError::InvalidEncoding(format!(
+                "unknown event {}",
+                index
+            )));
+        };
+        let num_transitions = LittleEndian::read_u32(take(4)?) as usize;
+        // the budget is in bytes of dense transition matrices
+        take_budget(budget, (num_states + 2).saturating_mul(8))?;
+        let mut m = vec![0.0; num_states + 2];
+        for _ in 0..num_transitions {
+            let i = LittleEndian::read_u32(take(4)?) as usize;
+            let p = LittleEndian::read_f64(take(8)?);
+            if i >= m.len() {
+                return Err(MachineError::InvalidEncoding(format!(
+                    "transition to state {}, but only {} states",
+                    i, num_states
+                )));
+            }
+            m[i] = p;
+        }
+        if next_state.insert(*event, m).is_some() {
+            return Err(MachineError::InvalidEncoding(format!(
+                "duplicate transitions on event {}",
+                event
+            )));
+        }
+    }
+
+    Ok((
+        State {
+            timeout,
+            action,
+            action_is_block,
+            action_is_timer,
+            bypass,
+            replace,
+            limit,
+            limit_includes_nonpadding,
+            counters,
+            next_state,
+        },
+        r,
+    ))
+}
+
+// parse a serialized distribution, with errors for machines
+fn parse_encoded_dist(buf: &[u8]) -> Result<Dist, MachineError> {
+    parse_dist(buf.to_vec()).map_err(|e| MachineError::InvalidEncoding(e.to_string()))
+}
+
+// Take the size in bytes from the budget for the memory of a parsed machine,
+// see MAXMACHINESIZE.
+pub(crate) fn take_budget(budget: &mut usize, size: usize) -> Result<(), MachineError> {
+    if *budget < size {
+        return Err(MachineError::TooLarge {
+            max: MAXMACHINESIZE,
+        });
+    }
+    *budget -= size;
+    Ok(())
+}
+
+// The sampling of the prepared distributions of a state, shared by State and
+// CompiledState (see crate::compiled).
+
+pub(crate) fn sample_timeout<R: RngCore + ?Sized>(timeout: &PreparedDist, rng: &mut R) -> f64 {
+    timeout.sample(rng).min(MAXSAMPLEDTIMEOUT)
+}
+
+pub(crate) fn sample_limit<R: RngCore + ?Sized>(limit: &PreparedDist, rng: &mut R) -> u64 {
+    if limit.dist().dist == DistType::None {
+        return STATELIMITMAX;
+    }
+    libm::round(limit.sample(rng)) as u64
+}
+
+pub(crate) fn sample_size<R: RngCore + ?Sized>(
+    action: &PreparedDist,
+    mtu: u64,
+    rng: &mut R,
+) -> u64 {
+    if action.dist().dist == DistType::None {
+        return mtu;
+    }
+    padding_size(action.sample(rng), mtu)
+}
+
+pub(crate) fn sample_block<R: RngCore + ?Sized>(action: &PreparedDist, rng: &mut R) -> f64 {
+    action.sample(rng).min(MAXSAMPLEDBLOCK)
+}
+
+pub(crate) fn sample_timer<R: RngCore + ?Sized>(action: &PreparedDist, rng: &mut R) -> f64 {
+    action.sample(rng).min(MAXSAMPLEDTIMEOUT)
+}
+
+pub(crate) fn sample_counter_value<R: RngCore + ?Sized>(
+    value: Option<&PreparedDist>,
+    rng: &mut R,
+) -> u64 {
+    match value {
+        Some(value) => libm::round(value.sample(rng)) as u64,
+        None => 1,
+    }
+}
+
+/// The size of padding from a sampled value, at most the MTU.
+pub(crate) fn padding_size(sampled: f64, mtu: u64) -> u64 {
+    let s = libm::round(sampled) as u64;
+    if s > mtu {
+        return mtu;
+    }
+    if s == 0 {
+        // never send empty padding
+        return 1;
+    }
+    s
+}
+
+/// The size of a serialized [`State`] as part of a machine with the given
+/// number of states and version of the format.
+pub(crate) fn serialized_state_size(num_states: usize, version: u16) -> usize {
+    // saturating, since the number of states may be untrusted
+    let matrix = num_states.saturating_add(2).saturating_mul(8);
+    match version {
+        // 3 distributions + 4 flags + next_state
+        1 => (3 * SERIALIZEDDISTSIZE + 4)
+            .saturating_add(matrix.saturating_mul(Event::v1_iterator().len())),
+        // 3 distributions + 5 flags + counter updates + next_state
+        _ => (3 * SERIALIZEDDISTSIZE + 5 + NUMCOUNTERS * SERIALIZEDCOUNTERUPDATESIZE)
+            .saturating_add(matrix.saturating_mul(Event::iterator().len())),
+    }
+}
+
+/// The maximum size of a sparse [`State`] (see [`State::serialize_sparse()`])
+/// as part of a machine with the given number of states: all distributions
+/// and counter updates, and a transition to every state on every event.
+pub(crate) fn max_sparse_state_size(num_states: usize) -> usize {
+    // index, count, and per transition an index and a probability
+    let event = num_states
+        .saturating_add(2)
+        .saturating_mul(4 + 8)
+        .saturating_add(1 + 4);
+    (1 + 3 * SERIALIZEDDISTSIZE + 5 + NUMCOUNTERS * (2 + SERIALIZEDDISTSIZE) + 1)
+        .saturating_add(event.saturating_mul(Event::iterator().len()))
+}
+
 /// Attempt to construct a [`State`] from the given bytes as part of a
 /// [`Machine`](crate::machine) with the specified number of states. The number
 /// of states has to be known since the size of the transition matrix depends on
 /// it.
-pub fn parse_state(buf: Vec<u8>, num_states: usize) -> Result<State, Box<dyn Error + Send + Sync>> {
-    // len: 3 distributions + 4 flags + next_state
-    if buf.len() < 3 * SERIALIZEDDISTSIZE + 4 + (num_states + 2) * 8 * Event::iterator().len() {
-        bail!("too small")
+pub fn parse_state(buf: Vec<u8>, num_states: usize) -> Result<State, MachineError> {
+    parse_state_version(&buf, num_states, VERSION as u16)
+}
+
+/// Like [`parse_state()`], but for a state serialized as part of a machine of
+/// the given version of the format. Version 1 states have no counter updates,
+/// no timer flag, and only the version 1 events.
+pub(crate) fn parse_state_version(
+    buf: &[u8],
+    num_states: usize,
+    version: u16,
+) -> Result<State, MachineError> {
+    if buf.len() < serialized_state_size(num_states, version) {
+        return Err(MachineError::Truncated(format!(
+            "not enough data for version {} state",
+            version
+        )));
     }
+    let (num_counters, events) = match version {
+        1 => (0, Event::v1_iterator().as_slice()),
+        _ => (NUMCOUNTERS, Event::iterator().as_slice()),
+    };
 
     // distributions
     let mut r: usize = 0;
-    let action = parse_dist(buf[r..r + SERIALIZEDDISTSIZE].to_vec()).unwrap();
+    let action = parse_encoded_dist(&buf[r..r + SERIALIZEDDISTSIZE])?;
     r += SERIALIZEDDISTSIZE;
-    let limit = parse_dist(buf[r..r + SERIALIZEDDISTSIZE].to_vec()).unwrap();
+    let limit = parse_encoded_dist(&buf[r..r + SERIALIZEDDISTSIZE])?;
     r += SERIALIZEDDISTSIZE;
-    let timeout = parse_dist(buf[r..r + SERIALIZEDDISTSIZE].to_vec()).unwrap();
+    let timeout = parse_encoded_dist(&buf[r..r + SERIALIZEDDISTSIZE])?;
     r += SERIALIZEDDISTSIZE;
 
     // flags
@@ -175,10 +637,22 @@ pub fn parse_state(buf: Vec<u8>, num_states: usize) -> Result<State, Box<dyn Err
     r += 1;
     let limit_includes_nonpadding: bool = buf[r] == 1;
     r += 1;
+    let mut action_is_timer = false;
+    if version > 1 {
+        action_is_timer = buf[r] == 1;
+        r += 1;
+    }
+
+    // counter updates
+    let mut counters = [None; NUMCOUNTERS];
+    for counter in counters.iter_mut().take(num_counters) {
+        *counter = CounterUpdate::parse(&buf[r..r + SERIALIZEDCOUNTERUPDATESIZE])?;
+        r += SERIALIZEDCOUNTERUPDATESIZE;
+    }
 
     // next state
-    let mut next_state: HashMap<Event, Vec<f64>> = HashMap::new();
-    for event in Event::iterator() {
+    let mut next_state: BTreeMap<Event, Vec<f64>> = BTreeMap::new();
+    for event in events {
         let mut m = vec![];
 
         let mut all_zeroes = true;
@@ -200,50 +674,38 @@ pub fn parse_state(buf: Vec<u8>, num_states: usize) -> Result<State, Box<dyn Err
         limit,
         action,
         action_is_block,
+        action_is_timer,
         bypass,
         replace,
         limit_includes_nonpadding,
+        counters,
         next_state,
     })
 }
 
 /// A helper used to construct [`State::next_state`] based on a map of
 /// transitions ([`Event`] to probability vector) and the total number of states
-/// in the [`Machine`](crate::machine).
-pub fn make_next_state(
-    t: HashMap<Event, HashMap<usize, f64>>,
-    num_states: usize,
-) -> HashMap<Event, Vec<f64>> {
-    let mut r = HashMap::new();
-    for event in Event::iterator() {
-        if !t.contains_key(event) {
-            continue;
-        }
-        let probmap = t.get(event).unwrap();
-        let mut res: Vec<f64> = vec![];
-
-        // go over the set states
-        for i in 0..num_states {
-            if probmap.contains_key(&i) {
-                res.push(*probmap.get(&i).unwrap());
-            } else {
-                res.push(0.0);
+/// in the [`Machine`](crate::machine). Transitions to states outside of the
+/// machine (other than [`STATECANCEL`] and [`STATEEND`]) are ignored.
+pub fn make_next_state<T, P>(t: T, num_states: usize) -> BTreeMap<Event, Vec<f64>>
+where
+    T: IntoIterator<Item = (Event, P)>,
+    P: IntoIterator<Item = (usize, f64)>,
+{
+    let mut r = BTreeMap::new();
+    for (event, probmap) in t {
+        // the set states followed by StateCancel and StateEnd
+        let mut res: Vec<f64> = vec![0.0; num_states + 2];
+        for (i, p) in probmap {
+            if i < num_states {
+                res[i] = p;
+            } else if i == STATECANCEL {
+                res[num_states] = p;
+            } else if i == STATEEND {
+                res[num_states + 1] = p;
             }
         }
-
-        // set StateCancel and StateEnd
-        if probmap.contains_key(&STATECANCEL) {
-            res.push(*probmap.get(&STATECANCEL).unwrap());
-        } else {
-            res.push(0.0);
-        }
-        if probmap.contains_key(&STATEEND) {
-            res.push(*probmap.get(&STATEEND).unwrap());
-        } else {
-            res.push(0.0);
-        }
-
-        r.insert(*event, res);
+        r.insert(event, res);
     }
 
     r
@@ -252,6 +714,7 @@ pub fn make_next_state(
 #[cfg(test)]
 mod tests {
     use crate::state::*;
+    use std::collections::HashMap;
 
     #[test]
     fn serialization() {
@@ -270,6 +733,9 @@ mod tests {
         t.insert(Event::NonPaddingRecv, e0);
         t.insert(Event::BlockingBegin, e1);
         t.insert(Event::LimitReached, e2);
+        let mut e3: HashMap<usize, f64> = HashMap::new();
+        e3.insert(STATEEND, 1.0);
+        t.insert(Event::CounterZero, e3);
 
         // create master
         let s = State {
@@ -295,9 +761,26 @@ mod tests {
                 max: 3.4,
             },
             action_is_block: false,
+            action_is_timer: true,
             bypass: false,
             replace: true,
             limit_includes_nonpadding: false,
+            counters: [
+                Some(CounterUpdate {
+                    operation: CounterOperation::Decrement,
+                    value: Some(Dist {
+                        dist: DistType::Uniform,
+                        param1: 1.0,
+                        param2: 3.0,
+                        start: 0.0,
+                        max: 0.0,
+                    }),
+                }),
+                Some(CounterUpdate {
+                    operation: CounterOperation::Set,
+                    value: None,
+                }),
+            ],
             next_state: make_next_state(t, num_states),
         };
 
@@ -305,4 +788,114 @@ mod tests {
         let parsed = parse_state(buf, num_states).unwrap();
         assert_eq!(s, parsed);
     }
+
+    #[test]
+    fn counter_update() {
+        let mut rng = rand::thread_rng();
+        let mut u = CounterUpdate {
+            operation: CounterOperation::Increment,
+            value: None,
+        };
+        assert_eq!(u.sample_value(&mut rng), 1);
+        assert_eq!(u.apply(u64::MAX, 1), u64::MAX);
+
+        u.operation = CounterOperation::Decrement;
+        u.value = Some(Dist {
+            dist: DistType::Uniform,
+            param1: 5.0,
+            param2: 5.0,
+            start: 0.0,
+            max: 0.0,
+        });
+        assert_eq!(u.sample_value(&mut rng), 5);
+        assert_eq!(u.apply(7, 5), 2);
+        assert_eq!(u.apply(2, 5), 0);
+
+        u.operation = CounterOperation::Set;
+        assert_eq!(u.apply(2, 5), 5);
+    }
+
+    #[test]
+    fn try_sample_invalid() {
+        let mut rng = rand::thread_rng();
+        let invalid = Dist {
+            dist: DistType::Uniform,
+            param1: 2.0,
+            param2: 1.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        let mut s = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), 1);
+        assert_eq!(s.try_sample_size(1420, &mut rng).unwrap(), 1420);
+        assert_eq!(s.try_sample_limit(&mut rng).unwrap(), STATELIMITMAX);
+        s.timeout = invalid;
+        s.limit = invalid;
+        s.action = invalid;
+        assert!(s.try_sample_timeout(&mut rng).is_err());
+        assert!(s.try_sample_limit(&mut rng).is_err());
+        assert!(s.try_sample_size(1420, &mut rng).is_err());
+        assert!(s.try_sample_block(&mut rng).is_err());
+        assert!(s.try_sample_timer(&mut rng).is_err());
+        let u = CounterUpdate {
+            operation: CounterOperation::Set,
+            value: Some(invalid),
+        };
+        assert!(u.try_sample_value(&mut rng).is_err());
+    }
+
+    #[test]
+    fn counter_update_unknown_operation() {
+        let mut buf = State::new(HashMap::<Event, HashMap<usize, f64>>::new(), 1).serialize(1);
+        // the operation of the first counter follows 3 dists and 5 flags
+        buf[3 * SERIALIZEDDISTSIZE + 5] = 4;
+        assert!(parse_state(buf, 1).is_err());
+    }
+
+    #[test]
+    fn sparse_serialization() {
+        let num_states = 4;
+        let mut t: HashMap<Event, HashMap<usize, f64>> = HashMap::new();
+        let mut e: HashMap<usize, f64> = HashMap::new();
+        e.insert(3, 0.4);
+        e.insert(STATEEND, 0.6);
+        t.insert(Event::CounterZero, e);
+        let mut s = State::new(t, num_states);
+        s.limit = Dist {
+            dist: DistType::Poisson,
+            param1: 2.0,
+            param2: 0.0,
+            start: 0.0,
+            max: 0.0,
+        };
+        s.counters[1] = Some(CounterUpdate {
+            operation: CounterOperation::Decrement,
+            value: None,
+        });
+        s.bypass = true;
+
+        let buf = s.serialize_version(num_states, 4);
+        // one distribution, flags, counters, and two transitions
+        assert_eq!(
+            buf.len(),
+            1 + SERIALIZEDDISTSIZE + 5 + 1 + 2 + 1 + 1 + 4 + 2 * (4 + 8)
+        );
+        let mut budget = MAXMACHINESIZE;
+        assert_eq!(
+            parse_sparse_state(&buf, num_states, &mut budget).unwrap(),
+            (s, buf.len())
+        );
+        // one dense transition matrix per event
+        assert_eq!(budget, MAXMACHINESIZE - (num_states + 2) * 8);
+
+        // truncated or out of bounds states are errors
+        for len in 0..buf.len() {
+            assert!(parse_sparse_state(&buf[..len], num_states, &mut budget).is_err());
+        }
+        assert!(parse_sparse_state(&buf, 2, &mut budget).is_err());
+        let mut budget = (num_states + 2) * 8 - 1;
+        assert!(matches!(
+            parse_sparse_state(&buf, num_states, &mut budget),
+            Err(MachineError::TooLarge { .. })
+        ));
+    }
 }
@@ -0,0 +1,19 @@
+//! Distributions shared by the tests of this crate.
+
+use crate::dist::{Dist, DistType};
+
+/// A distribution that always samples the value.
+pub(crate) fn constant(value: f64) -> Dist {
+    uniform(value, value)
+}
+
+/// A uniform distribution between low and high.
+pub(crate) fn uniform(low: f64, high: f64) -> Dist {
+    Dist {
+        dist: DistType::Uniform,
+        param1: low,
+        param2: high,
+        start: 0.0,
+        max: 0.0,
+    }
+}
@@ -0,0 +1,57 @@
+//! Time as used by the [`Framework`](crate::framework).
+//!
+//! The framework never reads a clock by itself: the current time is always
+//! provided by the caller. Anything that implements [`Instant`] can be used as
+//! time, e.g., [`std::time::Instant`] for regular use, a virtual clock in a
+//! simulator, or a monotonic counter in a kernel. The implementation for
+//! [`std::time::Instant`] requires the `std` feature.
+
+use core::ops::Add;
+use core::time::Duration;
+
+/// A measurement of a monotonically nondecreasing clock.
+pub trait Instant: Copy + Ord + Add<Duration, Output = Self> {
+    /// Returns the amount of time elapsed from `earlier` to `self`, or zero if
+    /// `earlier` is later than `self`.
+    fn duration_since(&self, earlier: Self) -> Duration;
+
+    /// Returns the instant `duration` after `self`, or `None` if that instant
+    /// cannot be represented.
+    fn checked_add(&self, duration: Duration) -> Option<Self>;
+
+    /// Returns the instant `duration` before `self`, or `None` if that instant
+    /// cannot be represented.
+    fn checked_sub(&self, duration: Duration) -> Option<Self>;
+}
+
+#[cfg(any(feature = "std", test))]
+impl Instant for std::time::Instant {
+    fn duration_since(&self, earlier: Self) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+
+    fn checked_add(&self, duration: Duration) -> Option<Self> {
+        std::time::Instant::checked_add(self, duration)
+    }
+
+    fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        std::time::Instant::checked_sub(self, duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::Instant;
+    use std::time::Duration;
+
+    #[test]
+    fn std_instant_saturates() {
+        let now = std::time::Instant::now();
+        let later = now + Duration::from_millis(1);
+        assert_eq!(
+            Instant::duration_since(&later, now),
+            Duration::from_millis(1)
+        );
+        assert_eq!(Instant::duration_since(&now, later), Duration::ZERO);
+    }
+}
@@ -0,0 +1,331 @@
+//! Sliding windows for padding and blocking budgets, see [`Window`].
+//!
+//! By default, the padding and blocking fractions of a
+//! [`Machine`](crate::machine::Machine) and the
+//! [`Framework`](crate::framework::Framework) are computed over their entire
+//! lifetime, so a connection that is idle for a long time accumulates headroom
+//! that can later be spent in a burst. With a sliding window, the fractions are
+//! instead computed over recent traffic only.
+
+use crate::constants::*;
+use crate::error::{FrameworkError, MachineError};
+use crate::time::Instant;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::ToString;
+use core::error::Error;
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// The window over which a padding or blocking fraction is computed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Window {
+    /// The entire lifetime of the machine or framework.
+    #[default]
+    Lifetime,
+    /// The last number of microseconds.
+    Microsec(u64),
+    /// The last number of bytes sent (padding and non-padding). Only for
+    /// padding fractions.
+    Bytes(u64),
+}
+
+impl Window {
+    /// Validates the window for use with padding fractions. A window in time
+    /// is at most [`MAXWINDOWMICROSEC`] long.
+    pub fn validate_padding(&self) -> Result<(), MachineError> {
+        self.validate("padding_window")
+    }
+
+    /// Validates the window for use with blocking fractions: blocking is
+    /// measured in time, so a window in bytes is invalid.
+    pub fn validate_blocking(&self) -> Result<(), MachineError> {
+        if let Window::Bytes(_) = self {
+            return Err(MachineError::InvalidWindow {
+                field: "blocking_window",
+                reason: "blocking window cannot be in bytes".to_string(),
+            });
+        }
+        self.validate("blocking_window")
+    }
+
+    fn validate(&self, field: &'static str) -> Result<(), MachineError> {
+        let reason = match self {
+            Window::Microsec(0) | Window::Bytes(0) => "window cannot be empty".to_string(),
+            Window::Microsec(w) if *w > MAXWINDOWMICROSEC => format!(
+                "window cannot be longer than {} microseconds",
+                MAXWINDOWMICROSEC
+            ),
+            _ => return Ok(()),
+        };
+        Err(MachineError::InvalidWindow { field, reason })
+    }
+
+    pub(crate) fn serialize(&self) -> [u8; SERIALIZEDWINDOWSIZE] {
+        let (kind, value): (u8, u64) = match self {
+            Window::Lifetime => (0, 0),
+            Window::Microsec(v) => (1, *v),
+            Window::Bytes(v) => (2, *v),
+        };
+        let mut buf = [0; SERIALIZEDWINDOWSIZE];
+        buf[0] = kind;
+        buf[1..].copy_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if buf.len() < SERIALIZEDWINDOWSIZE {
+            bail!("not enough data for window")
+        }
+        let value = u64::from_le_bytes(buf[1..SERIALIZEDWINDOWSIZE].try_into().unwrap());
+        match buf[0] {
+            0 => Ok(Window::Lifetime),
+            1 => Ok(Window::Microsec(value)),
+            2 => Ok(Window::Bytes(value)),
+            k => bail!("unknown window kind {}", k),
+        }
+    }
+}
+
+/// Approximate sliding window sums of two quantities (e.g., padding and
+/// non-padding bytes). The window is split into a current and a previous
+/// bucket, each as long as the window: the sums are the current bucket plus
+/// the previous bucket weighted by how much of it still overlaps the window.
+/// For time windows, the first quantity can also accrue elapsed microseconds
+/// (e.g., while blocking).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WindowCounter<T> {
+    start: T,
+    last: T,
+    current: [u64; 2],
+    previous: [u64; 2],
+}
+
+impl<T: Instant> WindowCounter<T> {
+    pub(crate) fn new(now: T) -> Self {
+        WindowCounter {
+            start: now,
+            last: now,
+            current: [0; 2],
+            previous: [0; 2],
+        }
+    }
+
+    /// Move the window forward to `now`. If `accrue`, the time since the last
+    /// advance is added to the first quantity.
+    pub(crate) fn advance(&mut self, window: Window, now: T, accrue: bool) {
+        let Window::Microsec(w) = window else {
+            return;
+        };
+        let len = Duration::from_micros(w);
+        loop {
+            // an end that cannot be represented is never reached
+            let end = self.start.checked_add(len);
+            let Some(end) = end.filter(|end| now >= *end) else {
+                if accrue {
+                    self.current[0] =
+                        self.current[0].saturating_add(micros(now.duration_since(self.last)));
+                }
+                self.last = self.last.max(now);
+                return;
+            };
+            if accrue {
+                self.current[0] =
+                    self.current[0].saturating_add(micros(end.duration_since(self.last)));
+            }
+            self.previous = self.current;
+            self.current = [0; 2];
+            self.start = end;
+            self.last = end;
+
+            // skip ahead over buckets that are entirely outside the window,
+            // leaving one full bucket for the loop to fill
+            let behind = micros(now.duration_since(self.start)) / w;
+            if behind >= 2 {
+                self.start = self.start + Duration::from_micros(w.saturating_mul(behind - 1));
+                self.last = self.start;
+            }
+        }
+    }
+
+    /// Add to the quantities at `now`.
+    pub(crate) fn add(&mut self, window: Window, now: T, first: u64, second: u64) {
+        match window {
+            Window::Lifetime => {}
+            Window::Microsec(_) => {
+                self.advance(window, now, false);
+                self.current[0] = self.current[0].saturating_add(first);
+                self.current[1] = self.current[1].saturating_add(second);
+            }
+            Window::Bytes(n) => {
+                self.current[0] = self.current[0].saturating_add(first);
+                self.current[1] = self.current[1].saturating_add(second);
+                if self.current[0].saturating_add(self.current[1]) >= n {
+                    self.previous = self.current;
+                    self.current = [0; 2];
+                }
+            }
+        }
+    }
+
+    /// Returns the sums of the quantities in the window at `now`, accruing
+    /// time since the last advance if `accrue` (see [`Self::advance()`]).
+    /// Returns `None` for [`Window::Lifetime`].
+    pub(crate) fn sums(&self, window: Window, now: T, accrue: bool) -> Option<(f64, f64)> {
+        let mut c = *self;
+        c.advance(window, now, accrue);
+        let overlap = match window {
+            Window::Lifetime => return None,
+            Window::Microsec(w) => {
+                let elapsed = micros(now.duration_since(c.start)).min(w);
+                1.0 - elapsed as f64 / w as f64
+            }
+            Window::Bytes(n) => {
+                let filled = c.current[0].saturating_add(c.current[1]).min(n);
+                1.0 - filled as f64 / n as f64
+            }
+        };
+        Some((
+            c.current[0] as f64 + c.previous[0] as f64 * overlap,
+            c.current[1] as f64 + c.previous[1] as f64 * overlap,
+        ))
+    }
+
+    pub(crate) fn snapshot(&self, reference: T) -> WindowCounterSnapshot {
+        WindowCounterSnapshot {
+            start: reference.duration_since(self.start),
+            last: reference.duration_since(self.last),
+            current: self.current,
+            previous: self.previous,
+        }
+    }
+
+    pub(crate) fn restore(
+        snapshot: &WindowCounterSnapshot,
+        reference: T,
+    ) -> Result<Self, FrameworkError> {
+        let time = |d| {
+            reference
+                .checked_sub(d)
+                .ok_or(FrameworkError::UnrepresentableTime)
+        };
+        Ok(WindowCounter {
+            start: time(snapshot.start)?,
+            last: time(snapshot.last)?,
+            current: snapshot.current,
+            previous: snapshot.previous,
+        })
+    }
+}
+
+/// A [`WindowCounter`] in a snapshot, with times as durations before the
+/// reference point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct WindowCounterSnapshot {
+    start: Duration,
+    last: Duration,
+    current: [u64; 2],
+    previous: [u64; 2],
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::window::*;
+    use std::time::Instant;
+
+    #[test]
+    fn window_serialization() {
+        for w in [Window::Lifetime, Window::Microsec(7), Window::Bytes(1500)] {
+            assert_eq!(Window::parse(&w.serialize()).unwrap(), w);
+        }
+        let mut buf = Window::Bytes(1).serialize();
+        buf[0] = 3;
+        assert!(Window::parse(&buf).is_err());
+        assert!(Window::parse(&buf[..4]).is_err());
+    }
+
+    #[test]
+    fn window_validation() {
+        assert!(Window::Lifetime.validate_padding().is_ok());
+        assert!(Window::Bytes(1).validate_padding().is_ok());
+        assert!(Window::Bytes(0).validate_padding().is_err());
+        assert!(Window::Microsec(0).validate_padding().is_err());
+        assert!(Window::Microsec(1).validate_blocking().is_ok());
+        assert!(Window::Microsec(0).validate_blocking().is_err());
+        assert!(matches!(
+            Window::Bytes(1).validate_blocking(),
+            Err(MachineError::InvalidWindow {
+                field: "blocking_window",
+                ..
+            })
+        ));
+        let max = Window::Microsec(MAXWINDOWMICROSEC);
+        assert!(max.validate_padding().is_ok());
+        assert!(max.validate_blocking().is_ok());
+        let over = Window::Microsec(MAXWINDOWMICROSEC + 1);
+        assert!(matches!(
+            over.validate_padding(),
+            Err(MachineError::InvalidWindow {
+                field: "padding_window",
+                ..
+            })
+        ));
+        assert!(over.validate_blocking().is_err());
+    }
+
+    #[test]
+    fn time_window() {
+        let w = Window::Microsec(100);
+        let start = Instant::now();
+        let at = |us| start + Duration::from_micros(us);
+        let mut c = WindowCounter::new(start);
+        assert_eq!(c.sums(Window::Lifetime, at(10), false), None);
+
+        c.add(w, at(10), 1000, 3000);
+        assert_eq!(c.sums(w, at(50), false), Some((1000.0, 3000.0)));
+        // half of the first bucket still overlaps the window
+        assert_eq!(c.sums(w, at(150), false), Some((500.0, 1500.0)));
+        c.add(w, at(150), 0, 500);
+        assert_eq!(c.sums(w, at(150), false), Some((500.0, 2000.0)));
+        // the first bucket is outside the window
+        assert_eq!(c.sums(w, at(250), false), Some((0.0, 250.0)));
+        assert_eq!(c.sums(w, at(10_000), false), Some((0.0, 0.0)));
+    }
+
+    #[test]
+    fn time_window_accrue() {
+        let w = Window::Microsec(100);
+        let start = Instant::now();
+        let at = |us| start + Duration::from_micros(us);
+        let mut c = WindowCounter::new(start);
+
+        // inactive until 20, then accrue until 80
+        c.advance(w, at(20), false);
+        assert_eq!(c.sums(w, at(80), true), Some((60.0, 0.0)));
+        c.advance(w, at(80), true);
+        assert_eq!(c.sums(w, at(150), false), Some((30.0, 0.0)));
+
+        // accrue across many buckets: the window is entirely filled
+        assert_eq!(c.sums(w, at(100_050), true), Some((100.0, 0.0)));
+        c.advance(w, at(100_050), true);
+        assert_eq!(c.sums(w, at(100_150), false), Some((25.0, 0.0)));
+    }
+
+    #[test]
+    fn bytes_window() {
+        let w = Window::Bytes(1000);
+        let now = Instant::now();
+        let mut c = WindowCounter::new(now);
+        c.add(w, now, 100, 300);
+        assert_eq!(c.sums(w, now, false), Some((100.0, 300.0)));
+        c.add(w, now, 0, 600);
+        // the bucket is full and rolls over, entirely in the window
+        assert_eq!(c.sums(w, now, false), Some((100.0, 900.0)));
+        c.add(w, now, 500, 0);
+        assert_eq!(c.sums(w, now, false), Some((550.0, 450.0)));
+    }
+}